@@ -0,0 +1,23 @@
+use colored::*;
+use expectation_shared::filesystem::*;
+use expectation_shared::objects::{collect_garbage, OBJECTS};
+use std::io::Result as IoResult;
+use std::path::Path;
+
+/// Removes the "actual" and "diff" folders, and the objects that no expected
+/// file points to.
+pub fn perform_clean() -> IoResult<()> {
+    let fs = RealFileSystem {
+        root: "./expectation-tests".into(),
+    };
+    fs.remove_dir(Path::new("actual"))?;
+    fs.remove_dir(Path::new("diff"))?;
+
+    let removed = collect_garbage(
+        &*fs.subsystem(Path::new("expected")),
+        &*fs.subsystem(Path::new(OBJECTS)),
+    )?;
+    println!("{} Removed the \"actual\" and \"diff\" folders", "◼".blue());
+    println!("{} Removed {} unreferenced objects", "◼".blue(), removed.len());
+    Ok(())
+}
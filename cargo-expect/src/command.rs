@@ -1,9 +1,10 @@
 use super::Specifier;
 use colored::*;
 use crossbeam::channel::{unbounded, Receiver};
+use expectation_shared::compressed::Compression;
 use expectation_shared::filesystem::*;
 use expectation_shared::Message;
-use promote::promote;
+use promote::{promote, Storage};
 use serde_json;
 use std::io::Result as IoResult;
 use std::net::TcpListener;
@@ -72,6 +73,21 @@ fn prepare_command(spec: Specifier, send_ser: String) -> Command {
     if !spec.filetypes.is_empty() {
         command.env("CARGO_EXPECT_FILES", spec.filetypes.join(","));
     }
+    if let Some(jobs) = spec.jobs {
+        command.env("CARGO_EXPECT_JOBS", jobs.to_string());
+    }
+    if let Some(untracked) = spec.untracked {
+        command.env("CARGO_EXPECT_UNTRACKED", untracked);
+    }
+    if let Some(file_size) = spec.file_size {
+        command.env("CARGO_EXPECT_FILE_SIZE", file_size);
+    }
+    if let Some(test_size) = spec.test_size {
+        command.env("CARGO_EXPECT_TEST_SIZE", test_size);
+    }
+    if spec.keep_output {
+        command.env("CARGO_EXPECT_KEEP_OUTPUT", "1");
+    }
     command.env("CARGO_EXPECT_IPC", send_ser);
     command.stdout(Stdio::null());
     command.stderr(Stdio::null());
@@ -121,6 +137,23 @@ where
 }
 
 pub fn perform_promote(spec: Specifier) -> IoResult<bool> {
+    let compression = match spec.compress.as_ref() {
+        Some(name) => match Compression::from_name(name) {
+            Some(compression) => Some(compression),
+            None => {
+                eprintln!("unknown compression {:?}, expected \"gzip\" or \"zstd\"", name);
+                return Ok(false);
+            }
+        },
+        None => None,
+    };
+    let storage = Storage {
+        compression,
+        dedup: spec.dedup,
+        objects: Box::new(RealFileSystem {
+            root: "./expectation-tests/objects".into(),
+        }),
+    };
     if !run_build(spec.release)?.success() {
         return Ok(false);
     }
@@ -143,7 +176,7 @@ pub fn perform_promote(spec: Specifier) -> IoResult<bool> {
                     let rs: Vec<_> = result
                         .into_iter()
                         .map(|r| {
-                            let p = promote(&r.kind, fs.duplicate());
+                            let p = promote(&r, fs.duplicate(), &storage);
                             (r, p)
                         }).collect();
                     let (s, c_count) = ::output::print_promotion(&name, rs, verbose);
@@ -1,4 +1,5 @@
-use expectation_shared::{Difference, Result as EResult, ResultKind};
+use expectation_shared::size::format_size;
+use expectation_shared::{Difference, OverBudget, Result as EResult, ResultKind};
 use std::io::{Result as IoResult, Write};
 
 pub fn format_html<W: Write>(
@@ -11,46 +12,110 @@ pub fn format_html<W: Write>(
         include_str!("./style.css")
     )?;
     for (name, result, passed) in results_with_html {
-        if *passed {
+        let over_budget = result.iter().any(|r| matches!(r.kind, ResultKind::OverBudget(_)));
+        // Passing tests are only listed for the sizes of their files.
+        let sized = result.iter().any(|r| r.size.is_some());
+        if *passed && !over_budget && !sized {
             continue;
         }
 
-        write!(writer, r#"<div class="test">"#);
+        write!(writer, r#"<div class="test">"#)?;
         write!(writer, "<h1>{}</h1>", name)?;
-        write!(writer, r#"<div class="indent">"#);
+        write!(writer, r#"<div class="indent">"#)?;
         for result in result {
             match result {
                 EResult {
                     kind: ResultKind::Difference(Difference { html: Some(s), .. }),
                     file_name,
+                    size,
                     ..
                 } => {
-                    write!(writer, r#"<div class="file">"#);
-                    write!(writer, "<h2>{}</h2>", file_name.to_string_lossy())?;
-                    write!(writer, r#"<div class="indent">"#);
+                    write!(writer, r#"<div class="file">"#)?;
+                    write!(writer, "<h2>{}{}</h2>", escape_html(&file_name.to_string_lossy()), size_html(*size))?;
+                    write!(writer, r#"<div class="indent">"#)?;
                     write!(writer, "{}", s)?;
-                    write!(writer, "</div>");
-                    write!(writer, "</div>");
+                    write!(writer, "</div>")?;
+                    write!(writer, "</div>")?;
                 }
                 EResult {
                     kind: ResultKind::Difference(Difference { html: None, .. }),
                     file_name,
+                    size,
                     ..
                 } => {
-                    write!(writer, r#"<div class="file">"#);
-                    write!(writer, "<h2>{}</h2>", file_name.to_string_lossy())?;
-                    write!(writer, r#"<div class="indent">"#);
+                    write!(writer, r#"<div class="file">"#)?;
+                    write!(writer, "<h2>{}{}</h2>", escape_html(&file_name.to_string_lossy()), size_html(*size))?;
+                    write!(writer, r#"<div class="indent">"#)?;
                     write!(writer, "No HTML diff for this format")?;
-                    write!(writer, "</div>");
-                    write!(writer, "</div>");
+                    write!(writer, "</div>")?;
+                    write!(writer, "</div>")?;
+                }
+                EResult {
+                    kind: ResultKind::OverBudget(OverBudget { size, limit, fatal }),
+                    file_name,
+                    ..
+                } => {
+                    let what = if file_name.as_os_str().is_empty() {
+                        "All files".to_owned()
+                    } else {
+                        escape_html(&file_name.to_string_lossy())
+                    };
+                    write!(writer, r#"<div class="file">"#)?;
+                    write!(writer, "<h2>{}</h2>", what)?;
+                    write!(writer, r#"<div class="indent">"#)?;
+                    write!(
+                        writer,
+                        "{} over budget: {} (limit {})",
+                        if *fatal { "Error" } else { "Warning" },
+                        format_size(*size),
+                        format_size(*limit)
+                    )?;
+                    write!(writer, "</div>")?;
+                    write!(writer, "</div>")?;
                 }
                 _ => {}
             }
         }
-        write!(writer, "</div>");
-        write!(writer, "</div>");
+        write_sizes(&mut writer, result)?;
+        write!(writer, "</div>")?;
+        write!(writer, "</div>")?;
     }
 
     write!(writer, "</body></html>")?;
     Ok(())
 }
+
+fn size_html(size: Option<u64>) -> String {
+    match size {
+        Some(size) => format!(r#" <span class="size">{}</span>"#, format_size(size)),
+        None => String::new(),
+    }
+}
+
+/// Lists the size of every file of a test that was written.
+fn write_sizes<W: Write>(writer: &mut W, results: &[EResult]) -> IoResult<()> {
+    let sized: Vec<_> = results
+        .iter()
+        .filter_map(|r| r.size.map(|size| (escape_html(&r.file_name.to_string_lossy()), size)))
+        .collect();
+    if sized.is_empty() {
+        return Ok(());
+    }
+    write!(writer, r#"<div class="file"><h2>File sizes</h2><table class="sizes">"#)?;
+    for (file, size) in sized {
+        write!(
+            writer,
+            "<tr><td>{}</td><td>{}</td></tr>",
+            file,
+            format_size(size)
+        )?;
+    }
+    write!(writer, "</table></div>")
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
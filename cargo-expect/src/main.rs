@@ -8,10 +8,12 @@ extern crate colored;
 
 use std::io::Result as IoResult;
 use structopt::StructOpt;
+mod clean;
 mod command;
 mod html;
 mod output;
 mod promote;
+mod stats;
 
 #[derive(StructOpt, Debug)]
 pub struct Specifier {
@@ -29,6 +31,47 @@ pub struct Specifier {
 
     #[structopt(long = "release")]
     release: bool,
+
+    /// The number of files of a test that are validated at the same time.
+    /// Defaults to the number of CPUs.
+    #[structopt(short = "j", long = "jobs")]
+    jobs: Option<usize>,
+
+    /// What to do with files in a test's "actual" folder that the test
+    /// didn't register: "error", "warn" (the default) or "ignore".
+    #[structopt(long = "untracked")]
+    untracked: Option<String>,
+
+    /// Keeps the "actual" and "diff" files of earlier runs instead of
+    /// clearing them before each test.
+    #[structopt(long = "keep-output")]
+    keep_output: bool,
+
+    /// The size an expected file may have before it is warned about, and
+    /// optionally before it fails the test: "<warn>[,<error>]", like
+    /// "1M,10M".  Defaults to a warning at 1M.  Sizes are what the files
+    /// take up in storage, compressed if they are stored compressed, as
+    /// listed by `cargo expect stats`.
+    #[structopt(long = "file-size")]
+    file_size: Option<String>,
+
+    /// The total size the expected files of a test may have, in the same
+    /// form as --file-size.
+    #[structopt(long = "test-size")]
+    test_size: Option<String>,
+
+    /// Stores promoted "expected" files compressed: "gzip" or "zstd".
+    /// Files that are already compressed stay compressed.  Compressed files
+    /// are decompressed into memory when they are compared, so only use
+    /// this for files that fit in memory.
+    #[structopt(long = "compress")]
+    compress: Option<String>,
+
+    /// Stores promoted "expected" files once in "expectation-tests/objects",
+    /// leaving pointers to them in "expected".  Files that are already
+    /// pointers stay pointers.
+    #[structopt(long = "dedup")]
+    dedup: bool,
 }
 
 #[derive(StructOpt, Debug)]
@@ -43,6 +86,8 @@ pub struct Specifier {
     cargo expect promote -f svg               # promotes all tests but only promotes svg files produced by those tests
     cargo expect promote my_test_name         # promotes all files in tests that match "my_test_name"
     cargo expect promote my_test_name -f svg  # promotes only svg files for tests that match "my_test_name"
+
+    cargo expect stats                   # lists the largest expected files and the size of each test
 "#
 )]
 pub enum Command {
@@ -54,7 +99,12 @@ pub enum Command {
     #[structopt(name = "promote")]
     Promote(Specifier),
 
-    /// Cleans up the expectation-tests directory by removing the "diff" and "actual" folders.
+    /// Lists the largest "expected" files and the total size per test
+    #[structopt(name = "stats")]
+    Stats(stats::StatsSpecifier),
+
+    /// Cleans up the expectation-tests directory by removing the "diff" and "actual" folders,
+    /// and the objects that no "expected" file points to.
     #[structopt(name = "clean")]
     Clean,
 }
@@ -79,7 +129,8 @@ fn main() -> IoResult<()> {
                 ::std::process::exit(1);
             }
         }
-        _ => panic!(),
+        Command::Stats(spec) => stats::perform_stats(spec)?,
+        Command::Clean => clean::perform_clean()?,
     }
     Ok(())
 }
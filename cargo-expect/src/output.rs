@@ -1,4 +1,5 @@
 use colored::*;
+use expectation_shared::size::format_size;
 use expectation_shared::{Result as EResult, ResultKind};
 use std::io::Result as IoResult;
 use std::error::Error;
@@ -10,7 +11,7 @@ pub fn print_promotion(name: &str, results: Vec<(EResult, IoResult<String>)>, ve
     let nothing_done = results
         .iter()
         .all(|&(ref r, _)|  match r.kind {
-            ResultKind::Ok => true,
+            ResultKind::Ok | ResultKind::Untracked(_) | ResultKind::OverBudget(_) => true,
             _ => false,
         });
     let change_count = results
@@ -18,6 +19,8 @@ pub fn print_promotion(name: &str, results: Vec<(EResult, IoResult<String>)>, ve
         .filter(|&(ref r, _)| match r.kind {
             ResultKind::Ok => false,
             ResultKind::IoError(_) => false,
+            ResultKind::Untracked(_) => false,
+            ResultKind::OverBudget(_) => false,
             _ => true,
         })
         .count();
@@ -58,7 +61,8 @@ pub fn print_promotion(name: &str, results: Vec<(EResult, IoResult<String>)>, ve
 }
 
 pub fn print_results(name: &str, results: &[EResult], verbose: bool) {
-    let passed = results.iter().all(|r| match r.kind {
+    let passed = results.iter().all(|r| r.is_ok());
+    let clean = results.iter().all(|r| match r.kind {
         ResultKind::Ok => true,
         _ => false,
     });
@@ -68,7 +72,7 @@ pub fn print_results(name: &str, results: &[EResult], verbose: bool) {
         println!("{} {}", "✘".red(), name);
     }
 
-    if passed && !verbose {
+    if clean && !verbose {
         return;
     }
 
@@ -144,6 +148,37 @@ pub fn print_results(name: &str, results: &[EResult], verbose: bool) {
                     }
                 }
             }
+            EResult {
+                file_name,
+                kind: ResultKind::Untracked(untracked),
+                ..
+            } => {
+                let mark = if untracked.fatal { "✘".red() } else { "⚠".yellow() };
+                println!(
+                    "  {} {} ❯ Untracked",
+                    mark,
+                    file_name.to_string_lossy()
+                );
+                println!("    ► Actual: {}", untracked.actual.to_string_lossy());
+            }
+            EResult {
+                file_name,
+                kind: ResultKind::OverBudget(budget),
+                ..
+            } => {
+                let mark = if budget.fatal { "✘".red() } else { "⚠".yellow() };
+                let what = if file_name.as_os_str().is_empty() {
+                    "All files".into()
+                } else {
+                    file_name.to_string_lossy()
+                };
+                println!("  {} {} ❯ Over Budget", mark, what);
+                println!(
+                    "    ► Size: {} (limit {})",
+                    format_size(budget.size),
+                    format_size(budget.limit)
+                );
+            }
             EResult {
                 file_name,
                 kind: ResultKind::IoError(error),
@@ -1,25 +1,122 @@
-use expectation_shared::ResultKind;
+use expectation_shared::compressed::{Compression, CompressedFileSystem};
+use expectation_shared::{Result, ResultKind};
 use expectation_shared::filesystem::FileSystem;
+use expectation_shared::expectignore::ExpectIgnore;
+use expectation_shared::hashes::{Entry, HashManifest};
+use expectation_shared::objects::ObjectFileSystem;
 use std::io::Result as IoResult;
+use std::path::Path;
 
-pub fn promote(result: &ResultKind, filesystem: Box<FileSystem>) -> IoResult<String> {
-    match result {
+/// How promoted expected files are stored.
+pub struct Storage {
+    /// Expected files are stored with this compression, except that
+    /// compressed ones keep the format they have.
+    pub compression: Option<Compression>,
+    /// Whether expected files are stored as pointers to shared objects.
+    /// Pointers always stay pointers.
+    pub dedup: bool,
+    /// The object store that pointers point into.
+    pub objects: Box<FileSystem>,
+}
+
+/// Promotes the actual file of a result.
+pub fn promote(result: &Result, filesystem: Box<FileSystem>, storage: &Storage) -> IoResult<String> {
+    let expected_fs = CompressedFileSystem::new(
+        Box::new(ObjectFileSystem::new(
+            filesystem.duplicate(),
+            storage.objects.duplicate(),
+            storage.dedup,
+        )),
+        storage.compression,
+    );
+    match &result.kind {
         ResultKind::IoError(_) |
+        ResultKind::Untracked(_) |
+        ResultKind::OverBudget(_) |
         ResultKind::Ok => Ok("Nothing to do".into()),
         ResultKind::ExpectedNotFound(double) => {
-            filesystem.copy(&double.actual, &double.expected)?;
+            copy_to_expected(&double.actual, &double.expected, &*filesystem, &expected_fs)?;
+            record_hash(&double.expected, &result.file_name, &*filesystem, Some(&expected_fs))?;
             Ok(format!("moved {} -> {}", double.actual.to_string_lossy(),
                                          double.expected.to_string_lossy()))
         }
         ResultKind::ActualNotFound(double) => {
-            filesystem.remove(&double.expected)?;
+            if is_ignored(&double.expected, &result.file_name, &*filesystem) {
+                return Ok(format!("kept ignored {}", double.expected.to_string_lossy()));
+            }
+            expected_fs.remove(&double.expected)?;
+            record_hash(&double.expected, &result.file_name, &*filesystem, None)?;
             Ok(format!("removed {}", double.expected.to_string_lossy()))
         }
         ResultKind::Difference(triple) => {
-            filesystem.copy(&triple.actual, &triple.expected)?;
+            copy_to_expected(&triple.actual, &triple.expected, &*filesystem, &expected_fs)?;
+            record_hash(&triple.expected, &result.file_name, &*filesystem, Some(&expected_fs))?;
             Ok(format!("moved {} -> {}", triple.actual.to_string_lossy(),
                                          triple.expected.to_string_lossy()))
         }
 
     }
-}
\ No newline at end of file
+}
+
+fn copy_to_expected(
+    actual: &Path,
+    expected: &Path,
+    filesystem: &FileSystem,
+    expected_fs: &FileSystem,
+) -> IoResult<()> {
+    expected_fs.write(expected, &mut |writer| {
+        filesystem.read(actual, &mut |reader| {
+            ::std::io::copy(reader, writer).map(|_| ())
+        })
+    })
+}
+
+/// Records the hash of a promoted file in the manifest of its test, reading
+/// it back through `expected_fs`, or drops the file from the manifest when it
+/// was removed.  This is the only place the manifest is written.
+fn record_hash(
+    expected: &Path,
+    file: &Path,
+    filesystem: &FileSystem,
+    expected_fs: Option<&FileSystem>,
+) -> IoResult<()> {
+    let dir = match test_dir(expected, file) {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+    let mut manifest = HashManifest::load(filesystem, dir);
+    match expected_fs {
+        Some(expected_fs) => manifest.insert(file, Entry::for_file(expected_fs, expected)?),
+        None => {
+            if manifest.remove(file).is_none() {
+                return Ok(());
+            }
+        }
+    }
+    manifest.save(filesystem, dir)
+}
+
+/// Whether an expected file is listed in the `.expectignore` files of its
+/// test, in which case promoting never removes it.
+fn is_ignored(expected: &Path, file: &Path, filesystem: &FileSystem) -> bool {
+    let dir = match test_dir(expected, file) {
+        Some(dir) => dir,
+        None => return false,
+    };
+    match (dir.parent(), dir.file_name()) {
+        (Some(root), Some(name)) => {
+            ExpectIgnore::load(filesystem, root, &name.to_string_lossy()).is_ignored(file)
+        }
+        _ => false,
+    }
+}
+
+/// The expected directory of the test that `expected`, the full path of
+/// `file`, belongs to.
+fn test_dir<'a>(expected: &'a Path, file: &Path) -> Option<&'a Path> {
+    let mut dir = expected;
+    for _ in file.components() {
+        dir = dir.parent()?;
+    }
+    Some(dir)
+}
@@ -0,0 +1,87 @@
+use colored::*;
+use expectation_shared::compressed::CompressedFileSystem;
+use expectation_shared::expectignore::IGNORE_FILE;
+use expectation_shared::filesystem::*;
+use expectation_shared::hashes::MANIFEST;
+use expectation_shared::objects::{ObjectFileSystem, OBJECTS};
+use expectation_shared::size::format_size;
+use std::collections::BTreeMap;
+use std::io::Result as IoResult;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct StatsSpecifier {
+    /// Specifies which tests to list
+    #[structopt(name = "filter")]
+    filter: Option<String>,
+
+    /// The number of the largest baseline files to list.
+    #[structopt(short = "n", long = "top", default_value = "10")]
+    top: usize,
+}
+
+/// Lists the largest expected files, and how big the expected files of each
+/// test are together.
+pub fn perform_stats(spec: StatsSpecifier) -> IoResult<()> {
+    let root = RealFileSystem {
+        root: Path::new("./expectation-tests").into(),
+    };
+    // Files are listed under their plain names, with the size they take up
+    // in storage.
+    let fs = CompressedFileSystem::new(
+        Box::new(ObjectFileSystem::new(
+            root.subsystem(Path::new("expected")),
+            root.subsystem(Path::new(OBJECTS)),
+            false,
+        )),
+        None,
+    );
+
+    let mut files: Vec<(u64, PathBuf)> = vec![];
+    let mut tests: BTreeMap<String, (u64, usize)> = BTreeMap::new();
+    for file in fs.files() {
+        let test = match file.components().next() {
+            Some(test) if file.components().count() > 1 => {
+                test.as_os_str().to_string_lossy().into_owned()
+            }
+            _ => continue,
+        };
+        let name = file.file_name().and_then(|n| n.to_str());
+        if name == Some(MANIFEST) || name == Some(IGNORE_FILE) {
+            continue;
+        }
+        if let Some(filter) = &spec.filter {
+            if !test.contains(filter.as_str()) {
+                continue;
+            }
+        }
+        let size = fs.size(&file)?;
+        let entry = tests.entry(test).or_insert((0, 0));
+        entry.0 += size;
+        entry.1 += 1;
+        files.push((size, file));
+    }
+
+    files.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    println!("{} Largest Baselines", "◼".blue());
+    for (size, file) in files.iter().take(spec.top) {
+        println!("  {} {:>10}  {}", "►".blue(), format_size(*size), file.to_string_lossy());
+    }
+
+    let mut tests: Vec<_> = tests.into_iter().collect();
+    tests.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then_with(|| a.0.cmp(&b.0)));
+    println!("{} Size Per Test", "◼".blue());
+    for (test, (size, count)) in &tests {
+        println!("  {} {:>10}  {} ({} files)", "►".blue(), format_size(*size), test, count);
+    }
+
+    let total: u64 = files.iter().map(|f| f.0).sum();
+    println!(
+        "{} Total: {} in {} files",
+        "◼".blue(),
+        format_size(total),
+        files.len()
+    );
+    Ok(())
+}
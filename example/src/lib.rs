@@ -5,6 +5,7 @@ extern crate expectation_plugin;
 
 use expectation::{extensions::*, *};
 use expectation_plugin::expectation_test;
+use std::collections::HashMap;
 use std::io::Write;
 
 #[expectation_test]
@@ -16,3 +17,12 @@ fn test_with_annotation(p: Provider) {
     writeln!(w, "d");
     writeln!(w, "e");
 }
+
+#[expectation_test]
+fn test_with_serde(p: Provider) {
+    let mut scores = HashMap::new();
+    scores.insert("alice", vec![1, 2, 3]);
+    scores.insert("bob", vec![4, 5]);
+    p.json("scores.json", &scores).unwrap();
+    p.ron("scores.ron", &scores).unwrap();
+}
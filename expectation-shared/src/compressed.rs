@@ -0,0 +1,337 @@
+use filesystem::{FileSystem, ReadSeek};
+use std::io::{self, Cursor, Read, Result as IoResult, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A format that expected files can be stored in, named by the extension
+/// that is added to the file name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub const ALL: &'static [Compression] = &[Compression::Gzip, Compression::Zstd];
+
+    /// Reads `gzip` (or `gz`) and `zstd` (or `zst`).
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "gzip" | "gz" => Some(Compression::Gzip),
+            "zstd" | "zst" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// The path that a file is stored at in this format.
+    pub fn stored_path(self, path: &Path) -> PathBuf {
+        let mut stored = path.as_os_str().to_owned();
+        stored.push(self.extension());
+        stored.into()
+    }
+
+    /// The format and plain path of a file stored at `path`, if it is
+    /// compressed.
+    fn of(path: &Path) -> Option<(Compression, PathBuf)> {
+        let name = path.to_str()?;
+        Compression::ALL.iter().find_map(|&c| {
+            name.strip_suffix(c.extension())
+                .filter(|plain| !plain.is_empty() && !plain.ends_with('/'))
+                .map(|plain| (c, PathBuf::from(plain)))
+        })
+    }
+
+    fn decompress(self, r: &mut Read) -> IoResult<Vec<u8>> {
+        match self {
+            Compression::Gzip => gzip::decompress(r),
+            Compression::Zstd => zstd::decompress(r),
+        }
+    }
+
+    /// Wraps `w` in an encoder that finishes the stream when it is dropped.
+    fn encoder(self, w: Box<Write>) -> IoResult<Box<Write>> {
+        match self {
+            Compression::Gzip => gzip::encoder(w),
+            Compression::Zstd => zstd::encoder(w),
+        }
+    }
+
+    /// Compresses everything that `f` writes into `w`, reporting errors in
+    /// finishing the stream.
+    fn encode(self, w: &mut Write, f: &mut FnMut(&mut Write) -> IoResult<()>) -> IoResult<()> {
+        match self {
+            Compression::Gzip => gzip::encode(w, f),
+            Compression::Zstd => zstd::encode(w, f),
+        }
+    }
+}
+
+#[cfg(feature = "gzip")]
+mod gzip {
+    use flate2::read::MultiGzDecoder;
+    use flate2::write::GzEncoder;
+    use flate2::Compression as Level;
+    use std::io::{Read, Result as IoResult, Write};
+
+    pub fn decompress(r: &mut Read) -> IoResult<Vec<u8>> {
+        let mut out = vec![];
+        MultiGzDecoder::new(r).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    pub fn encoder(w: Box<Write>) -> IoResult<Box<Write>> {
+        Ok(Box::new(GzEncoder::new(w, Level::default())))
+    }
+
+    pub fn encode(w: &mut Write, f: &mut FnMut(&mut Write) -> IoResult<()>) -> IoResult<()> {
+        let mut encoder = GzEncoder::new(w, Level::default());
+        f(&mut encoder)?;
+        encoder.finish().map(|_| ())
+    }
+}
+
+#[cfg(feature = "zstd")]
+mod zstd {
+    use std::io::{Read, Result as IoResult, Write};
+    use zstd::stream::read::Decoder;
+    use zstd::stream::write::Encoder;
+
+    pub fn decompress(r: &mut Read) -> IoResult<Vec<u8>> {
+        let mut out = vec![];
+        Decoder::new(r)?.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    pub fn encoder(w: Box<Write>) -> IoResult<Box<Write>> {
+        Ok(Box::new(Encoder::new(w, 0)?.auto_finish()))
+    }
+
+    pub fn encode(w: &mut Write, f: &mut FnMut(&mut Write) -> IoResult<()>) -> IoResult<()> {
+        let mut encoder = Encoder::new(w, 0)?;
+        f(&mut encoder)?;
+        encoder.finish().map(|_| ())
+    }
+}
+
+/// Stands in for a format whose feature is disabled, failing on every use.
+macro_rules! unsupported_format {
+    ($name:ident, $feature:expr) => {
+        #[cfg(not(feature = $feature))]
+        mod $name {
+            use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+
+            fn unsupported() -> IoError {
+                IoError::new(
+                    ErrorKind::Other,
+                    concat!("compressed expected files need the \"", $feature, "\" feature"),
+                )
+            }
+
+            pub fn decompress(_: &mut Read) -> IoResult<Vec<u8>> {
+                Err(unsupported())
+            }
+
+            pub fn encoder(_: Box<Write>) -> IoResult<Box<Write>> {
+                Err(unsupported())
+            }
+
+            pub fn encode(_: &mut Write, _: &mut FnMut(&mut Write) -> IoResult<()>) -> IoResult<()> {
+                Err(unsupported())
+            }
+        }
+    };
+}
+
+unsupported_format!(gzip, "gzip");
+unsupported_format!(zstd, "zstd");
+
+/// Stores files compressed, while they are read and written as plain bytes
+/// under their plain names: `foo.txt` is kept as `foo.txt.zst`.
+///
+/// Compressed files keep their format when they are rewritten, and other
+/// files are compressed when they are written only if a compression is
+/// given.  Compressed files are decompressed into memory to be read.
+pub struct CompressedFileSystem {
+    inner: Box<FileSystem>,
+    compression: Option<Compression>,
+}
+
+impl CompressedFileSystem {
+    pub fn new(inner: Box<FileSystem>, compression: Option<Compression>) -> CompressedFileSystem {
+        CompressedFileSystem { inner, compression }
+    }
+
+    /// The file system that the files are stored in, for files that are
+    /// never compressed.
+    pub fn inner(&self) -> &FileSystem {
+        &*self.inner
+    }
+
+    /// The format that `path` is stored in, or `None` if it is stored plain
+    /// or doesn't exist.
+    pub fn compression_of(&self, path: &Path) -> Option<Compression> {
+        Compression::ALL
+            .iter()
+            .cloned()
+            .find(|c| self.inner.exists(&c.stored_path(path)))
+    }
+
+    /// The number of bytes that `contents` would take up in storage if they
+    /// were written to `path`: compressed in the format of the file stored
+    /// there, or with the compression of this file system if there is none.
+    pub fn stored_size(&self, path: &Path, contents: &mut Read) -> IoResult<u64> {
+        match self.compression_of(path).or(self.compression) {
+            Some(c) => {
+                let mut counter = ByteCounter(0);
+                c.encode(&mut counter, &mut |w| io::copy(&mut *contents, w).map(|_| ()))?;
+                Ok(counter.0)
+            }
+            None => io::copy(contents, &mut io::sink()),
+        }
+    }
+
+    /// The path that `path` is stored at, if it exists.
+    fn stored(&self, path: &Path) -> Option<PathBuf> {
+        match self.compression_of(path) {
+            Some(c) => Some(c.stored_path(path)),
+            None if self.inner.exists(path) => Some(path.to_owned()),
+            None => None,
+        }
+    }
+
+    /// Picks the format to write `path` in, and removes the copies of it
+    /// that are stored in other formats.
+    fn prepare_write(&self, path: &Path) -> IoResult<Option<Compression>> {
+        let compression = self.compression_of(path).or(self.compression);
+        let stored = compression.map(|c| c.stored_path(path));
+        for c in Compression::ALL {
+            let other = c.stored_path(path);
+            if Some(&other) != stored.as_ref() && self.inner.exists(&other) {
+                self.inner.remove(&other)?;
+            }
+        }
+        if compression.is_some() && self.inner.exists(path) {
+            self.inner.remove(path)?;
+        }
+        Ok(compression)
+    }
+}
+
+/// Counts the bytes written to it and discards them.
+struct ByteCounter(u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl FileSystem for CompressedFileSystem {
+    fn duplicate(&self) -> Box<FileSystem> {
+        Box::new(CompressedFileSystem::new(self.inner.duplicate(), self.compression))
+    }
+
+    fn subsystem(&self, path: &Path) -> Box<FileSystem> {
+        Box::new(CompressedFileSystem::new(self.inner.subsystem(path), self.compression))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.stored(path).is_some()
+    }
+
+    fn read(&self, path: &Path, f: &mut FnMut(&mut ReadSeek) -> IoResult<()>) -> IoResult<()> {
+        match self.compression_of(path) {
+            Some(c) => {
+                let mut contents = vec![];
+                self.inner.read(&c.stored_path(path), &mut |r| {
+                    contents = c.decompress(r)?;
+                    Ok(())
+                })?;
+                f(&mut Cursor::new(&contents[..]))
+            }
+            None => self.inner.read(path, f),
+        }
+    }
+
+    fn write(&self, path: &Path, f: &mut FnMut(&mut Write) -> IoResult<()>) -> IoResult<()> {
+        match self.prepare_write(path)? {
+            Some(c) => self
+                .inner
+                .write(&c.stored_path(path), &mut |w| c.encode(w, f)),
+            None => self.inner.write(path, f),
+        }
+    }
+
+    fn create(&self, path: &Path) -> IoResult<Box<Write>> {
+        match self.prepare_write(path)? {
+            Some(c) => c.encoder(self.inner.create(&c.stored_path(path))?),
+            None => self.inner.create(path),
+        }
+    }
+
+    /// The plain path, which is what the file is reported as.
+    fn full_path_for(&self, path: &Path) -> PathBuf {
+        self.inner.full_path_for(path)
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<_> = self
+            .inner
+            .files()
+            .into_iter()
+            .map(|file| match Compression::of(&file) {
+                Some((_, plain)) => plain,
+                None => file,
+            })
+            .collect();
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    /// The size of the file as it is stored.
+    fn size(&self, path: &Path) -> IoResult<u64> {
+        match self.stored(path) {
+            Some(stored) => self.inner.size(&stored),
+            None => self.inner.size(path),
+        }
+    }
+
+    fn modified(&self, path: &Path) -> IoResult<SystemTime> {
+        match self.stored(path) {
+            Some(stored) => self.inner.modified(&stored),
+            None => self.inner.modified(path),
+        }
+    }
+
+    fn remove(&self, path: &Path) -> IoResult<()> {
+        let mut removed = false;
+        for c in Compression::ALL {
+            let stored = c.stored_path(path);
+            if self.inner.exists(&stored) {
+                self.inner.remove(&stored)?;
+                removed = true;
+            }
+        }
+        if removed && !self.inner.exists(path) {
+            return Ok(());
+        }
+        self.inner.remove(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> IoResult<()> {
+        self.inner.remove_dir(path)
+    }
+}
@@ -0,0 +1,57 @@
+use filesystem::FileSystem;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+/// The name of the files that list expected files to leave alone, using the
+/// syntax of `.gitignore`.
+pub const IGNORE_FILE: &str = ".expectignore";
+
+/// The ignore patterns that apply to the expected files of one test: those in
+/// `expected/.expectignore`, and those in `expected/<test>/.expectignore`,
+/// which take precedence.
+pub struct ExpectIgnore {
+    root: Gitignore,
+    test: Gitignore,
+    name: PathBuf,
+}
+
+impl ExpectIgnore {
+    /// Reads the ignore files for the test `name` from `expected`, the
+    /// directory that holds the expected files of all tests.  Missing files
+    /// ignore nothing, and lines that aren't valid patterns are skipped.
+    pub fn load(fs: &FileSystem, expected: &Path, name: &str) -> ExpectIgnore {
+        ExpectIgnore {
+            root: load_patterns(fs, &expected.join(IGNORE_FILE)),
+            test: load_patterns(fs, &expected.join(name).join(IGNORE_FILE)),
+            name: name.into(),
+        }
+    }
+
+    /// Whether `file`, relative to the expected directory of the test, is
+    /// ignored.
+    pub fn is_ignored(&self, file: &Path) -> bool {
+        match self.test.matched_path_or_any_parents(file, false) {
+            Match::Ignore(_) => true,
+            Match::Whitelist(_) => false,
+            Match::None => self
+                .root
+                .matched_path_or_any_parents(self.name.join(file), false)
+                .is_ignore(),
+        }
+    }
+}
+
+fn load_patterns(fs: &FileSystem, path: &Path) -> Gitignore {
+    let mut builder = GitignoreBuilder::new("");
+    if fs.exists(path) {
+        let _ = fs.read(path, &mut |r| {
+            for line in r.lines() {
+                let _ = builder.add_line(None, &line?);
+            }
+            Ok(())
+        });
+    }
+    builder.build().unwrap_or_else(|_| Gitignore::empty())
+}
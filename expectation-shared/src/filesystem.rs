@@ -1,10 +1,11 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
-use std::fs::{create_dir_all, File};
+use std::fs::{create_dir_all, remove_dir_all, rename, File};
 use std::io::{BufRead, Cursor, Result as IoResult, Seek, Write};
 use std::io::{BufReader, BufWriter, Error as IoError, ErrorKind};
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub trait ReadSeek: Seek + BufRead {}
 impl<R: BufRead + Seek> ReadSeek for R {}
@@ -17,18 +18,31 @@ pub struct RealFileSystem {
 #[derive(Clone, Debug)]
 pub struct FakeFileSystem {
     root: PathBuf,
-    mapping: Rc<RefCell<HashMap<PathBuf, Vec<u8>>>>,
+    mapping: Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>,
+    modified: Arc<Mutex<HashMap<PathBuf, SystemTime>>>,
 }
 
-pub trait FileSystem {
+/// File systems are shared between the threads that validate files.
+pub trait FileSystem: Send + Sync {
     fn duplicate(&self) -> Box<FileSystem>;
     fn subsystem(&self, path: &Path) -> Box<FileSystem>;
     fn exists(&self, path: &Path) -> bool;
     fn read(&self, path: &Path, f: &mut FnMut(&mut ReadSeek) -> IoResult<()>) -> IoResult<()>;
     fn write(&self, path: &Path, f: &mut FnMut(&mut Write) -> IoResult<()>) -> IoResult<()>;
+    /// Creates (or truncates) a file and returns a writer that streams into
+    /// it.
+    fn create(&self, path: &Path) -> IoResult<Box<Write>>;
     fn full_path_for(&self, path: &Path) -> PathBuf;
     fn files(&self) -> Vec<PathBuf>;
+    /// The size of a file in bytes.
+    fn size(&self, path: &Path) -> IoResult<u64>;
+    /// When a file was last written.
+    fn modified(&self, path: &Path) -> IoResult<SystemTime>;
     fn remove(&self, path: &Path) -> IoResult<()>;
+    /// Removes a directory and everything in it, doing nothing if it doesn't
+    /// exist.  The directory disappears at once: nothing sees it half
+    /// removed.
+    fn remove_dir(&self, path: &Path) -> IoResult<()>;
     fn is_empty(&self) -> bool {
         self.files().is_empty()
     }
@@ -41,11 +55,46 @@ pub trait FileSystem {
     }
 }
 
+/// A file in a `FakeFileSystem` that is appended to as it is written.
+struct FakeFile {
+    path: PathBuf,
+    mapping: Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>,
+    modified: Arc<Mutex<HashMap<PathBuf, SystemTime>>>,
+}
+
+/// Fake modification times come from a clock that ticks on every write, so
+/// that two writes never share a time.
+fn fake_now() -> SystemTime {
+    static CLOCK: AtomicU64 = AtomicU64::new(1);
+    UNIX_EPOCH + Duration::from_nanos(CLOCK.fetch_add(1, Ordering::SeqCst))
+}
+
+impl Write for FakeFile {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.modified
+            .lock()
+            .unwrap()
+            .insert(self.path.clone(), fake_now());
+        self.mapping
+            .lock()
+            .unwrap()
+            .entry(self.path.clone())
+            .or_default()
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
 impl FakeFileSystem {
     pub fn new() -> Self {
         FakeFileSystem {
             root: PathBuf::from("/"),
-            mapping: Rc::new(RefCell::new(HashMap::new())),
+            mapping: Arc::new(Mutex::new(HashMap::new())),
+            modified: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 }
@@ -67,11 +116,43 @@ impl FileSystem for RealFileSystem {
         Box::new(self.clone())
     }
 
+    fn remove_dir(&self, path: &Path) -> IoResult<()> {
+        static REMOVALS: AtomicUsize = AtomicUsize::new(0);
+        let path = self.root.join(path);
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => return Err(IoError::new(ErrorKind::InvalidInput, "no directory name")),
+        };
+        // Renaming is atomic, so the directory is moved out of the way
+        // before it is deleted.
+        let doomed = path.with_file_name(format!(
+            ".{}.removing-{}-{}",
+            name,
+            ::std::process::id(),
+            REMOVALS.fetch_add(1, Ordering::SeqCst)
+        ));
+        match rename(&path, &doomed) {
+            Ok(()) => remove_dir_all(doomed),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
     fn exists(&self, path: &Path) -> bool {
         let path = self.root.join(path);
         path.exists()
     }
 
+    fn size(&self, path: &Path) -> IoResult<u64> {
+        let path = self.root.join(path);
+        path.metadata().map(|m| m.len())
+    }
+
+    fn modified(&self, path: &Path) -> IoResult<SystemTime> {
+        let path = self.root.join(path);
+        path.metadata()?.modified()
+    }
+
     fn read(&self, path: &Path, f: &mut FnMut(&mut ReadSeek) -> IoResult<()>) -> IoResult<()> {
         let path = self.root.join(path);
         match File::open(path) {
@@ -96,6 +177,12 @@ impl FileSystem for RealFileSystem {
         }
     }
 
+    fn create(&self, path: &Path) -> IoResult<Box<Write>> {
+        let path = self.root.join(path);
+        create_dir_all(path.parent().unwrap())?;
+        Ok(Box::new(BufWriter::new(File::create(path)?)))
+    }
+
     fn full_path_for(&self, path: &Path) -> PathBuf {
         self.root.join(path)
     }
@@ -124,19 +211,51 @@ impl FileSystem for FakeFileSystem {
 
     fn exists(&self, path: &Path) -> bool {
         let path = self.root.join(path);
-        self.mapping.borrow().contains_key(&path)
+        self.mapping.lock().unwrap().contains_key(&path)
+    }
+
+    fn size(&self, path: &Path) -> IoResult<u64> {
+        let path = self.root.join(path);
+        match self.mapping.lock().unwrap().get(&path) {
+            Some(contents) => Ok(contents.len() as u64),
+            None => Err(IoError::new(
+                ErrorKind::NotFound,
+                format!("{:?} does not exist", path),
+            )),
+        }
+    }
+
+    fn modified(&self, path: &Path) -> IoResult<SystemTime> {
+        let path = self.root.join(path);
+        let exists = self.mapping.lock().unwrap().contains_key(&path);
+        match self.modified.lock().unwrap().get(&path) {
+            Some(&time) if exists => Ok(time),
+            _ => Err(IoError::new(
+                ErrorKind::NotFound,
+                format!("{:?} does not exist", path),
+            )),
+        }
     }
 
     fn remove(&self, path: &Path) -> IoResult<()> {
         let path = self.root.join(path);
-        self.mapping.borrow_mut().remove(&path);
+        self.mapping.lock().unwrap().remove(&path);
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> IoResult<()> {
+        let path = self.root.join(path);
+        self.mapping
+            .lock()
+            .unwrap()
+            .retain(|file, _| !file.starts_with(&path));
         Ok(())
     }
 
     fn read(&self, path: &Path, f: &mut FnMut(&mut ReadSeek) -> IoResult<()>) -> IoResult<()> {
         let path = self.root.join(path);
 
-        let contents = match self.mapping.borrow().get(&path) {
+        let contents = match self.mapping.lock().unwrap().get(&path) {
             Some(contents) => contents.clone(),
             None => {
                 return Err(IoError::new(
@@ -155,10 +274,22 @@ impl FileSystem for FakeFileSystem {
         let mut contents = vec![];
         f(&mut contents)?;
 
-        self.mapping.borrow_mut().insert(path, contents);
+        self.modified.lock().unwrap().insert(path.clone(), fake_now());
+        self.mapping.lock().unwrap().insert(path, contents);
         Ok(())
     }
 
+    fn create(&self, path: &Path) -> IoResult<Box<Write>> {
+        let path = self.root.join(path);
+        self.modified.lock().unwrap().insert(path.clone(), fake_now());
+        self.mapping.lock().unwrap().insert(path.clone(), vec![]);
+        Ok(Box::new(FakeFile {
+            path,
+            mapping: self.mapping.clone(),
+            modified: self.modified.clone(),
+        }))
+    }
+
     fn full_path_for(&self, path: &Path) -> PathBuf {
         self.root.join(path)
     }
@@ -166,7 +297,8 @@ impl FileSystem for FakeFileSystem {
     fn files(&self) -> Vec<PathBuf> {
         let root = self.root.clone();
         self.mapping
-            .borrow()
+            .lock()
+            .unwrap()
             .keys()
             .filter_map(|p| p.strip_prefix(&root).ok())
             .map(|p| p.into())
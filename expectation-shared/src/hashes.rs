@@ -0,0 +1,181 @@
+use filesystem::FileSystem;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Result as IoResult};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The name of the manifest inside `expected/<test>/`.
+pub const MANIFEST: &str = ".hashes";
+
+/// The directory inside `actual/` that holds the stat cache of each test.
+pub const STAT_CACHE: &str = ".stat-cache";
+
+/// What the manifest knows about an expected file: the SHA-256 and the
+/// length of its contents.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub hash: String,
+    pub size: u64,
+}
+
+impl Entry {
+    /// Hashes the file at `path`.
+    pub fn for_file(fs: &FileSystem, path: &Path) -> IoResult<Entry> {
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        fs.read(path, &mut |r| {
+            let mut buf = [0; 64 * 1024];
+            loop {
+                match r.read(&mut buf)? {
+                    0 => return Ok(()),
+                    n => {
+                        hasher.update(&buf[..n]);
+                        size += n as u64;
+                    }
+                }
+            }
+        })?;
+        Ok(Entry {
+            hash: format!("{:x}", hasher.finalize()),
+            size,
+        })
+    }
+}
+
+/// The SHA-256 hashes of the expected files of a test, stored next to them
+/// one per line as `<hash>  <size>  <file>`.  The manifest is written when
+/// files are promoted, and only read when they are validated.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HashManifest {
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl HashManifest {
+    /// Reads the manifest in `dir`.  A missing or unreadable manifest is
+    /// empty, and lines that can't be read are skipped.
+    pub fn load(fs: &FileSystem, dir: &Path) -> HashManifest {
+        let mut manifest = HashManifest::default();
+        for (file, fields) in read_lines(fs, &dir.join(MANIFEST), 2) {
+            if let Ok(size) = fields[1].parse() {
+                let hash = fields[0].clone();
+                manifest.entries.insert(file, Entry { hash, size });
+            }
+        }
+        manifest
+    }
+
+    /// Writes the manifest to `dir`, or removes it when it is empty.
+    pub fn save(&self, fs: &FileSystem, dir: &Path) -> IoResult<()> {
+        let path = dir.join(MANIFEST);
+        if self.entries.is_empty() {
+            return if fs.exists(&path) {
+                fs.remove(&path)
+            } else {
+                Ok(())
+            };
+        }
+        fs.write(&path, &mut |w| {
+            for (file, entry) in &self.entries {
+                writeln!(w, "{}  {}  {}", entry.hash, entry.size, file.display())?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn get(&self, file: &Path) -> Option<&Entry> {
+        self.entries.get(file)
+    }
+
+    pub fn insert<P: Into<PathBuf>>(&mut self, file: P, entry: Entry) {
+        self.entries.insert(file.into(), entry);
+    }
+
+    pub fn remove(&mut self, file: &Path) -> Option<Entry> {
+        self.entries.remove(file)
+    }
+}
+
+/// The size and modification time that the expected files of a test had
+/// when they were last found to have a hash, so that unchanged files aren't
+/// hashed again.  Unlike the manifest, the cache only describes the files of
+/// one checkout, so it is kept with the actual files, which aren't committed.
+/// It is stored one line per file as `<hash>  <size>  <modified>  <file>`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StatCache {
+    entries: BTreeMap<PathBuf, (String, Stamp)>,
+}
+
+/// The size of a file and its modification time in nanoseconds since the
+/// Unix epoch.
+type Stamp = (u64, u64);
+
+fn stamp(fs: &FileSystem, path: &Path) -> IoResult<Stamp> {
+    let modified = fs
+        .modified(path)?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    Ok((fs.size(path)?, modified))
+}
+
+impl StatCache {
+    /// Reads the cache at `path`.  A missing or unreadable cache is empty.
+    pub fn load(fs: &FileSystem, path: &Path) -> StatCache {
+        let mut cache = StatCache::default();
+        for (file, fields) in read_lines(fs, path, 3) {
+            if let (Ok(size), Ok(modified)) = (fields[1].parse(), fields[2].parse()) {
+                let hash = fields[0].clone();
+                cache.entries.insert(file, (hash, (size, modified)));
+            }
+        }
+        cache
+    }
+
+    pub fn save(&self, fs: &FileSystem, path: &Path) -> IoResult<()> {
+        fs.write(path, &mut |w| {
+            for (file, (hash, (size, modified))) in &self.entries {
+                writeln!(w, "{}  {}  {}  {}", hash, size, modified, file.display())?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Whether the file at `path` hashed to `hash` when it was recorded, and
+    /// still has the size and modification time it had then.
+    pub fn confirms(&self, fs: &FileSystem, path: &Path, hash: &str) -> bool {
+        match self.entries.get(path) {
+            Some((h, recorded)) => h == hash && stamp(fs, path).ok() == Some(*recorded),
+            None => false,
+        }
+    }
+
+    /// Records that the file at `path` hashes to `hash` as it is now.
+    pub fn record(&mut self, fs: &FileSystem, path: &Path, hash: &str) -> IoResult<()> {
+        let stamp = stamp(fs, path)?;
+        self.entries.insert(path.into(), (hash.into(), stamp));
+        Ok(())
+    }
+}
+
+/// Reads the lines of a manifest or cache at `path` as a file name after
+/// `fields` other fields, all separated by two spaces.  Lines without enough
+/// fields are skipped.
+fn read_lines(fs: &FileSystem, path: &Path, fields: usize) -> Vec<(PathBuf, Vec<String>)> {
+    let mut out = vec![];
+    if !fs.exists(path) {
+        return out;
+    }
+    let _ = fs.read(path, &mut |r| {
+        for line in r.lines() {
+            let line = line?;
+            let mut parts: Vec<String> = line.splitn(fields + 1, "  ").map(String::from).collect();
+            if parts.len() == fields + 1 {
+                let file = parts.pop().unwrap();
+                out.push((file.into(), parts));
+            }
+        }
+        Ok(())
+    });
+    out
+}
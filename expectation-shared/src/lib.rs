@@ -2,8 +2,19 @@
 extern crate serde_derive;
 extern crate serde;
 extern crate walkdir;
+extern crate ignore;
+extern crate sha2;
+#[cfg(feature = "gzip")]
+extern crate flate2;
+#[cfg(feature = "zstd")]
+extern crate zstd;
 
+pub mod compressed;
+pub mod expectignore;
 pub mod filesystem;
+pub mod hashes;
+pub mod objects;
+pub mod size;
 
 use std::path::PathBuf;
 
@@ -21,6 +32,24 @@ pub struct Difference {
     pub html: Option<String>,
 }
 
+/// A file in the actual directory of a test that the test didn't register.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Untracked {
+    pub actual: PathBuf,
+    /// Whether the file fails the test, or is only a warning.
+    pub fatal: bool,
+}
+
+/// A file, or all the files of a test, that is bigger than a size budget
+/// allows.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct OverBudget {
+    pub size: u64,
+    pub limit: u64,
+    /// Whether the error limit was exceeded, rather than the warning limit.
+    pub fatal: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum ResultKind {
     Ok,
@@ -28,12 +57,19 @@ pub enum ResultKind {
     ActualNotFound(Double),
     Difference(Difference),
     IoError(String),
+    Untracked(Untracked),
+    /// The file name is empty when the budget is for the whole test.
+    OverBudget(OverBudget),
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Result {
     pub test_name: String,
     pub file_name: PathBuf,
+    /// The size in bytes of the actual file, for the results of files that
+    /// were written.
+    #[serde(default)]
+    pub size: Option<u64>,
     pub kind: ResultKind,
 }
 
@@ -45,13 +81,24 @@ pub enum Message {
 }
 
 impl Result {
+    /// Whether the file passed, possibly with a warning.
     pub fn is_ok(&self) -> bool {
         match &self.kind {
             ResultKind::Ok => true,
+            ResultKind::Untracked(untracked) => !untracked.fatal,
+            ResultKind::OverBudget(budget) => !budget.fatal,
             _ => false,
         }
     }
 
+    /// The same result, with the size of the actual file.
+    pub fn with_size(self, size: u64) -> Self {
+        Result {
+            size: Some(size),
+            ..self
+        }
+    }
+
     pub fn ok<N, P>(name: N, file: P) -> Self
     where
         N: Into<String>,
@@ -60,6 +107,7 @@ impl Result {
         Result {
             test_name: name.into(),
             file_name: file.into(),
+            size: None,
             kind: ResultKind::Ok,
         }
     }
@@ -74,6 +122,7 @@ impl Result {
         Result {
             test_name: name.into(),
             file_name: file.into(),
+            size: None,
             kind: ResultKind::ExpectedNotFound(Double {
                 actual: actual.into(),
                 expected: expected.into(),
@@ -91,6 +140,7 @@ impl Result {
         Result {
             test_name: name.into(),
             file_name: file.into(),
+            size: None,
             kind: ResultKind::ActualNotFound(Double {
                 actual: actual.into(),
                 expected: expected.into(),
@@ -115,6 +165,7 @@ impl Result {
         Result {
             test_name: name.into(),
             file_name: file.into(),
+            size: None,
             kind: ResultKind::Difference(Difference {
                 actual: actual.into(),
                 expected: expected.into(),
@@ -124,6 +175,36 @@ impl Result {
         }
     }
 
+    pub fn untracked<N, P1, P2>(name: N, file: P1, actual: P2, fatal: bool) -> Self
+    where
+        N: Into<String>,
+        P1: Into<PathBuf>,
+        P2: Into<PathBuf>,
+    {
+        Result {
+            test_name: name.into(),
+            file_name: file.into(),
+            size: None,
+            kind: ResultKind::Untracked(Untracked {
+                actual: actual.into(),
+                fatal,
+            }),
+        }
+    }
+
+    pub fn over_budget<N, P>(name: N, file: P, size: u64, limit: u64, fatal: bool) -> Self
+    where
+        N: Into<String>,
+        P: Into<PathBuf>,
+    {
+        Result {
+            test_name: name.into(),
+            file_name: file.into(),
+            size: None,
+            kind: ResultKind::OverBudget(OverBudget { size, limit, fatal }),
+        }
+    }
+
     pub fn io_error<N, P>(name: N, file: P, io_error: std::io::Error) -> Self
     where
         N: Into<String>,
@@ -132,6 +213,7 @@ impl Result {
         Result {
             test_name: name.into(),
             file_name: file.into(),
+            size: None,
             kind: ResultKind::IoError(format!("{:?}", io_error)),
         }
     }
@@ -0,0 +1,299 @@
+use filesystem::{FileSystem, ReadSeek};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io::{BufRead, Error as IoError, ErrorKind, Result as IoResult, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The directory next to `expected/` that holds the shared objects.
+pub const OBJECTS: &str = "objects";
+
+/// The extension of the files that point to an object in place of an
+/// expected file: `foo.png` is kept as `foo.png.object`.
+pub const POINTER_EXTENSION: &str = ".object";
+
+/// The contents of a pointer file: the SHA-256 and size of the object it
+/// points to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pointer {
+    pub hash: String,
+    pub size: u64,
+}
+
+impl Pointer {
+    pub fn for_contents(contents: &[u8]) -> Pointer {
+        Pointer {
+            hash: format!("{:x}", Sha256::digest(contents)),
+            size: contents.len() as u64,
+        }
+    }
+
+    /// Where the object is kept in the objects directory: the first two
+    /// characters of the hash name a subdirectory, as in git.
+    pub fn object_path(&self) -> PathBuf {
+        Path::new(&self.hash[..2]).join(&self.hash[2..])
+    }
+
+    pub fn read(r: &mut BufRead) -> IoResult<Pointer> {
+        let (mut hash, mut size) = (None, None);
+        for line in r.lines() {
+            let line = line?;
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some("sha256"), Some(h)) => hash = Some(h.to_lowercase()),
+                (Some("size"), Some(s)) => size = s.parse().ok(),
+                _ => {}
+            }
+        }
+        match (hash, size) {
+            (Some(hash), Some(size)) if is_hash(&hash) => Ok(Pointer { hash, size }),
+            _ => Err(IoError::new(ErrorKind::InvalidData, "malformed object pointer")),
+        }
+    }
+
+    pub fn write(&self, w: &mut Write) -> IoResult<()> {
+        write!(w, "sha256 {}\nsize {}\n", self.hash, self.size)
+    }
+}
+
+fn is_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// The path of the pointer file that stands in for `path`.
+pub fn pointer_path(path: &Path) -> PathBuf {
+    let mut pointer = path.as_os_str().to_owned();
+    pointer.push(POINTER_EXTENSION);
+    pointer.into()
+}
+
+/// Stores files in a content-addressed object store, so that identical
+/// files are kept once: each file is a pointer to an object named by the
+/// hash of its contents.
+///
+/// Pointers stay pointers when they are rewritten, and other files become
+/// pointers when they are written only if `dedup` is set.  Files are hashed
+/// in memory before they are stored as objects.
+pub struct ObjectFileSystem {
+    inner: Box<FileSystem>,
+    objects: Box<FileSystem>,
+    dedup: bool,
+}
+
+impl ObjectFileSystem {
+    pub fn new(inner: Box<FileSystem>, objects: Box<FileSystem>, dedup: bool) -> ObjectFileSystem {
+        ObjectFileSystem {
+            inner,
+            objects,
+            dedup,
+        }
+    }
+
+    fn is_pointer(&self, path: &Path) -> bool {
+        self.inner.exists(&pointer_path(path))
+    }
+
+    fn pointer(&self, path: &Path) -> IoResult<Pointer> {
+        let mut pointer = None;
+        self.inner.read(&pointer_path(path), &mut |r| {
+            pointer = Some(Pointer::read(r)?);
+            Ok(())
+        })?;
+        Ok(pointer.unwrap())
+    }
+
+    /// Whether `path` is written as a pointer.
+    fn dedups(&self, path: &Path) -> bool {
+        self.dedup || self.is_pointer(path)
+    }
+}
+
+/// Adds `contents` to the store, unless an identical object is already
+/// there, and points `path` at it.
+fn store(inner: &FileSystem, objects: &FileSystem, path: &Path, contents: &[u8]) -> IoResult<()> {
+    let pointer = Pointer::for_contents(contents);
+    let object = pointer.object_path();
+    if !objects.exists(&object) {
+        objects.write(&object, &mut |w| w.write_all(contents))?;
+    }
+    inner.write(&pointer_path(path), &mut |w| pointer.write(w))
+}
+
+/// Buffers a file that is stored, through `ObjectFileSystem::write`, when
+/// the writer is flushed.  Whatever wasn't flushed is stored when the writer
+/// is dropped, where errors can't be reported, as with `BufWriter`.
+struct ObjectWriter {
+    fs: Box<FileSystem>,
+    path: PathBuf,
+    contents: Vec<u8>,
+    stored: bool,
+}
+
+impl Write for ObjectWriter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.stored = false;
+        self.contents.write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        if self.stored {
+            return Ok(());
+        }
+        let contents = &self.contents;
+        self.fs.write(&self.path, &mut |w| w.write_all(contents))?;
+        self.stored = true;
+        Ok(())
+    }
+}
+
+impl Drop for ObjectWriter {
+    fn drop(&mut self) {
+        if !self.stored {
+            let contents = mem::take(&mut self.contents);
+            let _ = self.fs.write(&self.path, &mut |w| w.write_all(&contents));
+        }
+    }
+}
+
+impl FileSystem for ObjectFileSystem {
+    fn duplicate(&self) -> Box<FileSystem> {
+        Box::new(ObjectFileSystem {
+            inner: self.inner.duplicate(),
+            objects: self.objects.duplicate(),
+            dedup: self.dedup,
+        })
+    }
+
+    fn subsystem(&self, path: &Path) -> Box<FileSystem> {
+        Box::new(ObjectFileSystem {
+            inner: self.inner.subsystem(path),
+            objects: self.objects.duplicate(),
+            dedup: self.dedup,
+        })
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.is_pointer(path) || self.inner.exists(path)
+    }
+
+    fn read(&self, path: &Path, f: &mut FnMut(&mut ReadSeek) -> IoResult<()>) -> IoResult<()> {
+        if self.is_pointer(path) {
+            self.objects.read(&self.pointer(path)?.object_path(), f)
+        } else {
+            self.inner.read(path, f)
+        }
+    }
+
+    fn write(&self, path: &Path, f: &mut FnMut(&mut Write) -> IoResult<()>) -> IoResult<()> {
+        if !self.dedups(path) {
+            return self.inner.write(path, f);
+        }
+        let mut contents = vec![];
+        f(&mut contents)?;
+        store(&*self.inner, &*self.objects, path, &contents)?;
+        if self.inner.exists(path) {
+            self.inner.remove(path)?;
+        }
+        Ok(())
+    }
+
+    /// Pointers are only written once the returned writer is flushed, which
+    /// reports errors in storing the file.  The old file stays in place
+    /// until then.
+    fn create(&self, path: &Path) -> IoResult<Box<Write>> {
+        if !self.dedups(path) {
+            return self.inner.create(path);
+        }
+        Ok(Box::new(ObjectWriter {
+            fs: self.duplicate(),
+            path: path.to_owned(),
+            contents: vec![],
+            stored: false,
+        }))
+    }
+
+    /// The plain path, which is what the file is reported as.
+    fn full_path_for(&self, path: &Path) -> PathBuf {
+        self.inner.full_path_for(path)
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<_> = self
+            .inner
+            .files()
+            .into_iter()
+            .map(|file| {
+                let plain = file
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(POINTER_EXTENSION))
+                    .filter(|plain| !plain.is_empty() && !plain.ends_with('/'))
+                    .map(PathBuf::from);
+                plain.unwrap_or(file)
+            })
+            .collect();
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    /// The size of the object that a pointer points to.
+    fn size(&self, path: &Path) -> IoResult<u64> {
+        if self.is_pointer(path) {
+            self.pointer(path).map(|p| p.size)
+        } else {
+            self.inner.size(path)
+        }
+    }
+
+    /// When the pointer, or the plain file, was last written.
+    fn modified(&self, path: &Path) -> IoResult<SystemTime> {
+        if self.is_pointer(path) {
+            self.inner.modified(&pointer_path(path))
+        } else {
+            self.inner.modified(path)
+        }
+    }
+
+    /// Removing a pointer leaves its object for `collect_garbage`.
+    fn remove(&self, path: &Path) -> IoResult<()> {
+        let pointer = pointer_path(path);
+        if self.inner.exists(&pointer) {
+            self.inner.remove(&pointer)?;
+            if !self.inner.exists(path) {
+                return Ok(());
+            }
+        }
+        self.inner.remove(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> IoResult<()> {
+        self.inner.remove_dir(path)
+    }
+}
+
+/// Removes the objects that no pointer in `expected` points to, returning
+/// their paths in `objects`.  Nothing is removed if a pointer can't be read.
+pub fn collect_garbage(expected: &FileSystem, objects: &FileSystem) -> IoResult<Vec<PathBuf>> {
+    let mut referenced = HashSet::new();
+    for file in expected.files() {
+        if !file.to_string_lossy().ends_with(POINTER_EXTENSION) {
+            continue;
+        }
+        expected.read(&file, &mut |r| {
+            referenced.insert(Pointer::read(r)?.object_path());
+            Ok(())
+        })?;
+    }
+
+    let mut removed = vec![];
+    let mut files = objects.files();
+    files.sort();
+    for object in files {
+        if !referenced.contains(&object) {
+            objects.remove(&object)?;
+            removed.push(object);
+        }
+    }
+    Ok(removed)
+}
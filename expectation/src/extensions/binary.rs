@@ -0,0 +1,325 @@
+use super::super::provider::{Provider, WriteRequester};
+use super::super::*;
+use super::{add_extension, escape_html, streams_equal};
+use expectation_shared::filesystem::ReadSeek;
+
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, Read, Result as IoResult, SeekFrom, Write};
+use std::path::Path;
+
+/// The number of bytes shown on each row of a hex dump.
+const ROW: usize = 16;
+
+pub trait BinaryDiffExtension {
+    /// Returns a writer whose contents are compared byte for byte and shown
+    /// as a hex dump when they differ.
+    fn binary_writer<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>;
+
+    fn bytes<N>(&self, filename: N, bytes: &[u8]) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+    {
+        let mut w = self.binary_writer(filename);
+        w.write_all(bytes)
+    }
+}
+
+impl BinaryDiffExtension for Provider {
+    fn binary_writer<S>(&self, filename: S) -> Writer
+    where
+        S: AsRef<Path>,
+    {
+        self.custom_test_parallel(
+            filename,
+            |a, b| binary_eq(a, b),
+            |a, b, c, d| binary_diff(a, b, c, d),
+        )
+    }
+}
+
+fn binary_eq<R1: BufRead, R2: BufRead>(r1: R1, r2: R2) -> IoResult<bool> {
+    streams_equal(r1, r2)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ByteChange {
+    Same,
+    Changed,
+    OnlyActual,
+    OnlyExpected,
+}
+
+fn byte_change(actual: &[u8], expected: &[u8], offset: usize) -> ByteChange {
+    match (actual.get(offset), expected.get(offset)) {
+        (Some(a), Some(e)) if a == e => ByteChange::Same,
+        (Some(_), Some(_)) => ByteChange::Changed,
+        (Some(_), None) => ByteChange::OnlyActual,
+        (None, Some(_)) => ByteChange::OnlyExpected,
+        (None, None) => ByteChange::Same,
+    }
+}
+
+/// A run of consecutive bytes that changed in the same way.
+struct Region {
+    start: u64,
+    end: u64,
+    change: ByteChange,
+}
+
+/// What is known about two files after a single streaming pass over them.
+struct Comparison {
+    actual_len: u64,
+    expected_len: u64,
+    regions: Vec<Region>,
+}
+
+/// The number of bytes compared at a time.
+const CHUNK: usize = 64 * 1024;
+
+/// Reads until `buf` is full or the reader is exhausted.
+fn read_chunk<R: Read>(r: &mut R, buf: &mut [u8]) -> IoResult<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn compare<R1: Read, R2: Read>(mut r1: R1, mut r2: R2) -> IoResult<Comparison> {
+    let mut out = Comparison {
+        actual_len: 0,
+        expected_len: 0,
+        regions: vec![],
+    };
+    let (mut a, mut e) = (vec![0; CHUNK], vec![0; CHUNK]);
+    loop {
+        let (na, ne) = (read_chunk(&mut r1, &mut a)?, read_chunk(&mut r2, &mut e)?);
+        if na == 0 && ne == 0 {
+            return Ok(out);
+        }
+        let base = out.actual_len.max(out.expected_len);
+        for i in 0..na.max(ne) {
+            let change = byte_change(&a[..na], &e[..ne], i);
+            if change == ByteChange::Same {
+                continue;
+            }
+            let offset = base + i as u64;
+            match out.regions.last_mut() {
+                Some(r) if r.end == offset && r.change == change => r.end += 1,
+                _ => out.regions.push(Region {
+                    start: offset,
+                    end: offset + 1,
+                    change,
+                }),
+            }
+        }
+        out.actual_len += na as u64;
+        out.expected_len += ne as u64;
+    }
+}
+
+impl Comparison {
+    /// The rows of the hex dump that are shown: every row with a change, plus
+    /// one row of context on either side.
+    fn shown_rows(&self) -> Vec<u64> {
+        let (row, rows) = (ROW as u64, self.rows());
+        let mut shown: Vec<u64> = vec![];
+        for r in &self.regions {
+            let first = (r.start / row).saturating_sub(1);
+            let last = ((r.end - 1) / row + 1).min(rows - 1);
+            let from = match shown.last() {
+                Some(&l) if l >= first => l + 1,
+                _ => first,
+            };
+            shown.extend(from..=last);
+        }
+        shown
+    }
+
+    fn rows(&self) -> u64 {
+        let row = ROW as u64;
+        self.actual_len.max(self.expected_len).div_ceil(row)
+    }
+}
+
+/// The bytes of one row of the hex dump on both sides.
+struct Row {
+    index: u64,
+    actual: Vec<u8>,
+    expected: Vec<u8>,
+}
+
+fn read_row<R: ReadSeek>(r: &mut R, index: u64) -> IoResult<Vec<u8>> {
+    let mut bytes = vec![];
+    r.seek(SeekFrom::Start(index * ROW as u64))?;
+    r.take(ROW as u64).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn hex_row(bytes: &[u8], row: u64) -> String {
+    let mut hex = String::new();
+    let mut ascii = String::new();
+    for offset in 0..ROW {
+        if offset == ROW / 2 {
+            hex.push(' ');
+        }
+        match bytes.get(offset) {
+            Some(&b) => {
+                write!(hex, "{:02x} ", b).unwrap();
+                ascii.push(if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                });
+            }
+            None => hex.push_str("   "),
+        }
+    }
+    format!("{:08x}  {} |{}|", row * ROW as u64, hex, ascii)
+}
+
+fn marker_row(row: &Row) -> String {
+    let mut out = String::from("          ");
+    for offset in 0..ROW {
+        if offset == ROW / 2 {
+            out.push(' ');
+        }
+        out.push_str(
+            if byte_change(&row.actual, &row.expected, offset) == ByteChange::Same {
+                "   "
+            } else {
+                "^^ "
+            },
+        );
+    }
+    out.trim_end().to_owned()
+}
+
+fn hex_dump(rows: &[Row], total: u64) -> String {
+    let mut out = String::new();
+    let mut next = 0;
+    for row in rows {
+        if row.index > next {
+            out.push_str(" ...\n");
+        }
+        next = row.index + 1;
+        let (a, e) = (
+            hex_row(&row.actual, row.index),
+            hex_row(&row.expected, row.index),
+        );
+        if a == e {
+            writeln!(out, " {}", a).unwrap();
+        } else {
+            writeln!(out, "+{}", a).unwrap();
+            writeln!(out, "-{}", e).unwrap();
+            writeln!(out, " {}", marker_row(row)).unwrap();
+        }
+    }
+    if total > next {
+        out.push_str(" ...\n");
+    }
+    out
+}
+
+fn summary(comparison: &Comparison) -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "length: actual {}, expected {}",
+        comparison.actual_len, comparison.expected_len
+    ).unwrap();
+    if let Some(first) = comparison.regions.first() {
+        writeln!(out, "first difference at offset 0x{:08x}", first.start).unwrap();
+    }
+    writeln!(out, "changed regions: {}", comparison.regions.len()).unwrap();
+    for r in &comparison.regions {
+        let what = match r.change {
+            ByteChange::Changed => "changed",
+            ByteChange::OnlyActual => "only in actual",
+            ByteChange::OnlyExpected => "only in expected",
+            ByteChange::Same => unreachable!(),
+        };
+        writeln!(
+            out,
+            "  0x{:08x}..0x{:08x}: {} bytes {}",
+            r.start,
+            r.end,
+            r.end - r.start,
+            what
+        ).unwrap();
+    }
+    out
+}
+
+/// Renders the shown rows of one side of the diff as a table, with changed
+/// bytes highlighted.
+fn hex_table(rows: &[Row], actual: bool) -> String {
+    let mut html = String::from(r#"<table style="font-family: monospace">"#);
+    for row in rows {
+        let (bytes, other) = if actual {
+            (&row.actual, &row.expected)
+        } else {
+            (&row.expected, &row.actual)
+        };
+        write!(html, "<tr><td>{:08x}</td><td>", row.index * ROW as u64).unwrap();
+        let mut ascii = String::new();
+        for (offset, &b) in bytes.iter().enumerate() {
+            let c = if b.is_ascii_graphic() || b == b' ' {
+                escape_html(&(b as char).to_string())
+            } else {
+                ".".to_owned()
+            };
+            if byte_change(bytes, other, offset) == ByteChange::Same {
+                write!(html, "{:02x} ", b).unwrap();
+                ascii.push_str(&c);
+            } else {
+                write!(html, "<mark>{:02x}</mark> ", b).unwrap();
+                write!(ascii, "<mark>{}</mark>", c).unwrap();
+            }
+        }
+        write!(html, "</td><td>{}</td></tr>", ascii).unwrap();
+    }
+    html.push_str("</table>");
+    html
+}
+
+/// Compares the files in one streaming pass, then seeks back to read only
+/// the rows that end up in the hex dump.
+fn binary_diff<R1: ReadSeek, R2: ReadSeek>(
+    mut r1: R1,
+    mut r2: R2,
+    path: &Path,
+    write_requester: &mut WriteRequester,
+) -> IoResult<()> {
+    let comparison = compare(&mut r1, &mut r2)?;
+    let mut rows = vec![];
+    for index in comparison.shown_rows() {
+        rows.push(Row {
+            index,
+            actual: read_row(&mut r1, index)?,
+            expected: read_row(&mut r2, index)?,
+        });
+    }
+
+    let summary = summary(&comparison);
+    let dump = hex_dump(&rows, comparison.rows());
+    write_requester.request(add_extension(path, ".diff"), |w| {
+        write!(w, "{}\n{}", summary, dump)
+    })?;
+
+    write_requester.set_html_renderer(move |_, _, _| {
+        format!(
+            "<h3> Actual </h3>{}<h3> Expected </h3>{}<pre>{}</pre>",
+            hex_table(&rows, true),
+            hex_table(&rows, false),
+            escape_html(&summary)
+        )
+    });
+
+    Ok(())
+}
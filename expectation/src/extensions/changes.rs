@@ -0,0 +1,64 @@
+use super::escape_html;
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// A single structural difference between the expected and actual document,
+/// located by a path into it like `a.b[3].c` or `/svg/g/rect[2]@width`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Change<V> {
+    Changed { path: String, expected: V, actual: V },
+    Added { path: String, actual: V },
+    Removed { path: String, expected: V },
+}
+
+impl<V> Change<V> {
+    fn path(&self) -> &str {
+        let path = match self {
+            Change::Changed { path, .. } => path,
+            Change::Added { path, .. } => path,
+            Change::Removed { path, .. } => path,
+        };
+        if path.is_empty() {
+            "(root)"
+        } else {
+            path
+        }
+    }
+}
+
+impl<V: Display> Display for Change<V> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Change::Changed {
+                expected, actual, ..
+            } => write!(f, "{}: {} → {}", self.path(), expected, actual),
+            Change::Added { actual, .. } => write!(f, "{}: + {}", self.path(), actual),
+            Change::Removed { expected, .. } => write!(f, "{}: - {}", self.path(), expected),
+        }
+    }
+}
+
+/// Renders the changes as a table of paths with their expected and actual
+/// values.
+pub(crate) fn changes_html<V: Display>(changes: &[Change<V>]) -> String {
+    let mut html = String::new();
+    html.push_str("<h3> Changes </h3>");
+    html.push_str("<table><tr><th>Path</th><th>Expected</th><th>Actual</th></tr>");
+    for change in changes {
+        let (expected, actual) = match change {
+            Change::Changed {
+                expected, actual, ..
+            } => (expected.to_string(), actual.to_string()),
+            Change::Added { actual, .. } => ("".into(), actual.to_string()),
+            Change::Removed { expected, .. } => (expected.to_string(), "".into()),
+        };
+        html.push_str(&format!(
+            "<tr><td><code>{}</code></td><td><code>{}</code></td><td><code>{}</code></td></tr>",
+            escape_html(change.path()),
+            escape_html(&expected),
+            escape_html(&actual)
+        ));
+    }
+    html.push_str("</table>");
+    html
+}
@@ -0,0 +1,350 @@
+use super::super::provider::{Provider, WriteRequester};
+use super::super::*;
+use super::{escape_html, streams_equal};
+use super::image::{
+    changed_pixels, compare_images, side_by_side, to_rgba16, write_png, ImageOptions, MaskSource,
+    Rgba16Image,
+};
+use expectation_shared::filesystem::ReadSeek;
+
+use image::{DynamicImage, GrayImage, ImageBuffer, Rgb, Rgba, RgbaImage};
+use png;
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, SeekFrom, Write};
+use std::path::Path;
+
+/// Animations, stored as APNGs.  These need the `apng` feature, which
+/// depends on a newer `png` than the one `image` uses.
+pub trait FramesDiffExtension {
+    /// Returns a writer for an animation stored as an APNG.  The frames are
+    /// compared one by one.
+    fn frames_writer_with<N>(&self, filename: N, options: ImageOptions) -> Writer
+    where
+        N: AsRef<Path>;
+
+    fn frames_writer<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>,
+    {
+        self.frames_writer_with(filename, ImageOptions::default())
+    }
+
+    /// Writes a sequence of frames as an APNG.  All frames must have the same
+    /// size.
+    fn frames<N, I>(&self, filename: N, frames: I) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        I: IntoIterator<Item = RgbaImage>,
+    {
+        self.frames_with(filename, ImageOptions::default(), frames)
+    }
+
+    fn frames_with<N, I>(&self, filename: N, options: ImageOptions, frames: I) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        I: IntoIterator<Item = RgbaImage>,
+    {
+        let frames: Vec<_> = frames.into_iter().collect();
+        let mut w = self.frames_writer_with(filename, options);
+        write_apng(&frames, &mut w)
+    }
+}
+
+impl FramesDiffExtension for Provider {
+    fn frames_writer_with<S>(&self, filename: S, options: ImageOptions) -> Writer
+    where
+        S: AsRef<Path>,
+    {
+        let eq_masks = MaskSource::new(self, filename.as_ref(), &options.masks);
+        let diff_masks = eq_masks.clone();
+        let diff_options = options.clone();
+        self.custom_test_parallel(
+            filename,
+            move |a, b| frames_eq(a, b, &options, &eq_masks),
+            move |a, b, c, d| frames_diff(a, b, c, d, &diff_options, &diff_masks),
+        )
+    }
+}
+
+/// The delay between frames of written animations, in seconds.
+const FRAME_DELAY: (u16, u16) = (1, 10);
+
+/// Writes `frames` as an APNG that loops forever.
+pub(crate) fn write_apng(frames: &[RgbaImage], w: &mut Write) -> IoResult<()> {
+    let (width, height) = match frames.first() {
+        Some(first) => first.dimensions(),
+        None => {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "an animation needs at least one frame",
+            ))
+        }
+    };
+    if frames.iter().any(|f| f.dimensions() != (width, height)) {
+        return Err(IoError::new(
+            ErrorKind::InvalidInput,
+            "all frames of an animation must have the same size",
+        ));
+    }
+
+    let mut encoder = png::Encoder::new(w, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, 0)?;
+    encoder.set_frame_delay(FRAME_DELAY.0, FRAME_DELAY.1)?;
+    let mut writer = encoder.write_header()?;
+    for frame in frames {
+        writer.write_image_data(frame)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Decodes every frame of an APNG (or the single frame of a plain PNG),
+/// compositing sub-frames onto the canvas like a viewer would.
+pub(crate) fn decode_frames<R: Read>(r: R) -> IoResult<Vec<RgbaImage>> {
+    let mut decoder = png::Decoder::new(r);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+
+    let (width, height) = reader.info().size();
+    let animation = reader.info().animation_control;
+    // Without an fcTL chunk before the image data, the default image is not
+    // part of the animation.
+    let skip_default = animation.is_some() && reader.info().frame_control.is_none();
+    let count = animation.map_or(1, |a| a.num_frames as usize) + skip_default as usize;
+
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let mut canvas = RgbaImage::new(width, height);
+    let mut frames = vec![];
+    for i in 0..count {
+        let output = reader.next_frame(&mut buf)?;
+        let sub = to_rgba8(&buf, &output)?;
+        if skip_default && i == 0 {
+            continue;
+        }
+
+        let control = reader.info().frame_control;
+        let (x0, y0) = control.map_or((0, 0), |c| (c.x_offset, c.y_offset));
+        let previous = canvas.clone();
+        for (x, y, p) in sub.enumerate_pixels() {
+            if x0 + x >= width || y0 + y >= height {
+                continue;
+            }
+            let pixel = match control.map(|c| c.blend_op) {
+                Some(png::BlendOp::Over) => blend_over(canvas.get_pixel(x0 + x, y0 + y), p),
+                _ => *p,
+            };
+            canvas.put_pixel(x0 + x, y0 + y, pixel);
+        }
+        frames.push(canvas.clone());
+
+        match control.map(|c| c.dispose_op) {
+            Some(png::DisposeOp::Background) => {
+                for (x, y, _) in sub.enumerate_pixels() {
+                    if x0 + x < width && y0 + y < height {
+                        canvas.put_pixel(x0 + x, y0 + y, Rgba([0, 0, 0, 0]));
+                    }
+                }
+            }
+            Some(png::DisposeOp::Previous) => canvas = previous,
+            _ => {}
+        }
+    }
+    Ok(frames)
+}
+
+fn to_rgba8(buf: &[u8], output: &png::OutputInfo) -> IoResult<RgbaImage> {
+    let channels = match output.color_type {
+        png::ColorType::Rgba => 4,
+        png::ColorType::Rgb => 3,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::Grayscale => 1,
+        png::ColorType::Indexed => {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "indexed frames were not expanded",
+            ))
+        }
+    };
+    Ok(ImageBuffer::from_fn(output.width, output.height, |x, y| {
+        let i = y as usize * output.line_size + x as usize * channels;
+        let p = &buf[i..i + channels];
+        match channels {
+            4 => Rgba([p[0], p[1], p[2], p[3]]),
+            3 => Rgba([p[0], p[1], p[2], 255]),
+            2 => Rgba([p[0], p[0], p[0], p[1]]),
+            _ => Rgba([p[0], p[0], p[0], 255]),
+        }
+    }))
+}
+
+fn blend_over(dst: &Rgba<u8>, src: &Rgba<u8>) -> Rgba<u8> {
+    let (sa, da) = (u32::from(src[3]), u32::from(dst[3]));
+    let out_a = sa * 255 + da * (255 - sa);
+    if out_a == 0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    let channel = |c: usize| {
+        ((u32::from(src[c]) * sa * 255 + u32::from(dst[c]) * da * (255 - sa)) / out_a) as u8
+    };
+    Rgba([channel(0), channel(1), channel(2), (out_a / 255) as u8])
+}
+
+/// The ignored pixels for each frame size, keyed by width and height.
+type MasksBySize = HashMap<(u32, u32), Option<GrayImage>>;
+
+/// Loads the masks once for every size among the expected frames, instead of
+/// once per frame.
+fn load_masks(masks: &MaskSource, expected: &[RgbaImage]) -> IoResult<MasksBySize> {
+    let mut out = HashMap::new();
+    for frame in expected {
+        if let Entry::Vacant(entry) = out.entry(frame.dimensions()) {
+            entry.insert(masks.load(frame.width(), frame.height())?);
+        }
+    }
+    Ok(out)
+}
+
+/// The indices of the frames that differ, including frames that only exist
+/// in one of the animations.
+fn differing_frames(
+    actual: &[RgbaImage],
+    expected: &[RgbaImage],
+    options: &ImageOptions,
+    masks: &MasksBySize,
+) -> IoResult<Vec<usize>> {
+    let mut out = vec![];
+    for i in 0..actual.len().max(expected.len()) {
+        let differs = match (actual.get(i), expected.get(i)) {
+            (Some(a), Some(e)) if a.dimensions() == e.dimensions() => {
+                let ignored = masks[&e.dimensions()].as_ref();
+                !compare_images(&wide(a), &wide(e), options, ignored).passed
+            }
+            _ => true,
+        };
+        if differs {
+            out.push(i);
+        }
+    }
+    Ok(out)
+}
+
+fn wide(frame: &RgbaImage) -> Rgba16Image {
+    to_rgba16(&DynamicImage::ImageRgba8(frame.clone()))
+}
+
+pub(crate) fn frames_eq<R1: ReadSeek, R2: ReadSeek>(
+    mut r1: R1,
+    mut r2: R2,
+    options: &ImageOptions,
+    masks: &MaskSource,
+) -> IoResult<bool> {
+    if streams_equal(&mut r1, &mut r2)? {
+        return Ok(true);
+    }
+    r1.seek(SeekFrom::Start(0))?;
+    r2.seek(SeekFrom::Start(0))?;
+    let actual = decode_frames(r1)?;
+    let expected = decode_frames(r2)?;
+    let masks = load_masks(masks, &expected)?;
+    Ok(differing_frames(&actual, &expected, options, &masks)?.is_empty())
+}
+
+pub(crate) fn frames_diff<R1: ReadSeek, R2: ReadSeek>(
+    r1: R1,
+    r2: R2,
+    path: &Path,
+    write_requester: &mut WriteRequester,
+    options: &ImageOptions,
+    masks: &MaskSource,
+) -> IoResult<()> {
+    let actual = decode_frames(r1)?;
+    let expected = decode_frames(r2)?;
+    let masks = load_masks(masks, &expected)?;
+    let differing = differing_frames(&actual, &expected, options, &masks)?;
+
+    let mut report = format!(
+        "frames: actual {}, expected {}\n",
+        actual.len(),
+        expected.len()
+    );
+    if let Some(first) = differing.first() {
+        report.push_str(&format!("first differing frame: {}\n", first));
+        let all: Vec<_> = differing.iter().map(|i| i.to_string()).collect();
+        report.push_str(&format!("differing frames: {}\n", all.join(", ")));
+    }
+    for &i in &differing {
+        match (actual.get(i), expected.get(i)) {
+            (Some(a), Some(e)) if a.dimensions() == e.dimensions() => {
+                let ignored = masks[&e.dimensions()].as_ref();
+                let comparison = compare_images(&wide(a), &wide(e), options, ignored);
+                report.push_str(&format!(
+                    "frame {}: {} of {} pixels differ ({:.4}%)\n",
+                    i,
+                    comparison.differing_pixels,
+                    comparison.total_pixels,
+                    comparison.differing_percent()
+                ));
+            }
+            (Some(a), Some(e)) => report.push_str(&format!(
+                "frame {}: size {}x{} (expected {}x{})\n",
+                i,
+                a.width(),
+                a.height(),
+                e.width(),
+                e.height()
+            )),
+            (Some(_), None) => report.push_str(&format!("frame {}: only in actual\n", i)),
+            (None, _) => report.push_str(&format!("frame {}: only in expected\n", i)),
+        }
+    }
+    write_requester.request(path.join("frames.txt"), |w| write!(w, "{}", report))?;
+
+    let mut captions = vec![];
+    for (i, (a, e)) in actual.iter().zip(&expected).enumerate() {
+        let ignored = masks[&e.dimensions()].as_ref();
+        let changed: GrayImage = changed_pixels(&wide(a), &wide(e), options.metric, ignored);
+        let panel = DynamicImage::ImageRgb8(side_by_side(a, e, &changed, ignored, |p| {
+            Rgb([p[0], p[1], p[2]])
+        }));
+        write_requester.request(path.join(format!("frame-{:04}.png", i)), |w| {
+            write_png(&panel, w)
+        })?;
+        captions.push(if differing.contains(&i) {
+            format!("Frame {} (differs)", i)
+        } else {
+            format!("Frame {}", i)
+        });
+    }
+
+    let first = differing.first().cloned().unwrap_or(0).min(captions.len().saturating_sub(1));
+    write_requester.set_html_renderer(move |_, _, diffs| {
+        let mut html = String::from("<h3> Frames: Actual / Expected / Diff </h3><div>");
+        html.push_str(&format!(
+            r#"<input type="range" min="0" max="{}" value="{}" oninput="var f = this.parentNode.getElementsByTagName('figure'); for (var i = 0; i < f.length; i++) {{ f[i].style.display = i == this.value ? '' : 'none'; }}"/>"#,
+            captions.len().saturating_sub(1),
+            first
+        ));
+        let frames = diffs.iter().filter(|d| {
+            d.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("frame-"))
+        });
+        for (i, (diff, caption)) in frames.zip(&captions).enumerate() {
+            html.push_str(&format!(
+                r#"<figure{}><img src="{}"/><figcaption>{}</figcaption></figure>"#,
+                if i == first { "" } else { r#" style="display: none""# },
+                diff.to_string_lossy(),
+                caption
+            ));
+        }
+        html.push_str("</div>");
+        html.push_str(&format!("<pre>{}</pre>", escape_html(&report)));
+        html
+    });
+
+    Ok(())
+}
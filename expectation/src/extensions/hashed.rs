@@ -0,0 +1,109 @@
+use super::super::provider::{Provider, WriteRequester};
+use super::super::*;
+use super::{add_extension, escape_html};
+
+use std::ffi::OsString;
+use std::io::{Read, Result as IoResult};
+use std::path::{Path, PathBuf};
+
+pub trait HashedExtension {
+    /// Returns a writer for an output that is too big or too sensitive to be
+    /// committed.  Only its SHA-256 digest and size are compared, stored in
+    /// `<filename>.sha256`; the output itself stays in `actual/` where it
+    /// can be inspected locally, and no diff can be shown for it.
+    fn hashed<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>;
+}
+
+impl HashedExtension for Provider {
+    fn hashed<S>(&self, filename: S) -> Writer
+    where
+        S: AsRef<Path>,
+    {
+        let filename = filename.as_ref();
+        self.register_uncompared(filename);
+        // The digest is stored next to the output, so the differ finds the
+        // output by its file name.
+        let data_name = filename.file_name().map(OsString::from).unwrap_or_default();
+        self.custom_test_parallel(
+            digest_path(filename),
+            |a, b| Ok(read_digest(a)? == read_digest(b)?),
+            move |a, b, c, d| digest_diff(a, b, c, d, &data_name),
+        ).digest_only(filename.to_owned())
+    }
+}
+
+/// The file that stores the digest of `filename`: its whole name with
+/// `.sha256` appended.
+fn digest_path(filename: &Path) -> PathBuf {
+    let mut path = filename.as_os_str().to_owned();
+    path.push(".sha256");
+    path.into()
+}
+
+/// The digest and size recorded in a `.sha256` file.
+#[derive(Debug, PartialEq)]
+struct Digest {
+    sha256: Option<String>,
+    size: Option<u64>,
+}
+
+fn read_digest<R: Read>(mut r: R) -> IoResult<Digest> {
+    let mut contents = String::new();
+    r.read_to_string(&mut contents)?;
+    let mut digest = Digest {
+        sha256: None,
+        size: None,
+    };
+    for line in contents.lines() {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some("sha256"), Some(hash)) => digest.sha256 = Some(hash.to_lowercase()),
+            (Some("size"), Some(size)) => digest.size = size.parse().ok(),
+            _ => {}
+        }
+    }
+    Ok(digest)
+}
+
+fn describe(digest: &Digest) -> String {
+    format!(
+        "sha256 {}, size {}",
+        digest.sha256.as_deref().unwrap_or("unknown"),
+        digest
+            .size
+            .map(|s| format!("{} bytes", s))
+            .unwrap_or_else(|| "unknown".into())
+    )
+}
+
+fn digest_diff<R1: Read, R2: Read>(
+    r1: R1,
+    r2: R2,
+    path: &Path,
+    write_requester: &mut WriteRequester,
+    data_name: &OsString,
+) -> IoResult<()> {
+    let (actual, expected) = (read_digest(r1)?, read_digest(r2)?);
+    let data = write_requester.actual_path().with_file_name(data_name);
+    let report = format!(
+        "only the digest of this output is stored, so no diff is possible\n\
+         expected: {}\n\
+         actual:   {}\n\
+         the actual output is kept at {}\n",
+        describe(&expected),
+        describe(&actual),
+        data.display()
+    );
+
+    write_requester.request(add_extension(path, ".diff"), |w| {
+        w.write_all(report.as_bytes())
+    })?;
+
+    write_requester.set_html_renderer(move |_, _, _| {
+        format!("<pre>{}</pre>", escape_html(&report))
+    });
+
+    Ok(())
+}
@@ -1,95 +1,502 @@
 use super::super::provider::{Provider, WriteRequester};
 use super::super::*;
-use expectation_shared::filesystem::ReadSeek;
+use super::{add_extension, escape_html, streams_equal};
+use expectation_shared::filesystem::{FileSystem, ReadSeek};
 
-use std::io::{BufReader, Result as IoResult};
-use std::path::Path;
+use std::io::{BufReader, Error as IoError, ErrorKind, Result as IoResult, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
 use image::*;
 
+pub(crate) type Rgba16Image = ImageBuffer<Rgba<u16>, Vec<u16>>;
+
+/// Decides which pixel differences are tolerated when comparing images.
+/// The default requires every pixel to match exactly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageOptions {
+    pub metric: ImageMetric,
+    /// The number of pixels that may differ.
+    pub max_differing_pixels: Option<u64>,
+    /// The percentage (0.0 - 100.0) of pixels that may differ.
+    pub max_differing_percent: Option<f64>,
+    /// Parts of the image that are left out of the comparison.
+    pub masks: Vec<Mask>,
+}
+
+/// A part of an image that is excluded from comparison.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mask {
+    /// A rectangle of pixels.
+    Rect {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// The non-black pixels of a mask image stored next to the expected file,
+    /// named after it with `.mask.png` appended (`frame.png.mask.png`).
+    Sidecar,
+}
+
+/// How two images are compared.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageMetric {
+    /// A pixel differs when any of its channels differ by more than
+    /// `tolerance`.
+    Channel { tolerance: u8 },
+    /// A pixel differs when its perceptual YIQ color delta (0.0 - 1.0)
+    /// exceeds `threshold`.
+    Yiq { threshold: f64 },
+    /// The images match when their structural similarity (SSIM) is at least
+    /// `min_score`.  Differing pixels are still counted with an exact
+    /// comparison, but the limits on them are not used.
+    Ssim { min_score: f64 },
+}
+
+impl Default for ImageOptions {
+    fn default() -> ImageOptions {
+        ImageOptions {
+            metric: ImageMetric::Channel { tolerance: 0 },
+            max_differing_pixels: None,
+            max_differing_percent: None,
+            masks: vec![],
+        }
+    }
+}
+
 pub trait ImageDiffExtension {
-    fn png_writer<N>(&self, filename: N) -> Writer
+    fn png_writer_with<N>(&self, filename: N, options: ImageOptions) -> Writer
     where
         N: AsRef<Path>;
 
+    fn png_writer<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>,
+    {
+        self.png_writer_with(filename, ImageOptions::default())
+    }
+
     fn rgb_image<N>(&self, filename: N, image: RgbImage) -> IoResult<()>
     where
         N: AsRef<Path>,
     {
-        let mut w = self.png_writer(filename);
-        let dyn_image = DynamicImage::ImageRgb8(image);
-        dyn_image.write_to(&mut w, ImageOutputFormat::PNG).unwrap();
-        Ok(())
+        self.dynamic_image(filename, &DynamicImage::ImageRgb8(image))
     }
 
     fn rgba_image<N>(&self, filename: N, image: RgbaImage) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+    {
+        self.dynamic_image(filename, &DynamicImage::ImageRgba8(image))
+    }
+
+    /// Writes an image of any color type as a PNG.  BGR(A) images are stored
+    /// as RGB(A).
+    fn dynamic_image<N>(&self, filename: N, image: &DynamicImage) -> IoResult<()>
     where
         N: AsRef<Path>,
     {
         let mut w = self.png_writer(filename);
-        let dyn_image = DynamicImage::ImageRgba8(image);
-        dyn_image.write_to(&mut w, ImageOutputFormat::PNG).unwrap();
-        Ok(())
+        write_png(image, &mut w)
     }
 }
 
 impl ImageDiffExtension for Provider {
-    fn png_writer<S>(&self, filename: S) -> Writer
+    fn png_writer_with<S>(&self, filename: S, options: ImageOptions) -> Writer
     where
         S: AsRef<Path>,
     {
-        self.custom_test(
+        let eq_masks = MaskSource::new(self, filename.as_ref(), &options.masks);
+        let diff_masks = eq_masks.clone();
+        let diff_options = options.clone();
+        self.custom_test_parallel(
             filename,
-            |a, b| image_eq(a, b),
-            |a, b, c, d| image_diff(a, b, c, d),
+            move |a, b| image_eq(a, b, &options, &eq_masks),
+            move |a, b, c, d| image_diff(a, b, c, d, &diff_options, &diff_masks),
         )
     }
 }
 
-fn image_eq<R1: ReadSeek, R2: ReadSeek>(r1: R1, r2: R2) -> IoResult<bool> {
-    let mut r1 = BufReader::new(r1);
-    let mut r2 = BufReader::new(r2);
+/// Where the masks of an image expectation are loaded from.
+pub(crate) struct MaskSource {
+    masks: Vec<Mask>,
+    fs: Box<FileSystem>,
+    sidecar: PathBuf,
+}
 
-    let i1 = load(&mut r1, ImageFormat::PNG).unwrap();
-    let i2 = load(&mut r2, ImageFormat::PNG).unwrap();
+impl Clone for MaskSource {
+    fn clone(&self) -> MaskSource {
+        MaskSource {
+            masks: self.masks.clone(),
+            fs: self.fs.duplicate(),
+            sidecar: self.sidecar.clone(),
+        }
+    }
+}
 
-    match (i1, i2) {
-        (DynamicImage::ImageRgb8(i1), DynamicImage::ImageRgb8(i2)) => {
-            if i1.width() != i2.width() || i1.height() != i2.height() {
-                return Ok(false);
-            }
-            for x in 0..i1.width() {
-                for y in 0..i1.height() {
-                    if i1.get_pixel(x, y) != i2.get_pixel(x, y) {
-                        return Ok(false);
+impl MaskSource {
+    /// Registers the sidecar mask of `filename` with the provider if it is
+    /// used.
+    pub(crate) fn new(provider: &Provider, filename: &Path, masks: &[Mask]) -> MaskSource {
+        let sidecar = add_extension(filename, ".mask.png");
+        if masks.contains(&Mask::Sidecar) {
+            provider.register_sidecar(&sidecar);
+        }
+        MaskSource {
+            masks: masks.to_vec(),
+            fs: provider.expected_fs.duplicate(),
+            sidecar,
+        }
+    }
+
+    /// Builds an image of the given size whose non-zero pixels are ignored,
+    /// or `None` when there are no masks.
+    pub(crate) fn load(&self, width: u32, height: u32) -> IoResult<Option<GrayImage>> {
+        if self.masks.is_empty() {
+            return Ok(None);
+        }
+        let mut ignored = GrayImage::new(width, height);
+        for mask in &self.masks {
+            match *mask {
+                Mask::Rect {
+                    x,
+                    y,
+                    width: w,
+                    height: h,
+                } => {
+                    for py in y..y.saturating_add(h).min(height) {
+                        for px in x..x.saturating_add(w).min(width) {
+                            ignored.put_pixel(px, py, Luma([255]));
+                        }
+                    }
+                }
+                Mask::Sidecar => {
+                    let mut mask = None;
+                    self.fs.read(&self.sidecar, &mut |r| {
+                        mask = Some(decode(r)?.to_luma8());
+                        Ok(())
+                    })?;
+                    for (x, y, p) in mask.unwrap().enumerate_pixels() {
+                        if x < width && y < height && p[0] != 0 {
+                            ignored.put_pixel(x, y, Luma([255]));
+                        }
                     }
                 }
             }
         }
-        (DynamicImage::ImageRgba8(i1), DynamicImage::ImageRgba8(i2)) => {
-            if i1.width() != i2.width() || i1.height() != i2.height() {
-                return Ok(false);
+        Ok(Some(ignored))
+    }
+}
+
+pub(crate) fn is_ignored(ignored: Option<&GrayImage>, x: u32, y: u32) -> bool {
+    ignored.is_some_and(|m| x < m.width() && y < m.height() && m.get_pixel(x, y)[0] != 0)
+}
+
+/// The outcome of comparing two images of the same size.
+pub(crate) struct ImageComparison {
+    pub(crate) differing_pixels: u64,
+    pub(crate) total_pixels: u64,
+    ignored_pixels: u64,
+    ssim: Option<f64>,
+    pub(crate) passed: bool,
+}
+
+impl ImageComparison {
+    pub(crate) fn differing_percent(&self) -> f64 {
+        if self.total_pixels == 0 {
+            0.0
+        } else {
+            self.differing_pixels as f64 * 100.0 / self.total_pixels as f64
+        }
+    }
+
+    fn summary(&self, options: &ImageOptions) -> String {
+        let mut out = String::new();
+        match options.metric {
+            ImageMetric::Channel { tolerance } => {
+                out.push_str(&format!("metric: channel (tolerance: {})\n", tolerance))
             }
-            for x in 0..i1.width() {
-                for y in 0..i1.height() {
-                    if i1.get_pixel(x, y) != i2.get_pixel(x, y) {
-                        return Ok(false);
-                    }
+            ImageMetric::Yiq { threshold } => {
+                out.push_str(&format!("metric: yiq (threshold: {})\n", threshold))
+            }
+            ImageMetric::Ssim { min_score } => out.push_str(&format!(
+                "metric: ssim (score: {:.6}, minimum: {})\n",
+                self.ssim.unwrap_or(0.0),
+                min_score
+            )),
+        }
+        out.push_str(&format!(
+            "differing pixels: {} of {} ({:.4}%)\n",
+            self.differing_pixels,
+            self.total_pixels,
+            self.differing_percent()
+        ));
+        if !options.masks.is_empty() {
+            out.push_str(&format!("ignored pixels: {}\n", self.ignored_pixels));
+        }
+        if let Some(max) = options.max_differing_pixels {
+            out.push_str(&format!("allowed differing pixels: {}\n", max));
+        }
+        if let Some(max) = options.max_differing_percent {
+            out.push_str(&format!("allowed differing percent: {}%\n", max));
+        }
+        out.push_str(if self.passed { "result: pass\n" } else { "result: fail\n" });
+        out
+    }
+}
+
+/// Compares two images of the same size according to `options`.
+///
+/// Images are compared at 16 bits per channel so that no precision is lost
+/// for 16-bit inputs; tolerances are given in 8-bit units and scaled up.
+/// Pixels marked in `ignored` are left out entirely.
+pub(crate) fn compare_images(
+    i1: &Rgba16Image,
+    i2: &Rgba16Image,
+    options: &ImageOptions,
+    ignored: Option<&GrayImage>,
+) -> ImageComparison {
+    let mut differing_pixels = 0;
+    let mut ignored_pixels = 0;
+    for (x, y, p1) in i1.enumerate_pixels() {
+        if is_ignored(ignored, x, y) {
+            ignored_pixels += 1;
+        } else if pixel_differs(p1, i2.get_pixel(x, y), options.metric) {
+            differing_pixels += 1;
+        }
+    }
+
+    let mut comparison = ImageComparison {
+        differing_pixels,
+        total_pixels: u64::from(i1.width()) * u64::from(i1.height()) - ignored_pixels,
+        ignored_pixels,
+        ssim: None,
+        passed: false,
+    };
+
+    comparison.passed = match options.metric {
+        ImageMetric::Ssim { min_score } => {
+            // Masked pixels are copied over from the expected image so that
+            // they can't lower the score.
+            let score = match ignored {
+                Some(_) => {
+                    let unmasked = ImageBuffer::from_fn(i1.width(), i1.height(), |x, y| {
+                        if is_ignored(ignored, x, y) {
+                            *i2.get_pixel(x, y)
+                        } else {
+                            *i1.get_pixel(x, y)
+                        }
+                    });
+                    ssim(&unmasked, i2)
                 }
+                None => ssim(i1, i2),
+            };
+            comparison.ssim = Some(score);
+            score >= min_score
+        }
+        _ => {
+            let within_count = options
+                .max_differing_pixels
+                .map(|max| comparison.differing_pixels <= max);
+            let within_percent = options
+                .max_differing_percent
+                .map(|max| comparison.differing_percent() <= max);
+            match (within_count, within_percent) {
+                (None, None) => comparison.differing_pixels == 0,
+                (count, percent) => count.unwrap_or(true) && percent.unwrap_or(true),
             }
         }
-        (_, _) => return Ok(false),
+    };
+
+    comparison
+}
+
+/// Marks the pixels that differ according to `metric`, leaving out ignored
+/// pixels.  Only the area the two images have in common is compared.
+pub(crate) fn changed_pixels(
+    i1: &Rgba16Image,
+    i2: &Rgba16Image,
+    metric: ImageMetric,
+    ignored: Option<&GrayImage>,
+) -> GrayImage {
+    let (w, h) = (i1.width().min(i2.width()), i1.height().min(i2.height()));
+    ImageBuffer::from_fn(w, h, |x, y| {
+        let differs = !is_ignored(ignored, x, y)
+            && pixel_differs(i1.get_pixel(x, y), i2.get_pixel(x, y), metric);
+        Luma([differs as u8])
+    })
+}
+
+fn pixel_differs(p1: &Rgba<u16>, p2: &Rgba<u16>, metric: ImageMetric) -> bool {
+    match metric {
+        ImageMetric::Channel { tolerance } => (0..4)
+            .any(|c| (i32::from(p1[c]) - i32::from(p2[c])).abs() > i32::from(tolerance) * 257),
+        ImageMetric::Yiq { threshold } => yiq_delta(p1, p2) > threshold,
+        ImageMetric::Ssim { .. } => p1 != p2,
     }
+}
 
-    Ok(true)
+/// Blends a pixel onto a white background and converts it to YIQ.
+fn yiq(p: &Rgba<u16>) -> (f64, f64, f64) {
+    let a = f64::from(p[3]) / 65535.0;
+    let blend = |c: u16| 255.0 + (f64::from(c) / 257.0 - 255.0) * a;
+    let (r, g, b) = (blend(p[0]), blend(p[1]), blend(p[2]));
+    (
+        r * 0.298_895_31 + g * 0.586_622_47 + b * 0.114_482_23,
+        r * 0.595_977_99 - g * 0.274_176_10 - b * 0.321_801_89,
+        r * 0.211_470_17 - g * 0.522_617_11 + b * 0.311_146_94,
+    )
 }
 
-fn _add_extension(p: &Path, new_ext: &str) -> PathBuf {
-    let old_ext = match p.extension() {
-        Some(e) => e.to_string_lossy().into_owned(),
-        None => "".to_owned(),
+/// The perceptual color difference between two pixels, scaled to 0.0 - 1.0.
+fn yiq_delta(p1: &Rgba<u16>, p2: &Rgba<u16>) -> f64 {
+    let (y1, i1, q1) = yiq(p1);
+    let (y2, i2, q2) = yiq(p2);
+    let (dy, di, dq) = (y1 - y2, i1 - i2, q1 - q2);
+    (0.5053 * dy * dy + 0.299 * di * di + 0.1957 * dq * dq) / 35215.0
+}
+
+/// The mean structural similarity of the luma of two images, computed over
+/// 8x8 windows.
+fn ssim(i1: &Rgba16Image, i2: &Rgba16Image) -> f64 {
+    const WINDOW: u32 = 8;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let luma = |p: &Rgba<u16>| {
+        (0.299 * f64::from(p[0]) + 0.587 * f64::from(p[1]) + 0.114 * f64::from(p[2])) / 257.0
     };
-    p.with_extension(format!("{}{}", old_ext, new_ext))
+    let (w, h) = (i1.width(), i1.height());
+    if w == 0 || h == 0 {
+        return 1.0;
+    }
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    let mut y = 0;
+    while y < h {
+        let mut x = 0;
+        while x < w {
+            let (ww, wh) = (WINDOW.min(w - x), WINDOW.min(h - y));
+            let n = f64::from(ww * wh);
+            let (mut sum1, mut sum2, mut sum11, mut sum22, mut sum12) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for dy in 0..wh {
+                for dx in 0..ww {
+                    let l1 = luma(i1.get_pixel(x + dx, y + dy));
+                    let l2 = luma(i2.get_pixel(x + dx, y + dy));
+                    sum1 += l1;
+                    sum2 += l2;
+                    sum11 += l1 * l1;
+                    sum22 += l2 * l2;
+                    sum12 += l1 * l2;
+                }
+            }
+            let (m1, m2) = (sum1 / n, sum2 / n);
+            let var1 = sum11 / n - m1 * m1;
+            let var2 = sum22 / n - m2 * m2;
+            let cov = sum12 / n - m1 * m2;
+            total += ((2.0 * m1 * m2 + C1) * (2.0 * cov + C2))
+                / ((m1 * m1 + m2 * m2 + C1) * (var1 + var2 + C2));
+            windows += 1;
+            x += WINDOW;
+        }
+        y += WINDOW;
+    }
+    total / f64::from(windows)
+}
+
+fn image_error(e: ImageError) -> IoError {
+    match e {
+        ImageError::IoError(e) => e,
+        e => IoError::new(ErrorKind::InvalidData, e.to_string()),
+    }
+}
+
+/// Decodes an image in any of the formats supported by the `image` crate,
+/// guessing the format from the file contents.
+pub(crate) fn decode<R: ReadSeek>(r: R) -> IoResult<DynamicImage> {
+    io::Reader::new(BufReader::new(r))
+        .with_guessed_format()?
+        .decode()
+        .map_err(image_error)
+}
+
+/// Writes `image` as a PNG, converting color types that PNG can't store.
+pub(crate) fn write_png(image: &DynamicImage, mut w: &mut Write) -> IoResult<()> {
+    let converted;
+    let image = match image {
+        DynamicImage::ImageBgr8(_) => {
+            converted = DynamicImage::ImageRgb8(image.to_rgb8());
+            &converted
+        }
+        DynamicImage::ImageBgra8(_) => {
+            converted = DynamicImage::ImageRgba8(image.to_rgba8());
+            &converted
+        }
+        image => image,
+    };
+    image
+        .write_to(&mut w, ImageOutputFormat::Png)
+        .map_err(image_error)
+}
+
+/// Converts any image to 16 bits per channel RGBA.  8-bit channels are
+/// scaled by 257 so that 255 maps to 65535 regardless of color type.
+pub(crate) fn to_rgba16(image: &DynamicImage) -> Rgba16Image {
+    match image {
+        DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_)
+        | DynamicImage::ImageRgb16(_)
+        | DynamicImage::ImageRgba16(_) => image.to_rgba16(),
+        _ => {
+            let rgba = image.to_rgba8();
+            ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+                let p = rgba.get_pixel(x, y);
+                Rgba([
+                    u16::from(p[0]) * 257,
+                    u16::from(p[1]) * 257,
+                    u16::from(p[2]) * 257,
+                    u16::from(p[3]) * 257,
+                ])
+            })
+        }
+    }
+}
+
+fn has_alpha(image: &DynamicImage) -> bool {
+    matches!(
+        image,
+        DynamicImage::ImageLumaA8(_)
+            | DynamicImage::ImageRgba8(_)
+            | DynamicImage::ImageBgra8(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgba16(_)
+    )
+}
+
+fn image_eq<R1: ReadSeek, R2: ReadSeek>(
+    mut r1: R1,
+    mut r2: R2,
+    options: &ImageOptions,
+    masks: &MaskSource,
+) -> IoResult<bool> {
+    // Identical files are equal under any tolerance, and checking that
+    // doesn't need either image to be decoded.
+    if streams_equal(&mut r1, &mut r2)? {
+        return Ok(true);
+    }
+    r1.seek(SeekFrom::Start(0))?;
+    r2.seek(SeekFrom::Start(0))?;
+
+    let i1 = decode(r1)?;
+    let i2 = decode(r2)?;
+
+    if i1.width() != i2.width() || i1.height() != i2.height() {
+        return Ok(false);
+    }
+
+    let ignored = masks.load(i2.width(), i2.height())?;
+    Ok(compare_images(&to_rgba16(&i1), &to_rgba16(&i2), options, ignored.as_ref()).passed)
 }
 
 fn image_diff<R1: ReadSeek, R2: ReadSeek>(
@@ -97,124 +504,323 @@ fn image_diff<R1: ReadSeek, R2: ReadSeek>(
     r2: R2,
     path: &Path,
     write_requester: &mut WriteRequester,
+    options: &ImageOptions,
+    masks: &MaskSource,
 ) -> IoResult<()> {
-    use image::{ImageBuffer, Rgb};
-    let mut r1 = BufReader::new(r1);
-    let mut r2 = BufReader::new(r2);
-
-    let i1 = load(&mut r1, ImageFormat::PNG).unwrap();
-    let i2 = load(&mut r2, ImageFormat::PNG).unwrap();
-
-    write_requester.set_html_renderer(|_, _, diffs| {
-        let mut image_diff = diffs
-            .iter()
-            .filter(|p| p.to_string_lossy().ends_with(".png"));
-        let first = image_diff.next().unwrap();
-        return format!(
-            r#"
-        <h3> Actual / Expected / Diff </h3>
-        <img src="{}"/>
-        "#,
-            first.to_string_lossy()
-        );
-    });
+    image_diff_decoded(&decode(r1)?, &decode(r2)?, path, write_requester, options, masks)
+}
 
-    match (i1, i2) {
-        (DynamicImage::ImageRgb8(i1), DynamicImage::ImageRgb8(i2)) => {
-            let (w, h) = (i1.width().min(i2.width()), i1.height().min(i2.height()));
-            if i1.width() != i2.width() || i1.height() != i2.height() {
-                write_requester.request(path.join("img-size.txt"), |w| {
-                    writeln!(w, "image dimensions are different")?;
-                    writeln!(w, "actual:   width: {} height: {}", i1.width(), i1.height())?;
-                    writeln!(w, "expected: width: {} height: {}", i2.width(), i2.height())?;
-                    Ok(())
-                })?;
-            }
-            let mut color_buffer: ImageBuffer<Rgb<u8>, _> =
-                ImageBuffer::new(i1.width() + i2.width() + w, i1.height().max(i2.height()));
-
-            assert!(color_buffer.copy_from(&i1, 0, 0));
-            assert!(color_buffer.copy_from(&i2, i1.width(), 0));
-
-            for x in 0..w {
-                for y in 0..h {
-                    let p1 = i1.get_pixel(x, y);
-                    let p2 = i2.get_pixel(x, y);
-                    // TODO: is this the right direction?
-                    let pd = Rgb {
-                        data: [
-                            u8::wrapping_sub(p1[0], p2[0]),
-                            u8::wrapping_sub(p1[1], p2[1]),
-                            u8::wrapping_sub(p1[2], p2[2]),
-                        ],
-                    };
-                    color_buffer.put_pixel(i1.width() + i2.width() + x, y, pd);
+/// Writes the comparison, stats and visualizations of two decoded images.
+pub(crate) fn image_diff_decoded(
+    i1: &DynamicImage,
+    i2: &DynamicImage,
+    path: &Path,
+    write_requester: &mut WriteRequester,
+    options: &ImageOptions,
+    masks: &MaskSource,
+) -> IoResult<()> {
+    let ignored = masks.load(i2.width(), i2.height())?;
+    let ignored = ignored.as_ref();
+
+    if i1.color() != i2.color() {
+        write_requester.request(path.join("img-format.txt"), |w| {
+            writeln!(w, "image formats are different")?;
+            writeln!(w, "actual:   {:?}", i1.color())?;
+            writeln!(w, "expected: {:?}", i2.color())?;
+            Ok(())
+        })?;
+    }
+
+    if i1.width() != i2.width() || i1.height() != i2.height() {
+        write_requester.request(path.join("img-size.txt"), |w| {
+            writeln!(w, "image dimensions are different")?;
+            writeln!(w, "actual:   width: {} height: {}", i1.width(), i1.height())?;
+            writeln!(w, "expected: width: {} height: {}", i2.width(), i2.height())?;
+            Ok(())
+        })?;
+    }
+
+    let summary = if i1.width() == i2.width() && i1.height() == i2.height() {
+        let summary =
+            compare_images(&to_rgba16(i1), &to_rgba16(i2), options, ignored).summary(options);
+        write_requester.request(path.join("comparison.txt"), |w| write!(w, "{}", summary))?;
+        Some(summary)
+    } else {
+        None
+    };
+
+    let has_alpha = has_alpha(i1) || has_alpha(i2);
+    let changed = changed_pixels(&to_rgba16(i1), &to_rgba16(i2), options.metric, ignored);
+    let (i1, i2) = (i1.to_rgba8(), i2.to_rgba8());
+
+    let stats = DiffStats::new(&i1, &i2, &changed, ignored);
+    write_requester.request(path.join("stats.txt"), |w| write!(w, "{}", stats))?;
+
+    let stats_text = stats.to_string();
+    write_requester.set_html_renderer(move |_, _, diffs| {
+        let mut html = String::new();
+        for diff in diffs {
+            let caption = match diff.file_name().and_then(|n| n.to_str()) {
+                Some("color-diff.png") => "Actual / Expected / Diff (ignored pixels in blue)",
+                Some("transparency-diff.png") => {
+                    "Transparency: Actual / Expected / Diff (ignored pixels in blue)"
                 }
+                Some("heatmap.png") => "Heatmap (ignored pixels in gray)",
+                Some("overlay.png") => "Changed Regions",
+                _ => continue,
+            };
+            html.push_str(&format!(
+                r#"<h3> {} </h3><img src="{}"/>"#,
+                caption,
+                diff.to_string_lossy()
+            ));
+        }
+        html.push_str(&format!("<pre>{}</pre>", escape_html(&stats_text)));
+        if let Some(summary) = &summary {
+            html.push_str(&format!("<pre>{}</pre>", escape_html(summary)));
+        }
+        html
+    });
+
+    let color = DynamicImage::ImageRgb8(side_by_side(&i1, &i2, &changed, ignored, |p| {
+        Rgb([p[0], p[1], p[2]])
+    }));
+    write_requester.request(path.join("color-diff.png"), |w| write_png(&color, w))?;
+    if has_alpha {
+        let transparency = DynamicImage::ImageRgb8(side_by_side(&i1, &i2, &changed, ignored, |p| {
+            Rgb([p[3], p[3], p[3]])
+        }));
+        write_requester.request(path.join("transparency-diff.png"), |w| {
+            write_png(&transparency, w)
+        })?;
+    }
+
+    let heatmap = DynamicImage::ImageRgb8(heatmap(&i1, &i2, ignored));
+    write_requester.request(path.join("heatmap.png"), |w| write_png(&heatmap, w))?;
+
+    let overlay = DynamicImage::ImageRgb8(overlay(&i1, &stats.regions, ignored));
+    write_requester.request(path.join("overlay.png"), |w| write_png(&overlay, w))
+}
+
+/// A rectangle of changed pixels, with inclusive bounds.
+struct Region {
+    min_x: u32,
+    min_y: u32,
+    max_x: u32,
+    max_y: u32,
+    pixels: u64,
+}
+
+struct DiffStats {
+    changed_pixels: u64,
+    compared_pixels: u64,
+    ignored_pixels: u64,
+    max_delta: [u8; 4],
+    regions: Vec<Region>,
+}
+
+impl DiffStats {
+    fn new(
+        i1: &RgbaImage,
+        i2: &RgbaImage,
+        changed: &GrayImage,
+        ignored: Option<&GrayImage>,
+    ) -> DiffStats {
+        let mut max_delta = [0u8; 4];
+        let mut ignored_pixels = 0;
+        for (x, y, _) in changed.enumerate_pixels() {
+            if is_ignored(ignored, x, y) {
+                ignored_pixels += 1;
+                continue;
             }
-            let image = DynamicImage::ImageRgb8(color_buffer);
-            return write_requester.request(path.join("color-diff.png"), |mut w| {
-                image.write_to(&mut w, ImageOutputFormat::PNG).unwrap();
-                Ok(())
-            });
-        }
-        (DynamicImage::ImageRgba8(i1), DynamicImage::ImageRgba8(i2)) => {
-            let (w, h) = (i1.width().min(i2.width()), i1.height().min(i2.height()));
-            if i1.width() != i2.width() || i1.height() != i2.height() {
-                write_requester.request(path.join("img-size.txt"), |w| {
-                    writeln!(w, "image dimensions are different")?;
-                    writeln!(w, "actual:   width: {} height: {}", i1.width(), i1.height())?;
-                    writeln!(w, "expected: width: {} height: {}", i2.width(), i2.height())?;
-                    Ok(())
-                })?;
+            let (p1, p2) = (i1.get_pixel(x, y), i2.get_pixel(x, y));
+            for c in 0..4 {
+                max_delta[c] = max_delta[c].max(p1[c].abs_diff(p2[c]));
             }
-            let mut color_buffer: ImageBuffer<Rgb<u8>, _> =
-                ImageBuffer::new(w * 3, i1.height().max(i2.height()));
-            let mut transparency_buffer: ImageBuffer<Rgb<u8>, _> =
-                ImageBuffer::new(w * 3, i1.height().max(i2.height()));
-            for x in 0..w {
-                for y in 0..h {
-                    let p1 = i1.get_pixel(x, y);
-                    let p2 = i2.get_pixel(x, y);
-                    // TODO: is this the right direction?
-                    let pd = Rgb {
-                        data: [
-                            u8::wrapping_sub(p1[0], p2[0]),
-                            u8::wrapping_sub(p1[1], p2[1]),
-                            u8::wrapping_sub(p1[2], p2[2]),
-                        ],
-                    };
-                    color_buffer.put_pixel(x + w + w, y, pd);
-                    let pd = u8::wrapping_sub(p1[3], p2[3]);
-                    transparency_buffer.put_pixel(x, y, Rgb { data: [pd, pd, pd] });
+        }
+        DiffStats {
+            changed_pixels: changed.pixels().filter(|p| p[0] != 0).count() as u64,
+            compared_pixels: u64::from(changed.width()) * u64::from(changed.height())
+                - ignored_pixels,
+            ignored_pixels,
+            max_delta,
+            regions: changed_regions(changed),
+        }
+    }
+}
+
+impl ::std::fmt::Display for DiffStats {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let percent = if self.compared_pixels == 0 {
+            0.0
+        } else {
+            self.changed_pixels as f64 * 100.0 / self.compared_pixels as f64
+        };
+        writeln!(
+            f,
+            "changed pixels: {} of {} ({:.4}%)",
+            self.changed_pixels, self.compared_pixels, percent
+        )?;
+        if self.ignored_pixels != 0 {
+            writeln!(f, "ignored pixels: {}", self.ignored_pixels)?;
+        }
+        writeln!(
+            f,
+            "max delta: {} (red: {}, green: {}, blue: {}, alpha: {})",
+            self.max_delta.iter().max().unwrap(),
+            self.max_delta[0],
+            self.max_delta[1],
+            self.max_delta[2],
+            self.max_delta[3]
+        )?;
+        writeln!(f, "changed regions: {}", self.regions.len())?;
+        for r in &self.regions {
+            writeln!(
+                f,
+                "  x: {}..={} y: {}..={} ({} pixels)",
+                r.min_x, r.max_x, r.min_y, r.max_y, r.pixels
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Finds the bounding boxes of 8-connected groups of changed pixels.
+fn changed_regions(changed: &GrayImage) -> Vec<Region> {
+    let (w, h) = changed.dimensions();
+    let mut visited = vec![false; (w * h) as usize];
+    let mut regions = vec![];
+    for (x, y, p) in changed.enumerate_pixels() {
+        if p[0] == 0 || visited[(y * w + x) as usize] {
+            continue;
+        }
+        let mut region = Region {
+            min_x: x,
+            min_y: y,
+            max_x: x,
+            max_y: y,
+            pixels: 0,
+        };
+        let mut stack = vec![(x, y)];
+        visited[(y * w + x) as usize] = true;
+        while let Some((x, y)) = stack.pop() {
+            region.min_x = region.min_x.min(x);
+            region.min_y = region.min_y.min(y);
+            region.max_x = region.max_x.max(x);
+            region.max_y = region.max_y.max(y);
+            region.pixels += 1;
+            for ny in y.saturating_sub(1)..(y + 2).min(h) {
+                for nx in x.saturating_sub(1)..(x + 2).min(w) {
+                    let i = (ny * w + nx) as usize;
+                    if !visited[i] && changed.get_pixel(nx, ny)[0] != 0 {
+                        visited[i] = true;
+                        stack.push((nx, ny));
+                    }
                 }
             }
-            let image = DynamicImage::ImageRgb8(color_buffer);
-            write_requester.request(path.join("color-diff.png"), |mut w| {
-                image.write_to(&mut w, ImageOutputFormat::PNG).unwrap();
-                Ok(())
-            })?;
-            let image = DynamicImage::ImageRgb8(transparency_buffer);
-            return write_requester.request(path.join("transparency-diff.png"), |mut w| {
-                image.write_to(&mut w, ImageOutputFormat::PNG).unwrap();
-                Ok(())
-            });
-        }
-        (DynamicImage::ImageRgb8(_), DynamicImage::ImageRgba8(_)) => {
-            return write_requester.request(path.join("img-format.txt"), |w| {
-                writeln!(w, "image formats are different");
-                writeln!(w, "actual:   RGB8");
-                writeln!(w, "expected: RGBA8 (Alpha)");
-                Ok(())
-            });
-        }
-        (DynamicImage::ImageRgba8(_), DynamicImage::ImageRgb8(_)) => {
-            return write_requester.request(path.join("img-format.txt"), |w| {
-                writeln!(w, "image formats are different");
-                writeln!(w, "actual:   RGBA8 (Alpha)");
-                writeln!(w, "expected: RGB8");
-                Ok(())
-            });
-        }
-        (_, _) => panic!(),
+        }
+        regions.push(region);
+    }
+    regions
+}
+
+/// Lays out actual, expected and a diff panel next to each other.  The diff
+/// panel shows the expected image faded out with changed pixels in red and
+/// ignored pixels tinted blue.
+pub(crate) fn side_by_side<F>(
+    i1: &RgbaImage,
+    i2: &RgbaImage,
+    changed: &GrayImage,
+    ignored: Option<&GrayImage>,
+    channels: F,
+) -> RgbImage
+where
+    F: Fn(&Rgba<u8>) -> Rgb<u8>,
+{
+    let mut out: RgbImage = ImageBuffer::new(
+        i1.width() + i2.width() + changed.width(),
+        i1.height().max(i2.height()),
+    );
+    for (offset, image) in &[(0, i1), (i1.width(), i2)] {
+        for (x, y, p) in image.enumerate_pixels() {
+            out.put_pixel(offset + x, y, channels(p));
+        }
+    }
+    for (x, y, c) in changed.enumerate_pixels() {
+        let pixel = if c[0] != 0 {
+            Rgb([255, 0, 0])
+        } else {
+            let p = channels(i2.get_pixel(x, y));
+            let luma = (u32::from(p[0]) * 299 + u32::from(p[1]) * 587 + u32::from(p[2]) * 114) / 1000;
+            let faded = (255 - (255 - luma) / 4) as u8;
+            if is_ignored(ignored, x, y) {
+                tint_blue(Rgb([faded, faded, faded]))
+            } else {
+                Rgb([faded, faded, faded])
+            }
+        };
+        out.put_pixel(i1.width() + i2.width() + x, y, pixel);
+    }
+    out
+}
+
+/// Colors each pixel by the largest difference of its channels, from black
+/// (unchanged) through blue and red to yellow (maximum difference).  Ignored
+/// pixels are gray, whatever their difference.
+fn heatmap(i1: &RgbaImage, i2: &RgbaImage, ignored: Option<&GrayImage>) -> RgbImage {
+    let (w, h) = (i1.width().min(i2.width()), i1.height().min(i2.height()));
+    ImageBuffer::from_fn(w, h, |x, y| {
+        if is_ignored(ignored, x, y) {
+            return IGNORED_HEAT;
+        }
+        let (p1, p2) = (i1.get_pixel(x, y), i2.get_pixel(x, y));
+        let magnitude = (0..4).map(|c| p1[c].abs_diff(p2[c])).max().unwrap();
+        if magnitude == 0 {
+            return Rgb([0, 0, 0]);
+        }
+        let t = f64::from(magnitude) / 255.0;
+        if t < 0.5 {
+            let s = t / 0.5;
+            Rgb([(255.0 * s) as u8, 0, (128.0 * (1.0 - s)) as u8 + 64])
+        } else {
+            let s = (t - 0.5) / 0.5;
+            Rgb([255, (255.0 * s) as u8, 0])
+        }
+    })
+}
+
+/// The color of ignored pixels in the heatmap.
+const IGNORED_HEAT: Rgb<u8> = Rgb([128, 128, 128]);
+
+fn tint_blue(p: Rgb<u8>) -> Rgb<u8> {
+    Rgb([p[0] / 2, p[1] / 2, 128 + p[2] / 2])
+}
+
+/// Draws a box around each changed region on top of the actual image, with
+/// ignored pixels tinted blue.
+fn overlay(actual: &RgbaImage, regions: &[Region], ignored: Option<&GrayImage>) -> RgbImage {
+    let (w, h) = actual.dimensions();
+    let mut out: RgbImage = ImageBuffer::from_fn(w, h, |x, y| {
+        let p = actual.get_pixel(x, y);
+        let p = Rgb([p[0], p[1], p[2]]);
+        if is_ignored(ignored, x, y) {
+            tint_blue(p)
+        } else {
+            p
+        }
+    });
+    let red = Rgb([255, 0, 0]);
+    for r in regions {
+        let (x0, y0) = (r.min_x.saturating_sub(1), r.min_y.saturating_sub(1));
+        let (x1, y1) = ((r.max_x + 1).min(w - 1), (r.max_y + 1).min(h - 1));
+        for x in x0..=x1 {
+            out.put_pixel(x, y0, red);
+            out.put_pixel(x, y1, red);
+        }
+        for y in y0..=y1 {
+            out.put_pixel(x0, y, red);
+            out.put_pixel(x1, y, red);
+        }
     }
+    out
 }
@@ -1,19 +1,80 @@
+mod binary;
+pub use self::binary::*;
+
+mod hashed;
+pub use self::hashed::*;
+
 #[cfg(feature = "text")]
 mod text;
 #[cfg(feature = "text")]
 pub use self::text::*;
 
-#[cfg(feature = "text")]
+#[cfg(any(feature = "xml", feature = "serialize"))]
+mod changes;
+
+#[cfg(feature = "xml")]
+mod xml;
+#[cfg(feature = "xml")]
+pub use self::xml::*;
+
+#[cfg(feature = "xml")]
 mod svg;
-#[cfg(feature = "text")]
+#[cfg(feature = "xml")]
 pub use self::svg::*;
 
 #[cfg(feature = "image")]
 mod image;
 #[cfg(feature = "image")]
 pub use self::image::*;
+#[cfg(feature = "apng")]
+mod frames;
+#[cfg(feature = "apng")]
+pub use self::frames::*;
+
+#[cfg(feature = "serialize")]
+mod serialize;
+#[cfg(feature = "serialize")]
+pub use self::serialize::*;
+
+#[cfg(feature = "table")]
+mod table;
+#[cfg(feature = "table")]
+pub use self::table::*;
 
 pub(crate) fn escape_html(input: &str) -> String {
     use marksman_escape::Escape;
     String::from_utf8(Escape::new(input.bytes()).collect()).unwrap()
 }
+
+#[allow(dead_code)]
+pub(crate) fn add_extension(p: &::std::path::Path, new_ext: &str) -> ::std::path::PathBuf {
+    let old_ext = match p.extension() {
+        Some(e) => e.to_string_lossy().into_owned(),
+        None => "".to_owned(),
+    };
+    p.with_extension(format!("{}{}", old_ext, new_ext))
+}
+
+/// Compares two streams a buffer at a time, stopping at the first
+/// difference instead of reading both of them into memory.
+pub(crate) fn streams_equal<R1, R2>(mut r1: R1, mut r2: R2) -> ::std::io::Result<bool>
+where
+    R1: ::std::io::BufRead,
+    R2: ::std::io::BufRead,
+{
+    loop {
+        let n = {
+            let (b1, b2) = (r1.fill_buf()?, r2.fill_buf()?);
+            if b1.is_empty() || b2.is_empty() {
+                return Ok(b1.is_empty() && b2.is_empty());
+            }
+            let n = b1.len().min(b2.len());
+            if b1[..n] != b2[..n] {
+                return Ok(false);
+            }
+            n
+        };
+        r1.consume(n);
+        r2.consume(n);
+    }
+}
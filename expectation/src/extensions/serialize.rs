@@ -0,0 +1,285 @@
+use super::super::provider::{Provider, WriteRequester};
+use super::super::*;
+use super::add_extension;
+use super::changes::{changes_html, Change};
+
+use ron;
+use serde::Serialize;
+use serde_json::{self, Map, Value};
+use serde_yaml;
+use std::fmt::Display;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::path::Path;
+use toml;
+
+/// The on-disk format used by the `SerdeDiffExtension`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerdeFormat {
+    Json,
+    Ron,
+    Yaml,
+    Toml,
+}
+
+pub trait SerdeDiffExtension {
+    /// Returns a writer whose contents are parsed as `format` and compared
+    /// structurally against the expected file.
+    fn serde_writer<N>(&self, filename: N, format: SerdeFormat) -> Writer
+    where
+        N: AsRef<Path>;
+
+    fn serde<N, T>(&self, filename: N, format: SerdeFormat, object: &T) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        T: Serialize,
+    {
+        let text = format.serialize(&to_canonical_value(object)?)?;
+        let mut w = self.serde_writer(filename, format);
+        write!(w, "{}", text)
+    }
+
+    fn json<N, T>(&self, filename: N, object: &T) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        T: Serialize,
+    {
+        self.serde(filename, SerdeFormat::Json, object)
+    }
+
+    fn ron<N, T>(&self, filename: N, object: &T) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        T: Serialize,
+    {
+        self.serde(filename, SerdeFormat::Ron, object)
+    }
+
+    fn yaml<N, T>(&self, filename: N, object: &T) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        T: Serialize,
+    {
+        self.serde(filename, SerdeFormat::Yaml, object)
+    }
+
+    fn toml<N, T>(&self, filename: N, object: &T) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        T: Serialize,
+    {
+        self.serde(filename, SerdeFormat::Toml, object)
+    }
+}
+
+impl SerdeDiffExtension for Provider {
+    fn serde_writer<S>(&self, filename: S, format: SerdeFormat) -> Writer
+    where
+        S: AsRef<Path>,
+    {
+        let redactions = self.redactions();
+        let diff_redactions = redactions.clone();
+        self.custom_test_parallel(
+            filename,
+            move |a, b| serde_eq(a, b, format),
+            move |a, b, c, d| serde_diff(a, b, c, d, format, &diff_redactions),
+        ).with_filter(move |bytes| redact(bytes, format, &redactions))
+    }
+}
+
+// `IoError::other` would need Rust 1.74.
+#[allow(clippy::io_other_error)]
+fn other_error<E: Display>(e: E) -> IoError {
+    IoError::new(ErrorKind::Other, e.to_string())
+}
+
+impl SerdeFormat {
+    fn serialize(self, value: &Value) -> IoResult<String> {
+        let mut out = match self {
+            SerdeFormat::Json => serde_json::to_string_pretty(value).map_err(other_error)?,
+            SerdeFormat::Ron => {
+                ron::ser::to_string_pretty(value, Default::default()).map_err(other_error)?
+            }
+            SerdeFormat::Yaml => serde_yaml::to_string(value).map_err(other_error)?,
+            SerdeFormat::Toml => toml::to_string_pretty(value).map_err(other_error)?,
+        };
+        if !out.ends_with('\n') {
+            out.push('\n');
+        }
+        Ok(out)
+    }
+
+    fn deserialize(self, text: &str) -> IoResult<Value> {
+        let value = match self {
+            SerdeFormat::Json => serde_json::from_str(text).map_err(other_error)?,
+            SerdeFormat::Ron => ron::de::from_str(text).map_err(other_error)?,
+            SerdeFormat::Yaml => serde_yaml::from_str(text).map_err(other_error)?,
+            SerdeFormat::Toml => toml::from_str(text).map_err(other_error)?,
+        };
+        Ok(canonicalize(value))
+    }
+
+    fn read<R: Read>(self, mut r: R) -> IoResult<Value> {
+        let mut s = String::new();
+        r.read_to_string(&mut s)?;
+        self.deserialize(&s)
+    }
+}
+
+/// Applies the path rules of `redactions` to the parsed value and then the
+/// regex rules to the re-serialized text.  Text that can't be parsed only has
+/// the regex rules applied.
+fn redact(bytes: Vec<u8>, format: SerdeFormat, redactions: &Redactions) -> Vec<u8> {
+    if redactions.is_empty() {
+        return bytes;
+    }
+    let redacted = ::std::str::from_utf8(&bytes)
+        .ok()
+        .and_then(|text| format.deserialize(text).ok())
+        .and_then(|mut value| {
+            redactions.redact_value(&mut value);
+            format.serialize(&value).ok()
+        });
+    match redacted {
+        Some(text) => redactions.redact_bytes(text.into_bytes()),
+        None => redactions.redact_bytes(bytes),
+    }
+}
+
+/// Converts `object` into a `Value` whose maps are sorted by key, so that the
+/// serialized output does not depend on hash-map iteration order.
+fn to_canonical_value<T: Serialize>(object: &T) -> IoResult<Value> {
+    serde_json::to_value(object)
+        .map(canonicalize)
+        .map_err(other_error)
+}
+
+fn canonicalize(value: Value) -> Value {
+    match value {
+        Value::Array(values) => Value::Array(values.into_iter().map(canonicalize).collect()),
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.into_iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            let mut out = Map::new();
+            for (k, v) in entries {
+                out.insert(k, canonicalize(v));
+            }
+            Value::Object(out)
+        }
+        other => other,
+    }
+}
+
+fn is_identifier(key: &str) -> bool {
+    !key.is_empty() && key
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+fn key_path(parent: &str, key: &str) -> String {
+    if is_identifier(key) {
+        if parent.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}.{}", parent, key)
+        }
+    } else {
+        format!("{}[{}]", parent, Value::String(key.to_owned()))
+    }
+}
+
+fn numbers_equal(a: &serde_json::Number, b: &serde_json::Number) -> bool {
+    if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        return a == b;
+    }
+    if let (Some(a), Some(b)) = (a.as_u64(), b.as_u64()) {
+        return a == b;
+    }
+    a.as_f64() == b.as_f64()
+}
+
+pub(crate) fn diff_values(path: &str, expected: &Value, actual: &Value, out: &mut Vec<Change<Value>>) {
+    match (expected, actual) {
+        (Value::Object(e), Value::Object(a)) => {
+            for (k, ev) in e {
+                let child = key_path(path, k);
+                match a.get(k) {
+                    Some(av) => diff_values(&child, ev, av, out),
+                    None => out.push(Change::Removed {
+                        path: child,
+                        expected: ev.clone(),
+                    }),
+                }
+            }
+            for (k, av) in a {
+                if !e.contains_key(k) {
+                    out.push(Change::Added {
+                        path: key_path(path, k),
+                        actual: av.clone(),
+                    });
+                }
+            }
+        }
+        (Value::Array(e), Value::Array(a)) => {
+            for i in 0..e.len().max(a.len()) {
+                let child = format!("{}[{}]", path, i);
+                match (e.get(i), a.get(i)) {
+                    (Some(ev), Some(av)) => diff_values(&child, ev, av, out),
+                    (Some(ev), None) => out.push(Change::Removed {
+                        path: child,
+                        expected: ev.clone(),
+                    }),
+                    (None, Some(av)) => out.push(Change::Added {
+                        path: child,
+                        actual: av.clone(),
+                    }),
+                    (None, None) => unreachable!(),
+                }
+            }
+        }
+        (Value::Number(e), Value::Number(a)) if numbers_equal(e, a) => {}
+        (e, a) if e == a => {}
+        (e, a) => out.push(Change::Changed {
+            path: path.to_owned(),
+            expected: e.clone(),
+            actual: a.clone(),
+        }),
+    }
+}
+
+pub(crate) fn serde_eq<R1: Read, R2: Read>(r1: R1, r2: R2, format: SerdeFormat) -> IoResult<bool> {
+    let actual = format.read(r1)?;
+    let expected = format.read(r2)?;
+    let mut changes = vec![];
+    diff_values("", &expected, &actual, &mut changes);
+    Ok(changes.is_empty())
+}
+
+pub(crate) fn serde_diff<R1: Read, R2: Read>(
+    r1: R1,
+    r2: R2,
+    path: &Path,
+    write_requester: &mut WriteRequester,
+    format: SerdeFormat,
+    redactions: &Redactions,
+) -> IoResult<()> {
+    let actual = format.read(r1)?;
+    let expected = format.read(r2)?;
+    let mut changes = vec![];
+    diff_values("", &expected, &actual, &mut changes);
+
+    write_requester.request(add_extension(path, ".diff"), |w| {
+        for change in &changes {
+            writeln!(w, "{}", change)?;
+        }
+        Ok(())
+    })?;
+
+    let redactions_html = redactions.to_html();
+    write_requester.set_html_renderer(move |_, _, _| {
+        let mut html = changes_html(&changes);
+        html.push_str(&redactions_html);
+        html
+    });
+
+    Ok(())
+}
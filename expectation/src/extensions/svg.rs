@@ -1,18 +1,33 @@
 use super::super::provider::{Provider, WriteRequester};
 use super::super::*;
-use super::escape_html;
 
-use super::text::{text_diff, text_eq};
-use diff;
-use std::fmt::Debug;
+use super::changes::changes_html;
+use super::xml::{xml_diff, xml_eq, Syntax, XmlOptions};
+#[cfg(feature = "svg-raster")]
+use super::image::{compare_images, image_diff_decoded, to_rgba16, ImageOptions, MaskSource};
+#[cfg(feature = "svg-raster")]
+use image::{DynamicImage, GenericImageView, RgbaImage};
+#[cfg(feature = "svg-raster")]
+use resvg::{tiny_skia, usvg};
+#[cfg(feature = "svg-raster")]
+use std::io::{Cursor, Error as IoError, ErrorKind};
 use std::io::{Read, Result as IoResult, Write};
 use std::path::Path;
 
 pub trait SvgDiffExtension {
-    fn svg_writer<N>(&self, filename: N) -> Writer
+    /// Returns a writer whose contents are compared as XML, so attribute
+    /// order, whitespace and number formatting don't matter.
+    fn svg_writer_with<N>(&self, filename: N, options: XmlOptions) -> Writer
     where
         N: AsRef<Path>;
 
+    fn svg_writer<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>,
+    {
+        self.svg_writer_with(filename, XmlOptions::default())
+    }
+
     fn svg<N, S>(&self, filename: N, text: S) -> IoResult<()>
     where
         N: AsRef<Path>,
@@ -24,15 +39,52 @@ pub trait SvgDiffExtension {
 }
 
 impl SvgDiffExtension for Provider {
-    fn svg_writer<S>(&self, filename: S) -> Writer
+    fn svg_writer_with<S>(&self, filename: S, options: XmlOptions) -> Writer
     where
         S: AsRef<Path>,
     {
-        self.custom_test(
+        let redactions = self.redactions();
+        let diff_redactions = redactions.clone();
+        let diff_options = options.clone();
+        self.custom_test_parallel(
             filename,
-            |a, b| text_eq(a, b),
-            |a, b, c, d| svg_diff(a, b, c, d),
-        )
+            move |a, b| xml_eq(a, b, Syntax::Xml, &options),
+            move |a, b, c, d| svg_diff(a, b, c, d, &diff_redactions, &diff_options),
+        ).with_redactions(redactions)
+    }
+}
+
+/// SVGs compared by the picture they draw.  These need the `svg-raster`
+/// feature, which renders them with `resvg`.
+#[cfg(feature = "svg-raster")]
+pub trait RasterizedSvgDiffExtension {
+    /// Returns a writer whose contents are rendered to pixels and compared
+    /// like a PNG, so two SVGs that draw the same picture are equal even when
+    /// their markup differs.  Text is not drawn, as it would depend on the
+    /// fonts installed on each machine.
+    fn svg_writer_rasterized<N>(&self, filename: N, options: ImageOptions) -> Writer
+    where
+        N: AsRef<Path>;
+}
+
+#[cfg(feature = "svg-raster")]
+impl RasterizedSvgDiffExtension for Provider {
+    fn svg_writer_rasterized<S>(&self, filename: S, options: ImageOptions) -> Writer
+    where
+        S: AsRef<Path>,
+    {
+        let redactions = self.redactions();
+        let diff_redactions = redactions.clone();
+        let eq_masks = MaskSource::new(self, filename.as_ref(), &options.masks);
+        let diff_masks = eq_masks.clone();
+        let diff_options = options.clone();
+        self.custom_test_parallel(
+            filename,
+            move |a, b| raster_eq(a, b, &options, &eq_masks),
+            move |a, b, c, d| {
+                raster_diff(a, b, c, d, &diff_redactions, &diff_options, &diff_masks)
+            },
+        ).with_redactions(redactions)
     }
 }
 
@@ -41,20 +93,119 @@ pub(crate) fn svg_diff<R1: Read, R2: Read>(
     r2: R2,
     path: &Path,
     write_requester: &mut WriteRequester,
+    redactions: &Redactions,
+    options: &XmlOptions,
 ) -> IoResult<()> {
-    text_diff(r1, r2, path, write_requester)?;
-    write_requester.set_html_renderer(|actual, expected, _| {
-        return format!(
+    let changes = xml_diff(r1, r2, path, write_requester, Syntax::Xml, options)?;
+    let redactions_html = redactions.to_html();
+    write_requester.set_html_renderer(move |actual, expected, _| {
+        format!(
             r#"
         <h3> Actual </h3>
         <img src="{}"/>
         <h3> Expected </h3>
         <img src="{}"/>
-
+        {}
+        {}
         "#,
             actual.to_string_lossy(),
             expected.to_string_lossy(),
+            changes_html(&changes),
+            redactions_html,
+        )
+    });
+    Ok(())
+}
+
+/// Renders an SVG at its own size.
+#[cfg(feature = "svg-raster")]
+fn rasterize(data: &[u8]) -> IoResult<DynamicImage> {
+    let invalid = |e: String| IoError::new(ErrorKind::InvalidData, e);
+    let tree =
+        usvg::Tree::from_data(data, &usvg::Options::default()).map_err(|e| invalid(e.to_string()))?;
+    let size = tree.size().to_int_size();
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or_else(|| invalid(format!("can't render an SVG of size {:?}", size)))?;
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            vec![c.red(), c.green(), c.blue(), c.alpha()]
+        }).collect();
+    let image = RgbaImage::from_raw(size.width(), size.height(), pixels).unwrap();
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+#[cfg(feature = "svg-raster")]
+fn read_all<R: Read>(mut r: R) -> IoResult<Vec<u8>> {
+    let mut buf = vec![];
+    r.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(feature = "svg-raster")]
+fn raster_eq<R1: Read, R2: Read>(
+    r1: R1,
+    r2: R2,
+    options: &ImageOptions,
+    masks: &MaskSource,
+) -> IoResult<bool> {
+    let i1 = rasterize(&read_all(r1)?)?;
+    let i2 = rasterize(&read_all(r2)?)?;
+    if i1.width() != i2.width() || i1.height() != i2.height() {
+        return Ok(false);
+    }
+    let ignored = masks.load(i2.width(), i2.height())?;
+    Ok(compare_images(&to_rgba16(&i1), &to_rgba16(&i2), options, ignored.as_ref()).passed)
+}
+
+/// Writes the element-level changes next to the pixel diffs of both
+/// renderings.
+#[cfg(feature = "svg-raster")]
+fn raster_diff<R1: Read, R2: Read>(
+    r1: R1,
+    r2: R2,
+    path: &Path,
+    write_requester: &mut WriteRequester,
+    redactions: &Redactions,
+    options: &ImageOptions,
+    masks: &MaskSource,
+) -> IoResult<()> {
+    let (a, e) = (read_all(r1)?, read_all(r2)?);
+    let changes = xml_diff(
+        Cursor::new(&a),
+        Cursor::new(&e),
+        path,
+        write_requester,
+        Syntax::Xml,
+        &XmlOptions::default(),
+    )?;
+    image_diff_decoded(
+        &rasterize(&a)?,
+        &rasterize(&e)?,
+        path,
+        write_requester,
+        options,
+        masks,
+    )?;
+
+    let image_html = write_requester.html_renderer.take();
+    let redactions_html = redactions.to_html();
+    write_requester.set_html_renderer(move |actual, expected, diffs| {
+        let mut html = format!(
+            r#"<h3> Actual </h3><img src="{}"/><h3> Expected </h3><img src="{}"/>"#,
+            actual.to_string_lossy(),
+            expected.to_string_lossy(),
         );
+        if let Some(image_html) = &image_html {
+            html.push_str(&image_html(actual, expected, diffs));
+        }
+        html.push_str(&changes_html(&changes));
+        html.push_str(&redactions_html);
+        html
     });
     Ok(())
 }
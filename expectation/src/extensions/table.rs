@@ -0,0 +1,432 @@
+use super::super::provider::{Provider, WriteRequester};
+use super::super::*;
+use super::{add_extension, escape_html};
+
+use csv;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult};
+use std::path::Path;
+
+/// The on-disk format used by the `TableDiffExtension`.  The first record
+/// of a table is its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableFormat {
+    Csv,
+    Tsv,
+}
+
+impl TableFormat {
+    fn delimiter(self) -> u8 {
+        match self {
+            TableFormat::Csv => b',',
+            TableFormat::Tsv => b'\t',
+        }
+    }
+}
+
+/// Decides how two tables are compared.  The default compares every cell
+/// exactly, with rows matched by position and columns matched by name.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableOptions {
+    /// Matches rows regardless of their order.
+    pub ignore_row_order: bool,
+    /// Columns whose values identify a row.  When set, rows are matched by
+    /// key instead of by position, and are named by their key in the diff.
+    pub key_columns: Vec<String>,
+    /// Cells that both parse as numbers are equal when they differ by at most
+    /// this much.
+    pub numeric_tolerance: Option<f64>,
+}
+
+impl TableOptions {
+    fn descriptions(&self) -> Vec<String> {
+        let mut out = vec![];
+        if self.ignore_row_order {
+            out.push("ignore row order".to_owned());
+        }
+        if !self.key_columns.is_empty() {
+            out.push(format!("key columns: {}", self.key_columns.join(", ")));
+        }
+        if let Some(tolerance) = self.numeric_tolerance {
+            out.push(format!("numeric tolerance: {}", tolerance));
+        }
+        out
+    }
+}
+
+pub trait TableDiffExtension {
+    /// Returns a writer whose contents are parsed as `format` and compared
+    /// cell by cell.
+    fn table_writer_with<N>(&self, filename: N, format: TableFormat, options: TableOptions) -> Writer
+    where
+        N: AsRef<Path>;
+
+    fn csv_writer<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>,
+    {
+        self.table_writer_with(filename, TableFormat::Csv, TableOptions::default())
+    }
+
+    fn tsv_writer<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>,
+    {
+        self.table_writer_with(filename, TableFormat::Tsv, TableOptions::default())
+    }
+
+    /// Writes `rows`, starting with the header, as a table.
+    fn table<N, R, C>(&self, filename: N, format: TableFormat, rows: R) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        R: IntoIterator<Item = C>,
+        C: IntoIterator,
+        C::Item: AsRef<[u8]>,
+    {
+        self.table_with(filename, format, TableOptions::default(), rows)
+    }
+
+    fn table_with<N, R, C>(
+        &self,
+        filename: N,
+        format: TableFormat,
+        options: TableOptions,
+        rows: R,
+    ) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        R: IntoIterator<Item = C>,
+        C: IntoIterator,
+        C::Item: AsRef<[u8]>,
+    {
+        let w = self.table_writer_with(filename, format, options);
+        let mut w = csv::WriterBuilder::new()
+            .delimiter(format.delimiter())
+            .flexible(true)
+            .from_writer(w);
+        for row in rows {
+            w.write_record(row).map_err(csv_error)?;
+        }
+        w.flush()
+    }
+}
+
+impl TableDiffExtension for Provider {
+    fn table_writer_with<S>(&self, filename: S, format: TableFormat, options: TableOptions) -> Writer
+    where
+        S: AsRef<Path>,
+    {
+        let redactions = self.redactions();
+        let diff_redactions = redactions.clone();
+        let diff_options = options.clone();
+        self.custom_test_parallel(
+            filename,
+            move |a, b| table_eq(a, b, format, &options),
+            move |a, b, c, d| table_diff(a, b, c, d, format, &diff_options, &diff_redactions),
+        ).with_redactions(redactions)
+    }
+}
+
+fn csv_error(e: csv::Error) -> IoError {
+    match e.into_kind() {
+        csv::ErrorKind::Io(e) => e,
+        kind => IoError::new(ErrorKind::InvalidData, format!("{:?}", kind)),
+    }
+}
+
+struct Table {
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    fn read<R: Read>(r: R, format: TableFormat) -> IoResult<Table> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(format.delimiter())
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(r);
+        let mut records = vec![];
+        for record in reader.records() {
+            let record = record.map_err(csv_error)?;
+            records.push(record.iter().map(|c| c.to_owned()).collect::<Vec<_>>());
+        }
+        let mut records = records.into_iter();
+        Ok(Table {
+            header: records.next().unwrap_or_default(),
+            rows: records.collect(),
+        })
+    }
+
+    fn column(&self, name: &str) -> Option<usize> {
+        self.header.iter().position(|h| h == name)
+    }
+
+    fn cell(&self, row: usize, column: usize) -> &str {
+        self.rows[row].get(column).map_or("", |c| c.as_str())
+    }
+
+    fn key(&self, row: usize, key_columns: &[String]) -> Vec<String> {
+        key_columns
+            .iter()
+            .map(|k| self.column(k).map_or("", |c| self.cell(row, c)).to_owned())
+            .collect()
+    }
+}
+
+/// A single difference between the expected and actual tables.
+#[derive(Debug, Clone, PartialEq)]
+enum TableChange {
+    ColumnAdded(String),
+    ColumnRemoved(String),
+    RowAdded { row: String, actual: Vec<String> },
+    RowRemoved { row: String, expected: Vec<String> },
+    CellChanged {
+        row: String,
+        column: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl Display for TableChange {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            TableChange::ColumnAdded(name) => write!(f, "column {:?}: added", name),
+            TableChange::ColumnRemoved(name) => write!(f, "column {:?}: removed", name),
+            TableChange::RowAdded { row, actual } => write!(f, "[{}]: + {:?}", row, actual),
+            TableChange::RowRemoved { row, expected } => write!(f, "[{}]: - {:?}", row, expected),
+            TableChange::CellChanged {
+                row,
+                column,
+                expected,
+                actual,
+            } => write!(f, "[{}] {}: {:?} → {:?}", row, column, expected, actual),
+        }
+    }
+}
+
+/// The outcome of matching the rows of two tables.
+struct Comparison {
+    changes: Vec<TableChange>,
+    /// The expected value of every changed cell, by `(actual row, column)`.
+    changed_cells: HashMap<(usize, usize), String>,
+    /// The actual rows that have no expected counterpart.
+    added_rows: HashSet<usize>,
+}
+
+fn cells_equal(expected: &str, actual: &str, options: &TableOptions) -> bool {
+    if expected == actual {
+        return true;
+    }
+    match (
+        options.numeric_tolerance,
+        expected.trim().parse::<f64>(),
+        actual.trim().parse::<f64>(),
+    ) {
+        (Some(tolerance), Ok(e), Ok(a)) => (e - a).abs() <= tolerance,
+        _ => false,
+    }
+}
+
+fn compare_tables(expected: &Table, actual: &Table, options: &TableOptions) -> Comparison {
+    let mut changes = vec![];
+    for name in &actual.header {
+        if expected.column(name).is_none() {
+            changes.push(TableChange::ColumnAdded(name.clone()));
+        }
+    }
+    for name in &expected.header {
+        if actual.column(name).is_none() {
+            changes.push(TableChange::ColumnRemoved(name.clone()));
+        }
+    }
+    // (actual column, expected column) for every column both tables have.
+    let common: Vec<(usize, usize)> = actual
+        .header
+        .iter()
+        .enumerate()
+        .filter_map(|(a, name)| expected.column(name).map(|e| (a, e)))
+        .collect();
+    let rows_equal = |a: usize, e: usize| {
+        common
+            .iter()
+            .all(|&(ac, ec)| cells_equal(expected.cell(e, ec), actual.cell(a, ac), options))
+    };
+
+    // The expected row matched to each actual row.
+    let mut matches: Vec<Option<usize>> = vec![None; actual.rows.len()];
+    if !options.key_columns.is_empty() {
+        let mut by_key: HashMap<Vec<String>, VecDeque<usize>> = HashMap::new();
+        for e in 0..expected.rows.len() {
+            by_key
+                .entry(expected.key(e, &options.key_columns))
+                .or_default()
+                .push_back(e);
+        }
+        for (a, m) in matches.iter_mut().enumerate() {
+            *m = by_key
+                .get_mut(&actual.key(a, &options.key_columns))
+                .and_then(|rows| rows.pop_front());
+        }
+    } else if options.ignore_row_order {
+        let mut used = vec![false; expected.rows.len()];
+        for (a, m) in matches.iter_mut().enumerate() {
+            *m = (0..expected.rows.len()).find(|&e| !used[e] && rows_equal(a, e));
+            if let Some(e) = *m {
+                used[e] = true;
+            }
+        }
+    } else {
+        for (a, m) in matches.iter_mut().enumerate() {
+            if a < expected.rows.len() {
+                *m = Some(a);
+            }
+        }
+    }
+
+    let label = |table: &Table, row: usize| {
+        if options.key_columns.is_empty() {
+            format!("row {}", row + 1)
+        } else {
+            options
+                .key_columns
+                .iter()
+                .zip(table.key(row, &options.key_columns))
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    };
+
+    let mut changed_cells = HashMap::new();
+    let mut added_rows = HashSet::new();
+    for (a, m) in matches.iter().enumerate() {
+        match *m {
+            Some(e) => {
+                for &(ac, ec) in &common {
+                    let (ev, av) = (expected.cell(e, ec), actual.cell(a, ac));
+                    if !cells_equal(ev, av, options) {
+                        changed_cells.insert((a, ac), ev.to_owned());
+                        changes.push(TableChange::CellChanged {
+                            row: label(actual, a),
+                            column: actual.header[ac].clone(),
+                            expected: ev.to_owned(),
+                            actual: av.to_owned(),
+                        });
+                    }
+                }
+            }
+            None => {
+                added_rows.insert(a);
+                changes.push(TableChange::RowAdded {
+                    row: label(actual, a),
+                    actual: actual.rows[a].clone(),
+                });
+            }
+        }
+    }
+    let matched: HashSet<usize> = matches.iter().filter_map(|m| *m).collect();
+    for e in 0..expected.rows.len() {
+        if !matched.contains(&e) {
+            changes.push(TableChange::RowRemoved {
+                row: label(expected, e),
+                expected: expected.rows[e].clone(),
+            });
+        }
+    }
+
+    Comparison {
+        changes,
+        changed_cells,
+        added_rows,
+    }
+}
+
+fn table_eq<R1: Read, R2: Read>(
+    r1: R1,
+    r2: R2,
+    format: TableFormat,
+    options: &TableOptions,
+) -> IoResult<bool> {
+    let actual = Table::read(r1, format)?;
+    let expected = Table::read(r2, format)?;
+    Ok(compare_tables(&expected, &actual, options).changes.is_empty())
+}
+
+/// Renders the actual table with changed cells and added rows highlighted.
+/// The expected value of a changed cell is shown when hovering over it.
+fn table_html(actual: &Table, comparison: &Comparison) -> String {
+    let mut html = String::from("<table><tr>");
+    for name in &actual.header {
+        html.push_str(&format!("<th>{}</th>", escape_html(name)));
+    }
+    html.push_str("</tr>");
+    for (a, row) in actual.rows.iter().enumerate() {
+        if comparison.added_rows.contains(&a) {
+            html.push_str(r#"<tr style="background: #cfc">"#);
+        } else {
+            html.push_str("<tr>");
+        }
+        for (c, cell) in row.iter().enumerate() {
+            if let Some(expected) = comparison.changed_cells.get(&(a, c)) {
+                html.push_str(&format!(
+                    r#"<td style="background: #fcc" title="{}">{}</td>"#,
+                    escape_html(expected),
+                    escape_html(cell)
+                ));
+            } else {
+                html.push_str(&format!("<td>{}</td>", escape_html(cell)));
+            }
+        }
+        html.push_str("</tr>");
+    }
+    html.push_str("</table>");
+    html
+}
+
+fn table_diff<R1: Read, R2: Read>(
+    r1: R1,
+    r2: R2,
+    path: &Path,
+    write_requester: &mut WriteRequester,
+    format: TableFormat,
+    options: &TableOptions,
+    redactions: &Redactions,
+) -> IoResult<()> {
+    let actual = Table::read(r1, format)?;
+    let expected = Table::read(r2, format)?;
+    let comparison = compare_tables(&expected, &actual, options);
+    let descriptions = options.descriptions();
+
+    write_requester.request(add_extension(path, ".diff"), |w| {
+        if !descriptions.is_empty() {
+            writeln!(w, "# table options: {}", descriptions.join("; "))?;
+        }
+        for change in &comparison.changes {
+            writeln!(w, "{}", change)?;
+        }
+        Ok(())
+    })?;
+
+    let mut html = String::new();
+    if !descriptions.is_empty() {
+        html.push_str(&format!(
+            "<p> Table options: {} </p>",
+            escape_html(&descriptions.join("; "))
+        ));
+    }
+    html.push_str("<h3> Actual </h3>");
+    html.push_str(&table_html(&actual, &comparison));
+    html.push_str("<h3> Changes </h3><code><pre>");
+    for change in &comparison.changes {
+        html.push_str(&escape_html(&change.to_string()));
+        html.push('\n');
+    }
+    html.push_str("</pre></code>");
+    html.push_str(&redactions.to_html());
+    write_requester.set_html_renderer(move |_, _, _| html.clone());
+
+    Ok(())
+}
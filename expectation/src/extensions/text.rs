@@ -1,17 +1,157 @@
 use super::super::provider::{Provider, WriteRequester};
 use super::super::*;
-use super::escape_html;
+use super::{add_extension, escape_html, streams_equal};
+use expectation_shared::filesystem::ReadSeek;
 
 use diff;
+use imara_diff;
+use similar;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
-use std::io::{Read, Result as IoResult, Write};
+use std::io::{BufRead, Error as IoError, ErrorKind, Read, Result as IoResult, SeekFrom, Write};
+use std::ops::Range;
 use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+
+/// Controls which differences between two text files are ignored when they
+/// are compared.  The default compares the files byte-for-byte.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TextOptions {
+    /// Treats `\r\n` and `\r` line endings as `\n`.
+    pub normalize_line_endings: bool,
+    /// Ignores whitespace at the end of each line, including a missing or
+    /// extra newline at the end of the file.
+    pub trim_trailing_whitespace: bool,
+    /// Ignores lines that are empty or only contain whitespace.
+    pub ignore_blank_lines: bool,
+    /// Applies Unicode NFC normalization before comparing.
+    pub unicode_nfc: bool,
+    /// Compares the text case-insensitively.
+    pub ignore_case: bool,
+    /// The number of unchanged lines shown around each change in the diff,
+    /// 3 when not set.  This doesn't affect the comparison.
+    pub context_lines: Option<usize>,
+    /// How the lines of the two files are lined up in the diff.
+    pub algorithm: DiffAlgorithm,
+    /// Reports blocks of lines that were moved unchanged as moves instead of
+    /// as a removal and an addition.  The diff marks the lines of a move with
+    /// `<` where they were and `>` where they went instead of `-` and `+`,
+    /// so it can no longer be applied with `patch`.
+    pub detect_moves: bool,
+}
+
+/// The algorithm used to line up the lines of two text files in a diff.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiffAlgorithm {
+    /// The longest common subsequence of lines.  Slow on large files.
+    #[default]
+    Lcs,
+    /// Myers' algorithm, which quickly finds a minimal diff.
+    Myers,
+    /// Anchors the diff on lines that are unique in both files, which keeps
+    /// reordered blocks readable.
+    Patience,
+    /// A faster variant of patience diffing, as in `git diff --histogram`.
+    Histogram,
+}
+
+/// The number of unchanged lines shown around each change by default.
+const DEFAULT_CONTEXT_LINES: usize = 3;
+
+impl TextOptions {
+    /// Normalizes line endings, trailing whitespace, blank lines and Unicode
+    /// composition, but stays case-sensitive.
+    pub fn lenient() -> TextOptions {
+        TextOptions {
+            normalize_line_endings: true,
+            trim_trailing_whitespace: true,
+            ignore_blank_lines: true,
+            unicode_nfc: true,
+            ignore_case: false,
+            context_lines: None,
+            algorithm: DiffAlgorithm::Lcs,
+            detect_moves: false,
+        }
+    }
+
+    fn is_exact(&self) -> bool {
+        TextOptions {
+            context_lines: None,
+            algorithm: DiffAlgorithm::Lcs,
+            detect_moves: false,
+            ..self.clone()
+        } == TextOptions::default()
+    }
+
+    fn descriptions(&self) -> Vec<&'static str> {
+        let mut out = vec![];
+        if self.normalize_line_endings {
+            out.push("normalize line endings");
+        }
+        if self.trim_trailing_whitespace {
+            out.push("trim trailing whitespace");
+        }
+        if self.ignore_blank_lines {
+            out.push("ignore blank lines");
+        }
+        if self.unicode_nfc {
+            out.push("unicode nfc");
+        }
+        if self.ignore_case {
+            out.push("ignore case");
+        }
+        out
+    }
+
+    pub(crate) fn normalize<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.is_exact() {
+            return Cow::Borrowed(text);
+        }
+
+        let mut text: String = if self.unicode_nfc {
+            text.nfc().collect()
+        } else {
+            text.to_owned()
+        };
+        if self.ignore_case {
+            text = text.to_lowercase();
+        }
+        if self.normalize_line_endings {
+            text = text.replace("\r\n", "\n").replace('\r', "\n");
+        }
+        if self.trim_trailing_whitespace || self.ignore_blank_lines {
+            let mut out = String::with_capacity(text.len());
+            for line in text.lines() {
+                let line = if self.trim_trailing_whitespace {
+                    line.trim_end()
+                } else {
+                    line
+                };
+                if self.ignore_blank_lines && line.trim().is_empty() {
+                    continue;
+                }
+                out.push_str(line);
+                out.push('\n');
+            }
+            text = out;
+        }
+        Cow::Owned(text)
+    }
+}
 
 pub trait TextDiffExtension {
-    fn text_writer<N>(&self, filename: N) -> Writer
+    fn text_writer_with<N>(&self, filename: N, options: TextOptions) -> Writer
     where
         N: AsRef<Path>;
 
+    fn text_writer<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>,
+    {
+        self.text_writer_with(filename, TextOptions::default())
+    }
+
     fn text<N, S>(&self, filename: N, text: S) -> IoResult<()>
     where
         N: AsRef<Path>,
@@ -32,73 +172,695 @@ pub trait TextDiffExtension {
 }
 
 impl TextDiffExtension for Provider {
-    fn text_writer<S>(&self, filename: S) -> Writer
+    fn text_writer_with<S>(&self, filename: S, options: TextOptions) -> Writer
     where
         S: AsRef<Path>,
     {
-        self.custom_test(
+        let redactions = self.redactions();
+        let diff_redactions = redactions.clone();
+        let diff_options = options.clone();
+        self.custom_test_parallel(
             filename,
-            |a, b| text_eq(a, b),
-            |a, b, c, d| text_diff(a, b, c, d),
-        )
+            move |a, b| text_eq(a, b, &options),
+            move |a, b, c, d| text_diff(a, b, c, d, &diff_redactions, &diff_options),
+        ).with_redactions(redactions)
     }
 }
 
-pub(crate) fn text_eq<R1: Read, R2: Read>(mut r1: R1, mut r2: R2) -> IoResult<bool> {
+pub(crate) fn text_eq<R1: BufRead, R2: BufRead>(
+    mut r1: R1,
+    mut r2: R2,
+    options: &TextOptions,
+) -> IoResult<bool> {
+    if options.is_exact() {
+        return streams_equal(r1, r2);
+    }
+
     let mut v1 = Vec::new();
     let mut v2 = Vec::new();
     r1.read_to_end(&mut v1)?;
     r2.read_to_end(&mut v2)?;
 
-    Ok(v1 == v2)
+    let s1 = String::from_utf8_lossy(&v1);
+    let s2 = String::from_utf8_lossy(&v2);
+    Ok(options.normalize(&s1) == options.normalize(&s2))
 }
 
-fn add_extension(p: &Path, new_ext: &str) -> PathBuf {
-    let old_ext = match p.extension() {
-        Some(e) => e.to_string_lossy().into_owned(),
-        None => "".to_owned(),
-    };
-    p.with_extension(format!("{}{}", old_ext, new_ext))
+/// The part of two files that a diff has to look at: everything but their
+/// common leading and trailing lines, keeping enough of those lines for the
+/// context of the hunks.
+struct Window {
+    /// Where the window starts in both files.
+    start: u64,
+    /// The number of lines before `start`.
+    lines_before: usize,
+    actual_end: u64,
+    expected_end: u64,
+}
+
+/// The number of bytes compared at a time when looking for the common
+/// suffix of two files.
+const CHUNK: u64 = 64 * 1024;
+
+/// Finds the window of two files that differ without reading more than the
+/// differing region and its context into memory.
+fn diff_window<R1: ReadSeek, R2: ReadSeek>(
+    r1: &mut R1,
+    r2: &mut R2,
+    context: usize,
+) -> IoResult<Window> {
+    // The last `context + 1` line starts of the common prefix, with the
+    // number of lines before each of them.
+    let mut starts = VecDeque::new();
+    starts.push_back((0, 0));
+    let (mut prefix, mut lines) = (0, 0);
+    loop {
+        let (n, done) = {
+            let (b1, b2) = (r1.fill_buf()?, r2.fill_buf()?);
+            let len = b1.len().min(b2.len());
+            let n = (0..len).find(|&i| b1[i] != b2[i]).unwrap_or(len);
+            for (i, _) in b1[..n].iter().enumerate().filter(|&(_, &b)| b == b'\n') {
+                lines += 1;
+                starts.push_back((prefix + i as u64 + 1, lines));
+                if starts.len() > context + 1 {
+                    starts.pop_front();
+                }
+            }
+            (n, n < len || len == 0)
+        };
+        r1.consume(n);
+        r2.consume(n);
+        prefix += n as u64;
+        if done {
+            break;
+        }
+    }
+
+    let actual_len = r1.seek(SeekFrom::End(0))?;
+    let expected_len = r2.seek(SeekFrom::End(0))?;
+    let longest_suffix = actual_len.min(expected_len) - prefix;
+    let mut suffix = 0;
+    let (mut b1, mut b2) = (vec![], vec![]);
+    while suffix < longest_suffix {
+        let len = CHUNK.min(longest_suffix - suffix);
+        read_before(r1, actual_len - suffix, len, &mut b1)?;
+        read_before(r2, expected_len - suffix, len, &mut b2)?;
+        let same = b1.iter().rev().zip(b2.iter().rev()).take_while(|(a, e)| a == e).count();
+        suffix += same as u64;
+        if same < b1.len() {
+            break;
+        }
+    }
+
+    // The common suffix is the same in both files, so extending the window
+    // into it by whole lines moves both ends by the same amount.
+    r1.seek(SeekFrom::Start(actual_len - suffix))?;
+    let mut extra = 0;
+    let mut line = vec![];
+    for _ in 0..context + 1 {
+        line.clear();
+        match r1.read_until(b'\n', &mut line)? {
+            0 => break,
+            n => extra += n as u64,
+        }
+    }
+
+    let &(start, lines_before) = starts.front().unwrap();
+    Ok(Window {
+        start,
+        lines_before,
+        actual_end: actual_len - suffix + extra,
+        expected_end: expected_len - suffix + extra,
+    })
+}
+
+/// Reads the `len` bytes before `end` into `buf`.
+fn read_before<R: ReadSeek>(r: &mut R, end: u64, len: u64, buf: &mut Vec<u8>) -> IoResult<()> {
+    buf.resize(len as usize, 0);
+    r.seek(SeekFrom::Start(end - len))?;
+    r.read_exact(buf)
+}
+
+fn read_range<R: ReadSeek>(r: &mut R, start: u64, end: u64) -> IoResult<String> {
+    let mut bytes = vec![];
+    r.seek(SeekFrom::Start(start))?;
+    (&mut *r).take(end - start).read_to_end(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| IoError::new(ErrorKind::InvalidData, e))
 }
 
-pub(crate) fn text_diff<R1: Read, R2: Read>(
+/// Writes the diff of two text files.  When they are compared exactly, only
+/// the lines between their first and last difference (plus context) are read.
+pub(crate) fn text_diff<R1: ReadSeek, R2: ReadSeek>(
     mut r1: R1,
     mut r2: R2,
     path: &Path,
     write_requester: &mut WriteRequester,
+    redactions: &Redactions,
+    options: &TextOptions,
 ) -> IoResult<()> {
-    let mut s1 = String::new();
-    let mut s2 = String::new();
+    let context = options.context_lines.unwrap_or(DEFAULT_CONTEXT_LINES);
+    let (s1, s2, skip) = if options.is_exact() {
+        let window = diff_window(&mut r1, &mut r2, context)?;
+        (
+            read_range(&mut r1, window.start, window.actual_end)?,
+            read_range(&mut r2, window.start, window.expected_end)?,
+            window.lines_before,
+        )
+    } else {
+        let mut s1 = String::new();
+        let mut s2 = String::new();
+        r1.read_to_string(&mut s1)?;
+        r2.read_to_string(&mut s2)?;
+        (s1, s2, 0)
+    };
     let mut diff = Vec::new();
-    r1.read_to_string(&mut s1)?;
-    r2.read_to_string(&mut s2)?;
 
-    for d in diff::lines(&s1, &s2) {
-        match d {
-            diff::Result::Left(l) => writeln!(diff, "+{}", l)?,
-            diff::Result::Both(l, _) => writeln!(diff, " {}", l)?,
-            diff::Result::Right(r) => writeln!(diff, "-{}", r)?,
-        }
+    let descriptions = options.descriptions();
+    if !descriptions.is_empty() {
+        writeln!(diff, "# text options: {}", descriptions.join(", "))?;
     }
+    writeln!(diff, "# actual: {}", write_requester.actual_path().display())?;
 
-    let diff = String::from_utf8(diff).unwrap();
+    let (lines, moves) = diff_lines(&s1, &s2, options, skip);
+    for m in &moves {
+        writeln!(diff, "# moved: {}", m)?;
+    }
+    let hunks = hunks(&lines, context);
+    let mut intraline_diff = diff.clone();
+    writeln!(diff, "--- a/{}", write_requester.expected_path().display())?;
+    writeln!(diff, "+++ b/{}", write_requester.expected_path().display())?;
+    write_unified(&mut diff, &lines, &hunks, skip, false)?;
+    write_unified(&mut intraline_diff, &lines, &hunks, skip, true)?;
 
-    write_requester.request(add_extension(path, ".diff"), |w| write!(w, "{}", diff))?;
+    write_requester.request(add_extension(path, ".diff"), |w| w.write_all(&diff))?;
+    if lines.iter().any(|l| l.changed.is_some()) {
+        write_requester.request(add_extension(path, ".intraline.diff"), |w| {
+            w.write_all(&intraline_diff)
+        })?;
+    }
 
+    let redactions_html = redactions.to_html();
+    let options_html = if descriptions.is_empty() {
+        String::new()
+    } else {
+        format!("<p> Text options: {} </p>", descriptions.join(", "))
+    };
+    let diff_html = lines_html(&lines, &hunks, skip);
     write_requester.set_html_renderer(move |_, _, _| {
-        let mut html = Vec::new();
+        let mut html = String::new();
 
-        write!(html, "<h3> Actual </h3>");
-        write!(html, "<code><pre>{}</pre></code>", escape_html(&s1));
+        html.push_str(&options_html);
 
-        write!(html, "<h3> Expected </h3>");
-        write!(html, "<code><pre>{}</pre></code>", escape_html(&s2));
+        html.push_str("<h3> Diff </h3>");
+        html.push_str(&format!("<code><pre>{}</pre></code>", diff_html));
 
-        write!(html, "<h3> Diff </h3>");
-        write!(html, "<code><pre>{}</pre></code>", escape_html(&diff));
+        html.push_str(&redactions_html);
 
-        String::from_utf8(html).unwrap()
+        html
     });
 
     Ok(())
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineKind {
+    Actual,
+    Expected,
+    Both,
+}
+
+/// A line of a text diff, without its line ending.  Changed lines that were
+/// paired with a similar line on the other side know which of their
+/// characters changed.
+struct DiffLine<'a> {
+    kind: LineKind,
+    text: &'a str,
+    newline: bool,
+    /// The 1-based line number in the expected file, or in the actual file
+    /// for added lines.
+    number: usize,
+    changed: Option<Vec<bool>>,
+    /// For lines that were moved, the number of the line on the other side.
+    moved: Option<usize>,
+}
+
+impl<'a> DiffLine<'a> {
+    fn prefix(&self) -> &'static str {
+        match (self.kind, self.moved) {
+            (LineKind::Actual, None) => "+",
+            (LineKind::Actual, Some(_)) => ">",
+            (LineKind::Expected, None) => "-",
+            (LineKind::Expected, Some(_)) => "<",
+            (LineKind::Both, _) => " ",
+        }
+    }
+}
+
+/// A block of lines that was moved without changes.
+struct Move {
+    expected: Range<usize>,
+    actual: Range<usize>,
+}
+
+impl ::std::fmt::Display for Move {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let lines = |r: &Range<usize>| {
+            if r.len() == 1 {
+                format!("line {}", r.start)
+            } else {
+                format!("lines {}-{}", r.start, r.end - 1)
+            }
+        };
+        write!(
+            f,
+            "expected {} → actual {}",
+            lines(&self.expected),
+            lines(&self.actual)
+        )
+    }
+}
+
+/// Lines up `expected` and `actual` with `algorithm`, returning the ranges
+/// of expected lines that were replaced by ranges of actual lines.
+fn changed_ranges(
+    actual: &[&str],
+    expected: &[&str],
+    algorithm: DiffAlgorithm,
+) -> Vec<(Range<usize>, Range<usize>)> {
+    let mut changes: Vec<(Range<usize>, Range<usize>)> = vec![];
+    match algorithm {
+        DiffAlgorithm::Lcs => {
+            let (mut e, mut a) = (0, 0);
+            for d in diff::slice(actual, expected) {
+                let (e_len, a_len) = match d {
+                    diff::Result::Left(_) => (0, 1),
+                    diff::Result::Right(_) => (1, 0),
+                    diff::Result::Both(..) => (1, 1),
+                };
+                if e_len != a_len {
+                    match changes.last_mut() {
+                        Some((er, ar)) if er.end == e && ar.end == a => {
+                            er.end += e_len;
+                            ar.end += a_len;
+                        }
+                        _ => changes.push((e..e + e_len, a..a + a_len)),
+                    }
+                }
+                e += e_len;
+                a += a_len;
+            }
+        }
+        DiffAlgorithm::Patience => {
+            for op in similar::capture_diff_slices(similar::Algorithm::Patience, expected, actual) {
+                let (tag, e, a) = op.as_tag_tuple();
+                if tag != similar::DiffTag::Equal {
+                    changes.push((e, a));
+                }
+            }
+        }
+        DiffAlgorithm::Myers | DiffAlgorithm::Histogram => {
+            let mut input = imara_diff::intern::InternedInput::default();
+            input.update_before(expected.iter().cloned());
+            input.update_after(actual.iter().cloned());
+            let algorithm = match algorithm {
+                DiffAlgorithm::Myers => imara_diff::Algorithm::Myers,
+                _ => imara_diff::Algorithm::Histogram,
+            };
+            imara_diff::diff(algorithm, &input, |e: Range<u32>, a: Range<u32>| {
+                changes.push((
+                    e.start as usize..e.end as usize,
+                    a.start as usize..a.end as usize,
+                ))
+            });
+        }
+    }
+    changes
+}
+
+/// Diffs the lines of two texts that both start after the first `skip`
+/// lines of their files.  The lines are lined up by their normalized text,
+/// but the diff shows them as they are in the files so that it applies to
+/// the expected file.  Differences that `options` ignore are left as they
+/// are in the expected file.
+fn diff_lines<'a>(
+    actual: &'a str,
+    expected: &'a str,
+    options: &TextOptions,
+    skip: usize,
+) -> (Vec<DiffLine<'a>>, Vec<Move>) {
+    let actual: Vec<&str> = actual.split_inclusive('\n').collect();
+    let expected: Vec<&str> = expected.split_inclusive('\n').collect();
+    // A line normalizes to an empty string only when it's an ignored blank
+    // line.
+    let actual_keys: Vec<Cow<str>> = actual.iter().map(|l| options.normalize(l)).collect();
+    let expected_keys: Vec<Cow<str>> = expected.iter().map(|l| options.normalize(l)).collect();
+    let ignored =
+        |keys: &[Cow<str>], range: &Range<usize>| keys[range.clone()].iter().all(|k| k.is_empty());
+    let line = |kind, text: &'a str, index: usize| DiffLine {
+        kind,
+        text: text.strip_suffix('\n').unwrap_or(text),
+        newline: text.ends_with('\n'),
+        number: skip + index + 1,
+        changed: None,
+        moved: None,
+    };
+
+    // Removed lines come before added lines within each change.
+    let mut lines = vec![];
+    let mut e = 0;
+    let changes = changed_ranges(
+        &actual_keys.iter().map(|k| &**k).collect::<Vec<_>>(),
+        &expected_keys.iter().map(|k| &**k).collect::<Vec<_>>(),
+        options.algorithm,
+    );
+    for (er, ar) in changes {
+        for (i, text) in expected.iter().enumerate().take(er.start).skip(e) {
+            lines.push(line(LineKind::Both, text, i));
+        }
+        if ignored(&expected_keys, &er) && ignored(&actual_keys, &ar) {
+            for i in er.clone() {
+                lines.push(line(LineKind::Both, expected[i], i));
+            }
+        } else {
+            for i in er.clone() {
+                lines.push(line(LineKind::Expected, expected[i], i));
+            }
+            for i in ar {
+                lines.push(line(LineKind::Actual, actual[i], i));
+            }
+        }
+        e = er.end;
+    }
+    for (i, text) in expected.iter().enumerate().skip(e) {
+        lines.push(line(LineKind::Both, text, i));
+    }
+
+    let moves = if options.detect_moves {
+        detect_moves(&mut lines)
+    } else {
+        vec![]
+    };
+
+    // Within each run of changed lines, the n-th removed and added lines that
+    // weren't moved are paired.
+    let mut start = 0;
+    while start < lines.len() {
+        let end = (start..lines.len())
+            .find(|&i| lines[i].kind == LineKind::Both)
+            .unwrap_or(lines.len());
+        let unmoved = |kind| {
+            (start..end)
+                .filter(|&i| lines[i].kind == kind && lines[i].moved.is_none())
+                .collect::<Vec<_>>()
+        };
+        let (expected, actual) = (unmoved(LineKind::Expected), unmoved(LineKind::Actual));
+        for (&e, &a) in expected.iter().zip(&actual) {
+            if let Some((ca, ce)) = intraline(lines[a].text, lines[e].text) {
+                lines[a].changed = Some(ca);
+                lines[e].changed = Some(ce);
+            }
+        }
+        start = end + 1;
+    }
+    (lines, moves)
+}
+
+/// Finds blocks of removed lines that were added unchanged in another run
+/// of changes, and marks their lines as moved.  The longest blocks are
+/// matched first, and a block can be part of a larger removal or addition.
+/// Blocks of blank lines are never moves.
+fn detect_moves(lines: &mut [DiffLine]) -> Vec<Move> {
+    // The run of changes that each line belongs to.
+    let mut runs = Vec::with_capacity(lines.len());
+    let mut run = 0;
+    for line in lines.iter() {
+        if line.kind == LineKind::Both {
+            run += 1;
+        }
+        runs.push(run);
+    }
+    let mut added: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, line) in lines.iter().enumerate() {
+        if line.kind == LineKind::Actual {
+            added.entry(line.text).or_default().push(i);
+        }
+    }
+
+    let mut moves = vec![];
+    loop {
+        let free = |i: usize, kind| {
+            matches!(lines.get(i), Some(l) if l.kind == kind && l.moved.is_none())
+        };
+        let same = |e: usize, a: usize| {
+            free(e, LineKind::Expected) && free(a, LineKind::Actual) && lines[e].text == lines[a].text
+        };
+        // The longest block of removed lines that was added elsewhere, as
+        // its first removed and added lines and its length.
+        let mut longest: Option<(usize, usize, usize)> = None;
+        for e in (0..lines.len()).filter(|&e| free(e, LineKind::Expected)) {
+            for &a in added.get(lines[e].text).into_iter().flatten() {
+                if runs[a] == runs[e] || !free(a, LineKind::Actual) {
+                    continue;
+                }
+                let len = (0..).take_while(|&k| same(e + k, a + k)).count();
+                let blank = lines[e..e + len].iter().all(|l| l.text.trim().is_empty());
+                if !blank && longest.map(|(_, _, l)| len > l).unwrap_or(true) {
+                    longest = Some((e, a, len));
+                }
+            }
+        }
+        let (e, a, len) = match longest {
+            Some(block) => block,
+            None => break,
+        };
+        for k in 0..len {
+            lines[e + k].moved = Some(lines[a + k].number);
+            lines[a + k].moved = Some(lines[e + k].number);
+        }
+        moves.push(Move {
+            expected: lines[e].number..lines[e + len - 1].number + 1,
+            actual: lines[a].number..lines[a + len - 1].number + 1,
+        });
+    }
+    moves.sort_by_key(|m| m.expected.start);
+    moves
+}
+
+/// Groups the changed lines into hunks of `[start, end)` line indices, each
+/// with up to `context` unchanged lines around it.  Hunks whose context
+/// would overlap are merged.
+fn hunks(lines: &[DiffLine], context: usize) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        if line.kind == LineKind::Both {
+            continue;
+        }
+        let start = i.saturating_sub(context);
+        let end = (i + context + 1).min(lines.len());
+        match hunks.last_mut() {
+            Some(h) if start <= h.1 => h.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    hunks
+}
+
+/// The `@@ -1,3 +1,4 @@` header of the hunk covering `lines[start..end]`,
+/// where `lines` starts after the first `skip` lines of both files.
+fn hunk_header(lines: &[DiffLine], start: usize, end: usize, skip: usize) -> String {
+    let count = |range: &[DiffLine], skip: LineKind| range.iter().filter(|l| l.kind != skip).count();
+    let range = |before: usize, len: usize| match len {
+        0 => format!("{},0", before),
+        1 => format!("{}", before + 1),
+        _ => format!("{},{}", before + 1, len),
+    };
+    format!(
+        "@@ -{} +{} @@",
+        range(
+            skip + count(&lines[..start], LineKind::Actual),
+            count(&lines[start..end], LineKind::Actual)
+        ),
+        range(
+            skip + count(&lines[..start], LineKind::Expected),
+            count(&lines[start..end], LineKind::Expected)
+        ),
+    )
+}
+
+/// Writes the hunks as a unified diff, with moved lines marked by `<` and
+/// `>`.  With `markers`, every paired line is followed by a `?` line marking
+/// its changed characters, which makes the output easier to read but no
+/// longer applicable with `patch`.
+fn write_unified(
+    out: &mut Vec<u8>,
+    lines: &[DiffLine],
+    hunks: &[(usize, usize)],
+    skip: usize,
+    markers: bool,
+) -> IoResult<()> {
+    for &(start, end) in hunks {
+        writeln!(out, "{}", hunk_header(lines, start, end, skip))?;
+        for line in &lines[start..end] {
+            writeln!(out, "{}{}", line.prefix(), line.text)?;
+            if !line.newline {
+                writeln!(out, "\\ No newline at end of file")?;
+            }
+            if let (true, Some(changed)) = (markers, &line.changed) {
+                writeln!(out, "?{}", marker_line(line.text, changed))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Splits a line into words, runs of whitespace and single punctuation
+/// characters.
+fn tokens(line: &str) -> Vec<&str> {
+    let class = |c: char| {
+        if c.is_alphanumeric() || c == '_' {
+            0
+        } else if c.is_whitespace() {
+            1
+        } else {
+            2
+        }
+    };
+    let mut out = vec![];
+    let mut start = 0;
+    let mut prev = None;
+    for (i, c) in line.char_indices() {
+        let cur = class(c);
+        if i != start && (prev != Some(cur) || cur == 2) {
+            out.push(&line[start..i]);
+            start = i;
+        }
+        prev = Some(cur);
+    }
+    if start < line.len() {
+        out.push(&line[start..]);
+    }
+    out
+}
+
+/// Marks the characters that changed between two lines, first by word and
+/// then by character within replaced words.  Returns `None` when the lines
+/// have too little in common for the marks to be useful.
+fn intraline(actual: &str, expected: &str) -> Option<(Vec<bool>, Vec<bool>)> {
+    let (ta, te) = (tokens(actual), tokens(expected));
+    let mut changed_actual = vec![];
+    let mut changed_expected = vec![];
+    let mut common = 0;
+    let (mut pending_actual, mut pending_expected) = (String::new(), String::new());
+
+    let flush = |pa: &mut String, pe: &mut String, ca: &mut Vec<bool>, ce: &mut Vec<bool>| {
+        let chars = diff::chars(pa, pe);
+        let same = chars
+            .iter()
+            .filter(|c| matches!(c, diff::Result::Both(..)))
+            .count();
+        if same * 2 >= pa.chars().count().max(pe.chars().count()) {
+            for c in chars {
+                match c {
+                    diff::Result::Left(_) => ca.push(true),
+                    diff::Result::Right(_) => ce.push(true),
+                    diff::Result::Both(..) => {
+                        ca.push(false);
+                        ce.push(false);
+                    }
+                }
+            }
+        } else {
+            ca.extend(pa.chars().map(|_| true));
+            ce.extend(pe.chars().map(|_| true));
+        }
+        pa.clear();
+        pe.clear();
+    };
+
+    for d in diff::slice(&ta, &te) {
+        match d {
+            diff::Result::Left(t) => pending_actual.push_str(t),
+            diff::Result::Right(t) => pending_expected.push_str(t),
+            diff::Result::Both(t, _) => {
+                flush(
+                    &mut pending_actual,
+                    &mut pending_expected,
+                    &mut changed_actual,
+                    &mut changed_expected,
+                );
+                let n = t.chars().count();
+                common += n;
+                changed_actual.extend((0..n).map(|_| false));
+                changed_expected.extend((0..n).map(|_| false));
+            }
+        }
+    }
+    flush(
+        &mut pending_actual,
+        &mut pending_expected,
+        &mut changed_actual,
+        &mut changed_expected,
+    );
+
+    let longest = changed_actual.len().max(changed_expected.len());
+    if common * 2 < longest {
+        None
+    } else {
+        Some((changed_actual, changed_expected))
+    }
+}
+
+/// Puts a `^` under every changed character, keeping tabs so that the marks
+/// line up with the line above.
+fn marker_line(text: &str, changed: &[bool]) -> String {
+    let marks: String = text
+        .chars()
+        .zip(changed)
+        .map(|(c, &changed)| match (changed, c) {
+            (true, _) => '^',
+            (false, '\t') => '\t',
+            (false, _) => ' ',
+        }).collect();
+    marks.trim_end().to_owned()
+}
+
+/// Renders the hunks with changed lines colored and changed characters
+/// wrapped in `<ins>` or `<del>`.
+fn lines_html(lines: &[DiffLine], hunks: &[(usize, usize)], skip: usize) -> String {
+    let mut html = String::new();
+    for &(start, end) in hunks {
+        html.push_str(&escape_html(&hunk_header(lines, start, end, skip)));
+        html.push('\n');
+        for line in &lines[start..end] {
+            let (tag, style) = match (line.kind, line.moved) {
+                (LineKind::Actual, Some(from)) => (
+                    "ins",
+                    format!(r#" style="background: #ddf" title="moved from line {}""#, from),
+                ),
+                (LineKind::Expected, Some(to)) => (
+                    "del",
+                    format!(r#" style="background: #ddf" title="moved to line {}""#, to),
+                ),
+                (LineKind::Actual, None) => ("ins", r#" style="background: #dfd""#.to_owned()),
+                (LineKind::Expected, None) => ("del", r#" style="background: #fdd""#.to_owned()),
+                (LineKind::Both, _) => ("", String::new()),
+            };
+            html.push_str(&format!("<span{}>{}", style, escape_html(line.prefix())));
+            let changed = line.changed.clone().unwrap_or_default();
+            let mut open = false;
+            for (i, c) in line.text.chars().enumerate() {
+                let marked = changed.get(i).cloned().unwrap_or(false);
+                if marked != open {
+                    html.push_str(&format!("<{}{}>", if open { "/" } else { "" }, tag));
+                    open = marked;
+                }
+                html.push_str(&escape_html(&c.to_string()));
+            }
+            if open {
+                html.push_str(&format!("</{}>", tag));
+            }
+            html.push_str("</span>\n");
+        }
+    }
+    html
+}
@@ -0,0 +1,523 @@
+use super::super::provider::{Provider, WriteRequester};
+use super::super::*;
+use super::add_extension;
+use super::changes::{changes_html, Change};
+
+use diff;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Decides which differences between two XML or HTML documents matter.
+///
+/// Attribute order, comments and whitespace-only text never matter, and
+/// numbers inside geometry attributes like `x`, `width`, `d` or `transform`
+/// are compared by value (`1.0` equals `1`).  Other attributes, like `id` or
+/// `version`, are compared as they are written.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct XmlOptions {
+    /// Rounds numbers inside geometry attributes to this many decimal places.
+    pub round_numbers: Option<usize>,
+    /// Keeps the whitespace of text nodes instead of trimming it and
+    /// collapsing runs of whitespace into a single space.
+    pub preserve_whitespace: bool,
+}
+
+pub trait XmlDiffExtension {
+    /// Returns a writer whose contents are parsed as XML and compared
+    /// element by element.
+    fn xml_writer_with<N>(&self, filename: N, options: XmlOptions) -> Writer
+    where
+        N: AsRef<Path>;
+
+    /// Like `xml_writer_with`, but parses HTML: tag names are case
+    /// insensitive, void elements need no end tag and unclosed elements are
+    /// closed by their parent.
+    fn html_writer_with<N>(&self, filename: N, options: XmlOptions) -> Writer
+    where
+        N: AsRef<Path>;
+
+    fn xml_writer<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>,
+    {
+        self.xml_writer_with(filename, XmlOptions::default())
+    }
+
+    fn html_writer<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>,
+    {
+        self.html_writer_with(filename, XmlOptions::default())
+    }
+
+    fn xml<N, S>(&self, filename: N, text: S) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        S: AsRef<str>,
+    {
+        let mut w = self.xml_writer(filename);
+        write!(w, "{}", text.as_ref())
+    }
+
+    fn html<N, S>(&self, filename: N, text: S) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        S: AsRef<str>,
+    {
+        let mut w = self.html_writer(filename);
+        write!(w, "{}", text.as_ref())
+    }
+}
+
+impl XmlDiffExtension for Provider {
+    fn xml_writer_with<S>(&self, filename: S, options: XmlOptions) -> Writer
+    where
+        S: AsRef<Path>,
+    {
+        markup_writer(self, filename, Syntax::Xml, options)
+    }
+
+    fn html_writer_with<S>(&self, filename: S, options: XmlOptions) -> Writer
+    where
+        S: AsRef<Path>,
+    {
+        markup_writer(self, filename, Syntax::Html, options)
+    }
+}
+
+fn markup_writer<S: AsRef<Path>>(
+    provider: &Provider,
+    filename: S,
+    syntax: Syntax,
+    options: XmlOptions,
+) -> Writer {
+    let redactions = provider.redactions();
+    let diff_redactions = redactions.clone();
+    let diff_options = options.clone();
+    provider
+        .custom_test_parallel(
+            filename,
+            move |a, b| xml_eq(a, b, syntax, &options),
+            move |a, b, c, d| {
+                let changes = xml_diff(a, b, c, d, syntax, &diff_options)?;
+                let redactions_html = diff_redactions.to_html();
+                d.set_html_renderer(move |_, _, _| {
+                    let mut html = changes_html(&changes);
+                    html.push_str(&redactions_html);
+                    html
+                });
+                Ok(())
+            },
+        ).with_redactions(redactions)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Syntax {
+    Xml,
+    Html,
+}
+
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+struct Element {
+    name: String,
+    attributes: BTreeMap<String, String>,
+    children: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    fn new(name: String) -> Element {
+        Element {
+            name,
+            attributes: BTreeMap::new(),
+            children: vec![],
+        }
+    }
+
+    /// The start tag with its attributes in canonical order.
+    fn describe(&self) -> String {
+        let mut out = format!("<{}", self.name);
+        for (k, v) in &self.attributes {
+            out.push_str(&format!(" {}={:?}", k, v));
+        }
+        out.push('>');
+        out
+    }
+}
+
+impl Node {
+    fn key(&self) -> &str {
+        match self {
+            Node::Element(e) => &e.name,
+            Node::Text(_) => "text()",
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Node::Element(e) => e.describe(),
+            Node::Text(t) => format!("{:?}", t),
+        }
+    }
+}
+
+fn invalid<E: Display>(e: E) -> IoError {
+    IoError::new(ErrorKind::InvalidData, e.to_string())
+}
+
+fn parse<R: Read>(mut r: R, syntax: Syntax, options: &XmlOptions) -> IoResult<Element> {
+    let mut text = String::new();
+    r.read_to_string(&mut text)?;
+
+    let mut reader = Reader::from_str(&text);
+    if syntax == Syntax::Html {
+        let config = reader.config_mut();
+        config.check_end_names = false;
+        config.allow_unmatched_ends = true;
+    }
+
+    let mut stack = vec![Element::new(String::new())];
+    loop {
+        match reader.read_event().map_err(invalid)? {
+            Event::Start(start) => {
+                let element = start_element(&start, syntax, options)?;
+                if syntax == Syntax::Html && VOID_ELEMENTS.contains(&element.name.as_str()) {
+                    push_child(&mut stack, Node::Element(element));
+                } else if syntax == Syntax::Html
+                    && (element.name == "script" || element.name == "style")
+                {
+                    let mut element = element;
+                    let content = reader.read_text(start.name()).map_err(invalid)?;
+                    element.children.push(Node::Text(content.into_owned()));
+                    push_child(&mut stack, Node::Element(element));
+                } else {
+                    stack.push(element);
+                }
+            }
+            Event::Empty(start) => {
+                let element = start_element(&start, syntax, options)?;
+                push_child(&mut stack, Node::Element(element));
+            }
+            Event::End(end) => {
+                let name = element_name(end.name().as_ref(), syntax);
+                // HTML end tags close every element opened after the
+                // matching start tag; stray end tags are ignored.
+                if let Some(i) = stack.iter().rposition(|e| e.name == name) {
+                    while stack.len() > i.max(1) {
+                        let element = stack.pop().unwrap();
+                        push_child(&mut stack, Node::Element(element));
+                    }
+                }
+            }
+            Event::Text(t) => {
+                let text = match t.unescape() {
+                    Ok(text) => text.into_owned(),
+                    Err(_) => String::from_utf8_lossy(&t).into_owned(),
+                };
+                push_child(&mut stack, Node::Text(text));
+            }
+            Event::CData(c) => {
+                let text = String::from_utf8_lossy(&c.into_inner()).into_owned();
+                push_child(&mut stack, Node::Text(text));
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    while stack.len() > 1 {
+        let element = stack.pop().unwrap();
+        push_child(&mut stack, Node::Element(element));
+    }
+
+    let mut root = stack.pop().unwrap();
+    normalize_text(&mut root, options);
+    Ok(root)
+}
+
+fn element_name(name: &[u8], syntax: Syntax) -> String {
+    let name = String::from_utf8_lossy(name).into_owned();
+    match syntax {
+        Syntax::Xml => name,
+        Syntax::Html => name.to_lowercase(),
+    }
+}
+
+fn start_element(start: &BytesStart, syntax: Syntax, options: &XmlOptions) -> IoResult<Element> {
+    let mut element = Element::new(element_name(start.name().as_ref(), syntax));
+    let attributes = match syntax {
+        Syntax::Xml => start.attributes(),
+        Syntax::Html => start.html_attributes(),
+    };
+    for attribute in attributes {
+        let attribute = attribute.map_err(invalid)?;
+        let key = element_name(attribute.key.as_ref(), syntax);
+        let value = match attribute.unescape_value() {
+            Ok(value) => value.into_owned(),
+            Err(_) => String::from_utf8_lossy(&attribute.value).into_owned(),
+        };
+        let value = if is_numeric_attribute(&key) {
+            normalize_numbers(&value, options.round_numbers)
+        } else {
+            value
+        };
+        element.attributes.insert(key, value);
+    }
+    Ok(element)
+}
+
+fn push_child(stack: &mut [Element], node: Node) {
+    stack.last_mut().unwrap().children.push(node);
+}
+
+/// Merges adjacent text nodes and, unless whitespace is preserved, trims
+/// them and drops the ones that are left empty.
+fn normalize_text(element: &mut Element, options: &XmlOptions) {
+    let mut children: Vec<Node> = vec![];
+    for child in element.children.drain(..) {
+        match (children.last_mut(), child) {
+            (Some(Node::Text(prev)), Node::Text(t)) => prev.push_str(&t),
+            (_, child) => children.push(child),
+        }
+    }
+    for child in &mut children {
+        match child {
+            Node::Element(e) => normalize_text(e, options),
+            Node::Text(t) if !options.preserve_whitespace => {
+                *t = t.split_whitespace().collect::<Vec<_>>().join(" ");
+            }
+            Node::Text(_) => {}
+        }
+    }
+    children.retain(|c| match c {
+        Node::Text(t) => !t.is_empty(),
+        Node::Element(_) => true,
+    });
+    element.children = children;
+}
+
+/// The attributes that hold coordinates, lengths or other numbers, mostly
+/// from SVG.  Only these have their numbers compared by value.
+const NUMERIC_ATTRIBUTES: &[&str] = &[
+    "x", "y", "x1", "y1", "x2", "y2", "cx", "cy", "dx", "dy", "fx", "fy", "r", "rx", "ry",
+    "width", "height", "d", "points", "transform", "gradientTransform", "patternTransform",
+    "viewBox", "offset", "opacity", "fill-opacity", "stroke-opacity", "stop-opacity",
+    "stroke-width", "stroke-dasharray", "stroke-dashoffset", "stroke-miterlimit",
+    "font-size", "letter-spacing", "word-spacing", "rotate", "pathLength", "stdDeviation",
+];
+
+/// Whether numbers inside the attribute `key` are compared by value.  HTML
+/// attribute names are lowercased, so the names are matched ignoring case.
+fn is_numeric_attribute(key: &str) -> bool {
+    NUMERIC_ATTRIBUTES.iter().any(|name| name.eq_ignore_ascii_case(key))
+}
+
+/// Rewrites the numbers inside an attribute value in a canonical form, so
+/// that `1.0`, `1` and `1e0` compare equal.  Values that look like colors,
+/// fragment references or URLs are left untouched.
+fn normalize_numbers(value: &str, round: Option<usize>) -> String {
+    if value.starts_with('#') || value.contains("://") {
+        return value.to_owned();
+    }
+    static NUMBER: OnceLock<Regex> = OnceLock::new();
+    let number =
+        NUMBER.get_or_init(|| Regex::new(r"[-+]?(\d+\.?\d*|\.\d+)([eE][-+]?\d+)?").unwrap());
+    let mut out = String::new();
+    let mut last = 0;
+    for m in number.find_iter(value) {
+        let before = &value[..m.start()];
+        if !starts_number(before) {
+            continue;
+        }
+        let normalized = if m.as_str().contains(|c| ".eE".contains(c)) {
+            match normalize_float(m.as_str(), round) {
+                Some(n) => n,
+                None => continue,
+            }
+        } else {
+            normalize_integer(m.as_str())
+        };
+        out.push_str(&value[last..m.start()]);
+        out.push_str(&normalized);
+        last = m.end();
+    }
+    out.push_str(&value[last..]);
+    out
+}
+
+/// Rounds a number with a fraction or exponent and writes it back in its
+/// shortest form.
+fn normalize_float(number: &str, round: Option<usize>) -> Option<String> {
+    let mut n: f64 = number.parse().ok()?;
+    if let Some(places) = round {
+        let scale = 10f64.powi(places as i32);
+        n = (n * scale).round() / scale;
+    }
+    if n == 0.0 {
+        n = 0.0;
+    }
+    Some(n.to_string())
+}
+
+/// Drops the plus sign and leading zeros of an integer, keeping all of its
+/// digits however long it is.
+fn normalize_integer(number: &str) -> String {
+    let (negative, digits) = match number.as_bytes()[0] {
+        b'-' => (true, &number[1..]),
+        b'+' => (false, &number[1..]),
+        _ => (false, number),
+    };
+    let digits = digits.trim_start_matches('0');
+    match (negative, digits) {
+        (_, "") => "0".to_owned(),
+        (true, digits) => format!("-{}", digits),
+        (false, digits) => digits.to_owned(),
+    }
+}
+
+/// Whether a number may start after `before`: at the start of the value,
+/// after a separator, or after a single command letter like in SVG path data.
+fn starts_number(before: &str) -> bool {
+    let mut chars = before.chars().rev();
+    match chars.next() {
+        None => true,
+        Some(c) if c.is_whitespace() || ",;:(".contains(c) => true,
+        Some(c) if c.is_ascii_alphabetic() => match chars.next() {
+            None => true,
+            Some(c) => c.is_whitespace() || c.is_ascii_digit() || ",;:().".contains(c),
+        },
+        Some(_) => false,
+    }
+}
+
+/// The path segments of `children`; siblings that share a name are told
+/// apart by their 1-based position.
+fn segments(children: &[Node]) -> Vec<String> {
+    let mut counts = BTreeMap::new();
+    for child in children {
+        *counts.entry(child.key()).or_insert(0) += 1;
+    }
+    let mut seen = BTreeMap::new();
+    children
+        .iter()
+        .map(|child| {
+            let n = seen.entry(child.key()).or_insert(0);
+            *n += 1;
+            if counts[child.key()] > 1 {
+                format!("{}[{}]", child.key(), n)
+            } else {
+                child.key().to_owned()
+            }
+        }).collect()
+}
+
+fn diff_elements(path: &str, expected: &Element, actual: &Element, out: &mut Vec<Change<String>>) {
+    for (k, ev) in &expected.attributes {
+        let attribute = format!("{}@{}", path, k);
+        match actual.attributes.get(k) {
+            Some(av) if av == ev => {}
+            Some(av) => out.push(Change::Changed {
+                path: attribute,
+                expected: format!("{:?}", ev),
+                actual: format!("{:?}", av),
+            }),
+            None => out.push(Change::Removed {
+                path: attribute,
+                expected: format!("{:?}", ev),
+            }),
+        }
+    }
+    for (k, av) in &actual.attributes {
+        if !expected.attributes.contains_key(k) {
+            out.push(Change::Added {
+                path: format!("{}@{}", path, k),
+                actual: format!("{:?}", av),
+            });
+        }
+    }
+
+    let expected_keys: Vec<_> = expected.children.iter().map(Node::key).collect();
+    let actual_keys: Vec<_> = actual.children.iter().map(Node::key).collect();
+    let expected_segments = segments(&expected.children);
+    let actual_segments = segments(&actual.children);
+    let (mut i, mut j) = (0, 0);
+    for result in diff::slice(&expected_keys, &actual_keys) {
+        match result {
+            diff::Result::Both(..) => {
+                let child = format!("{}/{}", path, expected_segments[i]);
+                match (&expected.children[i], &actual.children[j]) {
+                    (Node::Element(e), Node::Element(a)) => diff_elements(&child, e, a, out),
+                    (e, a) if e == a => {}
+                    (e, a) => out.push(Change::Changed {
+                        path: child,
+                        expected: e.describe(),
+                        actual: a.describe(),
+                    }),
+                }
+                i += 1;
+                j += 1;
+            }
+            diff::Result::Left(_) => {
+                out.push(Change::Removed {
+                    path: format!("{}/{}", path, expected_segments[i]),
+                    expected: expected.children[i].describe(),
+                });
+                i += 1;
+            }
+            diff::Result::Right(_) => {
+                out.push(Change::Added {
+                    path: format!("{}/{}", path, actual_segments[j]),
+                    actual: actual.children[j].describe(),
+                });
+                j += 1;
+            }
+        }
+    }
+}
+
+pub(crate) fn xml_eq<R1: Read, R2: Read>(
+    r1: R1,
+    r2: R2,
+    syntax: Syntax,
+    options: &XmlOptions,
+) -> IoResult<bool> {
+    Ok(parse(r1, syntax, options)? == parse(r2, syntax, options)?)
+}
+
+/// Writes the element-level changes to a `.diff` file and returns them.
+pub(crate) fn xml_diff<R1: Read, R2: Read>(
+    r1: R1,
+    r2: R2,
+    path: &Path,
+    write_requester: &mut WriteRequester,
+    syntax: Syntax,
+    options: &XmlOptions,
+) -> IoResult<Vec<Change<String>>> {
+    let actual = parse(r1, syntax, options)?;
+    let expected = parse(r2, syntax, options)?;
+    let mut changes = vec![];
+    diff_elements("", &expected, &actual, &mut changes);
+
+    write_requester.request(add_extension(path, ".diff"), |w| {
+        for change in &changes {
+            writeln!(w, "{}", change)?;
+        }
+        Ok(())
+    })?;
+    Ok(changes)
+}
@@ -0,0 +1,11 @@
+use sha2::{Digest, Sha256};
+
+pub(crate) fn hex_digest(hasher: Sha256) -> String {
+    format!("{:x}", hasher.finalize())
+}
+
+/// The contents of the file that stands in for an output that is only
+/// checked by its digest.
+pub(crate) fn digest_file(hash: &str, size: u64) -> String {
+    format!("sha256 {}\nsize {}\n", hash, size)
+}
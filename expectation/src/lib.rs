@@ -1,24 +1,62 @@
 extern crate expectation_shared;
+extern crate serde;
 extern crate serde_json;
 extern crate marksman_escape;
+extern crate regex;
+extern crate sha2;
 
-#[cfg(feature = "text")]
+#[cfg(any(feature = "text", feature = "xml"))]
 extern crate diff;
+#[cfg(feature = "text")]
+extern crate imara_diff;
+#[cfg(feature = "text")]
+extern crate similar;
+#[cfg(feature = "xml")]
+extern crate quick_xml;
+#[cfg(feature = "text")]
+extern crate unicode_normalization;
 
 #[cfg(feature = "image")]
 extern crate image;
+#[cfg(feature = "apng")]
+extern crate png;
+
+#[cfg(feature = "svg-raster")]
+extern crate resvg;
+
+#[cfg(feature = "table")]
+extern crate csv;
+
+#[cfg(feature = "serialize")]
+extern crate ron;
+#[cfg(feature = "serialize")]
+extern crate serde_yaml;
+#[cfg(feature = "serialize")]
+extern crate toml;
 
 pub mod extensions;
+mod hashing;
 mod ipc;
 mod provider;
+mod redaction;
 #[cfg(test)]
 mod test;
 
 pub use provider::Provider;
+pub use redaction::Redactions;
 
+use expectation_shared::compressed::{Compression, CompressedFileSystem};
 use expectation_shared::filesystem::*;
+use expectation_shared::expectignore::{ExpectIgnore, IGNORE_FILE};
+use expectation_shared::hashes::{Entry, HashManifest, StatCache, MANIFEST, STAT_CACHE};
+use expectation_shared::objects::{pointer_path, ObjectFileSystem, OBJECTS};
+use expectation_shared::size::parse_size;
 use expectation_shared::{Result as EResult, ResultKind};
+use provider::Check;
 use std::collections::HashSet;
+use std::io::SeekFrom;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::path::{Path, PathBuf};
 
 pub use provider::Writer;
@@ -39,6 +77,19 @@ fn file_filter(file: &Path) -> bool {
     }
 }
 
+/// Whether the output of earlier runs is kept, for debugging.  Set
+/// `CARGO_EXPECT_KEEP_OUTPUT` to keep it.
+fn keep_output() -> bool {
+    std::env::var_os("CARGO_EXPECT_KEEP_OUTPUT").is_some()
+}
+
+/// Removes what earlier runs of a test left in its actual and diff
+/// directories.
+fn clear_output(fs: &FileSystem, name: &str) -> std::io::Result<()> {
+    fs.remove_dir(&Path::new("actual").join(name))?;
+    fs.remove_dir(&Path::new("diff").join(name))
+}
+
 pub fn expect<F: FnOnce(Provider)>(name: &str, f: F) {
     if !name.starts_with("expectation_test_") {
         panic!("expectation test {} is an invalid test name.  It must start with \"expectation_test_\"", name);
@@ -52,14 +103,24 @@ pub fn expect<F: FnOnce(Provider)>(name: &str, f: F) {
     let top_fs = RealFileSystem {
         root: Path::new("./").canonicalize().unwrap(),
     }.subsystem(Path::new("expectation-tests"));
-    let act_fs = top_fs
-        .subsystem(Path::new("actual"))
-        .subsystem(Path::new(name));
-    let provider = Provider::new(top_fs.duplicate(), act_fs.duplicate());
+    if !keep_output() {
+        if let Err(e) = clear_output(&*top_fs, name) {
+            panic!("could not clear the output of expectation test {}: {}", name, e);
+        }
+    }
+    let provider = Provider::new(top_fs.duplicate(), name);
     f(provider.clone());
 
     let mut succeeded = true;
-    let results = validate(name, top_fs, provider, file_filter);
+    let results = validate(
+        name,
+        top_fs,
+        provider,
+        file_filter,
+        jobs(),
+        untracked_policy(),
+        size_budgets(),
+    );
 
     ipc::send(name, results.clone());
 
@@ -97,6 +158,29 @@ pub fn expect<F: FnOnce(Provider)>(name: &str, f: F) {
                 }
                 succeeded = false;
             }
+            ResultKind::Untracked(untracked) => {
+                if untracked.fatal {
+                    println!("Untracked file");
+                    succeeded = false;
+                } else {
+                    println!("Untracked file (warning)");
+                }
+                println!("  actual  {}", untracked.actual.to_string_lossy());
+            }
+            ResultKind::OverBudget(budget) => {
+                let what = if result.file_name.as_os_str().is_empty() {
+                    "All files".into()
+                } else {
+                    result.file_name.to_string_lossy()
+                };
+                if budget.fatal {
+                    println!("Over size budget");
+                    succeeded = false;
+                } else {
+                    println!("Over size budget (warning)");
+                }
+                println!("  {}  {} bytes, limit {}", what, budget.size, budget.limit);
+            }
             _ => {}
         }
     }
@@ -105,120 +189,419 @@ pub fn expect<F: FnOnce(Provider)>(name: &str, f: F) {
     }
 }
 
+/// The number of files that are validated at the same time, from
+/// `CARGO_EXPECT_JOBS` or else the number of CPUs.
+fn jobs() -> usize {
+    std::env::var("CARGO_EXPECT_JOBS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&jobs| jobs > 0)
+        .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1)
+}
+
+/// What `validate` does with files in the actual directory of a test that
+/// the test didn't register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UntrackedPolicy {
+    Error,
+    Warn,
+    Ignore,
+}
+
+/// The policy for untracked files, from `CARGO_EXPECT_UNTRACKED` (`error`,
+/// `warn` or `ignore`).  Untracked files are warned about by default.
+fn untracked_policy() -> UntrackedPolicy {
+    match std::env::var("CARGO_EXPECT_UNTRACKED").as_ref().map(|v| v.as_str()) {
+        Ok("error") => UntrackedPolicy::Error,
+        Ok("ignore") => UntrackedPolicy::Ignore,
+        _ => UntrackedPolicy::Warn,
+    }
+}
+
+/// A warning and an error threshold for a size in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct SizeLimit {
+    warn: Option<u64>,
+    error: Option<u64>,
+}
+
+impl SizeLimit {
+    /// Reads `<warn>`, `<warn>,<error>` or `,<error>`, with sizes such as
+    /// `512K` or `10M`.
+    fn parse(s: &str) -> SizeLimit {
+        let mut parts = s.splitn(2, ',');
+        SizeLimit {
+            warn: parts.next().and_then(parse_size),
+            error: parts.next().and_then(parse_size),
+        }
+    }
+
+    /// The limit that `size` is over, and whether it is the error limit.
+    fn exceeded(&self, size: u64) -> Option<(u64, bool)> {
+        match (self.error, self.warn) {
+            (Some(error), _) if size > error => Some((error, true)),
+            (_, Some(warn)) if size > warn => Some((warn, false)),
+            _ => None,
+        }
+    }
+}
+
+/// How large the baseline files of a test may get, each and together.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct SizeBudgets {
+    file: SizeLimit,
+    test: SizeLimit,
+}
+
+/// The size budgets from `CARGO_EXPECT_FILE_SIZE` and
+/// `CARGO_EXPECT_TEST_SIZE`.  Without them, files bigger than 1 MiB are
+/// warned about.
+fn size_budgets() -> SizeBudgets {
+    let limit = |var| std::env::var(var).ok().map(|v| SizeLimit::parse(&v));
+    SizeBudgets {
+        file: limit("CARGO_EXPECT_FILE_SIZE").unwrap_or(SizeLimit {
+            warn: Some(1 << 20),
+            error: None,
+        }),
+        test: limit("CARGO_EXPECT_TEST_SIZE").unwrap_or_default(),
+    }
+}
+
+/// Calls `f` on every item on up to `jobs` threads, returning the results in
+/// the order of `items`.
+fn parallel_map<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    if jobs <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<R>>> = items.iter().map(|_| Mutex::new(None)).collect();
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(items.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                match items.get(i) {
+                    Some(item) => *results[i].lock().unwrap() = Some(f(item)),
+                    None => break,
+                }
+            });
+        }
+    });
+    results
+        .into_iter()
+        .map(|r| r.into_inner().unwrap().unwrap())
+        .collect()
+}
+
+/// The directories of one test.
+struct Dirs {
+    actual: Box<FileSystem>,
+    /// Expected files may be stored compressed, or as pointers to shared
+    /// objects.
+    expected: CompressedFileSystem,
+    /// The expected directory as it is stored, for the files in it that are
+    /// never compressed or shared.
+    stored: Box<FileSystem>,
+    diff: Box<FileSystem>,
+    /// Where the stat cache of the test is kept.
+    stat_cache: Box<FileSystem>,
+}
+
+/// What is known about the hashes of a test's files.
+struct KnownHashes {
+    /// The hashes of the outputs that were hashed while they were written.
+    outputs: provider::Hashes,
+    manifest: HashManifest,
+    stat_cache: Mutex<StatCache>,
+}
+
 fn validate<Fi: Fn(&Path) -> bool>(
     name: &str,
     fs: Box<FileSystem>,
     provider: Provider,
     filter: Fi,
+    jobs: usize,
+    untracked: UntrackedPolicy,
+    budgets: SizeBudgets,
 ) -> Vec<EResult> {
     let mut visited = HashSet::new();
     let mut out = Vec::new();
 
-    let expected_fs = fs
-        .subsystem(Path::new("expected"))
-        .subsystem(Path::new(name));
-    let actual_fs = fs.subsystem(Path::new("actual")).subsystem(Path::new(name));
-    let diff_fs = fs.subsystem(Path::new("diff")).subsystem(Path::new(name));
+    let stored = fs.subsystem(Path::new("expected")).subsystem(Path::new(name));
+    let dirs = Dirs {
+        expected: CompressedFileSystem::new(
+            Box::new(ObjectFileSystem::new(
+                stored.duplicate(),
+                fs.subsystem(Path::new(OBJECTS)),
+                false,
+            )),
+            None,
+        ),
+        stored,
+        actual: fs.subsystem(Path::new("actual")).subsystem(Path::new(name)),
+        diff: fs.subsystem(Path::new("diff")).subsystem(Path::new(name)),
+        stat_cache: fs.subsystem(Path::new("actual")).subsystem(Path::new(STAT_CACHE)),
+    };
+    let ignore = ExpectIgnore::load(&*fs, Path::new("expected"), name);
 
     #[allow(unused_variables)]
     let fs = ();
 
-    for (file, eq, diff) in provider.take_files() {
-        if !filter(&file) || visited.contains(&file) {
-            continue;
+    visited.extend(provider.take_sidecars());
+    visited.insert(PathBuf::from(MANIFEST));
+    visited.insert(PathBuf::from(IGNORE_FILE));
+    let stat_cache = StatCache::load(&*dirs.stat_cache, Path::new(name));
+    let hashes = KnownHashes {
+        outputs: provider.take_hashes(),
+        manifest: HashManifest::load(&*dirs.stored, Path::new("")),
+        stat_cache: Mutex::new(stat_cache.clone()),
+    };
+
+    let mut files = provider.take_files();
+    let mut known = visited.clone();
+    known.extend(provider.take_uncompared());
+    known.extend(files.iter().map(|(file, _)| file.clone()));
+    files.retain(|(file, _)| filter(file) && visited.insert(file.clone()));
+
+    // Local checks can't leave this thread, so they run here, and the
+    // others run in parallel to fill in the gaps.
+    let mut checked: Vec<_> = files
+        .iter()
+        .map(|(file, check)| match check {
+            Check::Local(eq, diff) => {
+                Some(validate_file(name, file, &**eq, &**diff, &dirs, &hashes))
+            }
+            Check::Shared(..) => None,
+        }).collect();
+    let shared: Vec<_> = files
+        .iter()
+        .filter_map(|(file, check)| match check {
+            Check::Shared(eq, diff) => Some((file, &**eq, &**diff)),
+            Check::Local(..) => None,
+        }).collect();
+    let mut shared_checked = parallel_map(&shared, jobs, |&(file, eq, diff)| {
+        validate_file(name, file, eq, diff, &dirs, &hashes)
+    }).into_iter();
+    for results in checked.iter_mut().filter(|results| results.is_none()) {
+        *results = shared_checked.next();
+    }
+    let checked: Vec<_> = checked.into_iter().map(Option::unwrap).collect();
+    // The cache only saves time, so it is fine if it can't be written.
+    let new_stat_cache = hashes.stat_cache.into_inner().unwrap();
+    if new_stat_cache != stat_cache {
+        let _ = new_stat_cache.save(&*dirs.stat_cache, Path::new(name));
+    }
+    // Files are reported and held to the budgets by the size they take up
+    // as expected files, which is what `cargo expect stats` lists: files
+    // that match are measured where they are stored, and the others as they
+    // would be stored once promoted.
+    let sizes: Vec<_> = files
+        .iter()
+        .zip(&checked)
+        .map(|((file, _), results)| {
+            if results.iter().all(|r| matches!(r.kind, ResultKind::Ok)) {
+                dirs.expected.size(file).ok()
+            } else {
+                promoted_size(&dirs, file).ok()
+            }
+        }).collect();
+    for (results, size) in checked.into_iter().zip(&sizes) {
+        out.extend(results.into_iter().map(|result| match (&result.kind, size) {
+            (ResultKind::Ok, Some(size))
+            | (ResultKind::ExpectedNotFound(_), Some(size))
+            | (ResultKind::Difference(_), Some(size)) => result.with_size(*size),
+            _ => result,
+        }));
+    }
+
+    let mut total = 0;
+    for ((file, _), size) in files.iter().zip(sizes) {
+        let size = match size {
+            Some(size) => size,
+            None => continue,
+        };
+        total += size;
+        if let Some((limit, fatal)) = budgets.file.exceeded(size) {
+            out.push(EResult::over_budget(name, file, size, limit, fatal));
         }
-        visited.insert(file.clone());
+    }
+    if let Some((limit, fatal)) = budgets.test.exceeded(total) {
+        out.push(EResult::over_budget(name, "", total, limit, fatal));
+    }
 
-        if !actual_fs.exists(&file) {
-            out.push(EResult::actual_not_found(
-                name,
-                &file,
-                actual_fs.full_path_for(&file),
-                expected_fs.full_path_for(&file),
-            ));
+    // A registered file whose name ends like a compressed file or a pointer
+    // is listed under its name without that ending.
+    let registered = |file: &Path| {
+        visited.contains(file)
+            || visited.contains(&pointer_path(file))
+            || Compression::ALL
+                .iter()
+                .any(|c| visited.contains(&c.stored_path(file)))
+    };
+    for file in dirs.expected.files() {
+        if !filter(&file) || registered(&file) || ignore.is_ignored(&file) {
             continue;
         }
 
-        if !expected_fs.exists(&file) {
-            out.push(EResult::expected_not_found(
+        if !dirs.actual.exists(&file) {
+            out.push(EResult::actual_not_found(
                 name,
                 &file,
-                actual_fs.full_path_for(&file),
-                expected_fs.full_path_for(&file),
+                dirs.actual.full_path_for(&file),
+                dirs.expected.full_path_for(&file),
             ));
             continue;
         }
+    }
 
-        let mut is_eq = false;
-        let res = actual_fs.read(&file, &mut |actual_read| {
-            expected_fs.read(&file, &mut |expected_read| {
-                is_eq = eq(actual_read, expected_read)?;
-                Ok(())
-            })
-        });
-
-        let is_eq = match res {
-            Ok(_) => is_eq,
-            Err(e) => {
-                out.push(EResult::io_error(name, &file, e));
+    if untracked != UntrackedPolicy::Ignore {
+        let mut files = dirs.actual.files();
+        files.sort();
+        for file in files {
+            if !filter(&file) || known.contains(&file) {
                 continue;
             }
-        };
-
-        if !is_eq {
-            let mut write_requester = provider::WriteRequester {
-                fs: diff_fs.duplicate(),
-                files: vec![],
-                html_renderer: None,
-            };
-
-            let diff_result = actual_fs.read(&file, &mut |actual_read| {
-                expected_fs.read(&file, &mut |expected_read| {
-                    diff(actual_read, expected_read, &file, &mut write_requester)
-                })
-            });
-
-            let actual_file = actual_fs.full_path_for(&file);
-            let expected_file = expected_fs.full_path_for(&file);
-            let diff_files = write_requester.files;
-
-            let html = write_requester
-                .html_renderer
-                .map(|f| f(&actual_file, &expected_file, &diff_files));
-
-            out.push(EResult::difference(
+            out.push(EResult::untracked(
                 name,
                 &file,
-                actual_file,
-                expected_file,
-                diff_files,
-                html,
+                dirs.actual.full_path_for(&file),
+                untracked == UntrackedPolicy::Error,
             ));
+        }
+    }
 
-            if let Err(e) = diff_result {
-                out.push(EResult::io_error(name, &file, e));
-            }
-            continue;
+    out
+}
+
+/// The size that the actual file would take up in storage if it was
+/// promoted, compressed like the expected file that it replaces.
+fn promoted_size(dirs: &Dirs, file: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    dirs.actual.read(file, &mut |r| {
+        size = dirs.expected.stored_size(file, r)?;
+        Ok(())
+    })?;
+    Ok(size)
+}
+
+/// Whether the expected file hashes to `hash`.  Files that haven't changed
+/// since the stat cache last saw them aren't hashed again.
+fn expected_has_hash(dirs: &Dirs, stat_cache: &Mutex<StatCache>, file: &Path, hash: &str) -> bool {
+    if stat_cache.lock().unwrap().confirms(&dirs.expected, file, hash) {
+        return true;
+    }
+    match Entry::for_file(&dirs.expected, file) {
+        Ok(entry) if entry.hash == hash => {
+            let _ = stat_cache.lock().unwrap().record(&dirs.expected, file, hash);
+            true
         }
+        _ => false,
+    }
+}
+
+fn validate_file(
+    name: &str,
+    file: &Path,
+    eq: &provider::LocalComparator,
+    diff: &provider::LocalDiffer,
+    dirs: &Dirs,
+    hashes: &KnownHashes,
+) -> Vec<EResult> {
+    if !dirs.actual.exists(file) {
+        let result = EResult::actual_not_found(
+            name,
+            file,
+            dirs.actual.full_path_for(file),
+            dirs.expected.full_path_for(file),
+        );
+        return vec![result];
+    }
 
-        out.push(EResult::ok(name, &file));
+    if !dirs.expected.exists(file) {
+        let result = EResult::expected_not_found(
+            name,
+            file,
+            dirs.actual.full_path_for(file),
+            dirs.expected.full_path_for(file),
+        );
+        return vec![result];
     }
 
-    for file in expected_fs.files() {
-        if !filter(&file) || visited.contains(&file) {
-            continue;
+    // Outputs that hash the same as their expected file are confirmed
+    // without comparing them, as long as the expected file still has the
+    // hash it was promoted with.
+    if let (Some(hash), Some(entry)) = (hashes.outputs.get(file), hashes.manifest.get(file)) {
+        if *hash == entry.hash && expected_has_hash(dirs, &hashes.stat_cache, file, hash) {
+            return vec![EResult::ok(name, file)];
         }
+    }
 
-        if !actual_fs.exists(&file) {
-            out.push(EResult::actual_not_found(
-                name,
-                &file,
-                actual_fs.full_path_for(&file),
-                expected_fs.full_path_for(&file),
-            ));
-            continue;
+    // Each file is opened once: the diff runs on the same readers after
+    // they have been rewound.
+    let mut write_requester = provider::WriteRequester {
+        fs: dirs.diff.duplicate(),
+        files: vec![],
+        html_renderer: None,
+        actual: Path::new("actual").join(name).join(file),
+        expected: Path::new("expected").join(name).join(file),
+    };
+    let mut is_eq = None;
+    let res = dirs.actual.read(file, &mut |actual_read| {
+        dirs.expected.read(file, &mut |expected_read| {
+            is_eq = Some(eq(actual_read, expected_read)?);
+            if is_eq == Some(true) {
+                return Ok(());
+            }
+            actual_read.seek(SeekFrom::Start(0))?;
+            expected_read.seek(SeekFrom::Start(0))?;
+            diff(actual_read, expected_read, file, &mut write_requester)
+        })
+    });
+
+    let diff_result = match (is_eq, res) {
+        (Some(true), Ok(())) => return vec![EResult::ok(name, file)],
+        (Some(false), res) => res,
+        (_, Err(e)) => return vec![EResult::io_error(name, file, e)],
+        (None, Ok(())) => unreachable!(),
+    };
+
+    let actual_file = dirs.actual.full_path_for(file);
+    let expected_file = dirs.expected.full_path_for(file);
+    let diff_files = write_requester.files;
+
+    // Browsers can't show compressed files or follow pointers, so the
+    // report links to a plain copy.
+    let mut expected_view = expected_file.clone();
+    if !dirs.stored.exists(file) {
+        let copy = Path::new("expected").join(file);
+        let copied = dirs.diff.write(&copy, &mut |w| {
+            dirs.expected.read(file, &mut |r| std::io::copy(r, w).map(|_| ()))
+        });
+        if copied.is_ok() {
+            expected_view = dirs.diff.full_path_for(&copy);
         }
     }
 
+    let html = write_requester
+        .html_renderer
+        .map(|f| f(&actual_file, &expected_view, &diff_files));
+
+    let mut out = vec![EResult::difference(
+        name,
+        file,
+        actual_file,
+        expected_file,
+        diff_files,
+        html,
+    )];
+
+    if let Err(e) = diff_result {
+        out.push(EResult::io_error(name, file, e));
+    }
     out
 }
@@ -1,13 +1,19 @@
+use std::collections::HashMap;
 use std::io::{Result as IoResult, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use expectation_shared::filesystem::{FileSystem, ReadSeek};
+use hashing::{digest_file, hex_digest};
+use redaction::Redactions;
+use sha2::{Digest, Sha256};
 
 pub struct WriteRequester {
     pub(crate) fs: Box<FileSystem>,
     pub(crate) files: Vec<PathBuf>,
     pub(crate) html_renderer: Option<Box<Fn(&Path, &Path, &[PathBuf]) -> String>>,
+    pub(crate) actual: PathBuf,
+    pub(crate) expected: PathBuf,
 }
 
 impl WriteRequester {
@@ -21,6 +27,16 @@ impl WriteRequester {
         self.files.push(self.fs.full_path_for(path.as_ref()));
         self.fs.write(path.as_ref(), &mut f)
     }
+    /// The actual file, relative to the root of the expectation tests.
+    pub fn actual_path(&self) -> &Path {
+        &self.actual
+    }
+
+    /// The expected file, relative to the root of the expectation tests.
+    pub fn expected_path(&self) -> &Path {
+        &self.expected
+    }
+
     pub fn set_html_renderer<F>(&mut self, f: F)
     where
         F: Fn(&Path, &Path, &[PathBuf]) -> String + 'static,
@@ -29,28 +45,67 @@ impl WriteRequester {
     }
 }
 
-pub(crate) type Files = Vec<(
-    PathBuf,
-    Box<for<'a> Fn(&'a mut ReadSeek, &'a mut ReadSeek) -> IoResult<bool>>,
-    Box<
-        for<'b> Fn(&'b mut ReadSeek, &'b mut ReadSeek, &'b Path, &'b mut WriteRequester)
-            -> IoResult<()>,
-    >,
-)>;
+pub(crate) type Comparator =
+    for<'a> Fn(&'a mut ReadSeek, &'a mut ReadSeek) -> IoResult<bool> + Send + Sync;
+
+pub(crate) type Differ = for<'b> Fn(&'b mut ReadSeek, &'b mut ReadSeek, &'b Path, &'b mut WriteRequester)
+    -> IoResult<()>
+    + Send
+    + Sync;
+
+pub(crate) type LocalComparator =
+    for<'a> Fn(&'a mut ReadSeek, &'a mut ReadSeek) -> IoResult<bool>;
+
+pub(crate) type LocalDiffer = for<'b> Fn(&'b mut ReadSeek, &'b mut ReadSeek, &'b Path, &'b mut WriteRequester)
+    -> IoResult<()>;
+
+/// The functions that compare and diff a registered file.  Shared ones run
+/// on the threads that validate files, local ones on the thread that runs
+/// the test.
+pub(crate) enum Check {
+    Shared(Box<Comparator>, Box<Differ>),
+    Local(Box<LocalComparator>, Box<LocalDiffer>),
+}
+
+/// The files registered by a test, in the order they were registered.
+pub(crate) type Files = Vec<(PathBuf, Check)>;
 
 pub struct Provider {
     #[cfg_attr(not(test), allow(dead_code))]
     pub(crate) root_fs: Box<FileSystem>,
     pub(crate) fs: Box<FileSystem>,
+    pub(crate) expected_fs: Box<FileSystem>,
     pub(crate) files: Arc<Mutex<Files>>,
+    sidecars: Arc<Mutex<Vec<PathBuf>>>,
+    uncompared: Arc<Mutex<Vec<PathBuf>>>,
+    hashes: Arc<Mutex<Hashes>>,
     cur_offset: PathBuf,
     is_diagnostic: bool,
+    redactions: Arc<Redactions>,
 }
 
+/// The SHA-256 hashes of the files written by `Writer`s, by path relative to
+/// the test directory.
+pub(crate) type Hashes = HashMap<PathBuf, String>;
+
+/// Writes an "actual" file.  Without a filter the output is streamed to
+/// disk as it is written; with one it is buffered until the writer is
+/// dropped, because filters see the whole file at once.
 pub struct Writer {
     inner: Vec<u8>,
+    file: Option<Box<Write>>,
     filesystem: Box<FileSystem>,
     path: PathBuf,
+    filter: Option<Box<Fn(Vec<u8>) -> Vec<u8>>>,
+    hasher: Sha256,
+    /// Where the hash of the file is recorded once it is written, unless
+    /// writing it failed.
+    record: Option<(Arc<Mutex<Hashes>>, PathBuf)>,
+    /// When set, the written bytes go to `path` and only their digest is
+    /// stored here.
+    digest: Option<PathBuf>,
+    size: u64,
+    failed: bool,
 }
 
 impl Clone for Provider {
@@ -58,9 +113,14 @@ impl Clone for Provider {
         Provider {
             root_fs: self.root_fs.duplicate(),
             fs: self.fs.duplicate(),
+            expected_fs: self.expected_fs.duplicate(),
             files: self.files.clone(),
+            sidecars: self.sidecars.clone(),
+            uncompared: self.uncompared.clone(),
+            hashes: self.hashes.clone(),
             cur_offset: self.cur_offset.clone(),
             is_diagnostic: self.is_diagnostic,
+            redactions: self.redactions.clone(),
         }
     }
 }
@@ -71,6 +131,41 @@ impl Writer {
             filesystem,
             path,
             inner: vec![],
+            file: None,
+            filter: None,
+            hasher: Sha256::new(),
+            record: None,
+            digest: None,
+            size: 0,
+            failed: false,
+        }
+    }
+
+    /// Writes the bytes to `data` instead, storing only their digest and
+    /// size in the file that this writer was created for.
+    pub(crate) fn digest_only(mut self, data: PathBuf) -> Self {
+        self.digest = Some(::std::mem::replace(&mut self.path, data));
+        self.record = None;
+        self
+    }
+
+    /// Sets a function that transforms the written bytes right before they
+    /// are stored in the filesystem.
+    pub(crate) fn with_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(Vec<u8>) -> Vec<u8> + 'static,
+    {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Applies `redactions` to the written bytes.  Does nothing when there
+    /// are no rules, so that the output can still be streamed.
+    pub(crate) fn with_redactions(self, redactions: Arc<Redactions>) -> Self {
+        if redactions.is_empty() {
+            self
+        } else {
+            self.with_filter(move |bytes| redactions.redact_bytes(bytes))
         }
     }
 }
@@ -81,9 +176,14 @@ impl Provider {
         Provider {
             root_fs: self.root_fs.duplicate(),
             fs: self.fs.duplicate().subsystem(path.as_ref()),
+            expected_fs: self.expected_fs.duplicate().subsystem(path.as_ref()),
             files: self.files.clone(),
+            sidecars: self.sidecars.clone(),
+            uncompared: self.uncompared.clone(),
+            hashes: self.hashes.clone(),
             cur_offset: self.cur_offset.join(path),
             is_diagnostic: self.is_diagnostic,
+            redactions: self.redactions.clone(),
         }
     }
 
@@ -93,13 +193,35 @@ impl Provider {
         new
     }
 
-    pub(crate) fn new(root_fs: Box<FileSystem>, fs: Box<FileSystem>) -> Provider {
+    /// Returns a provider that applies `redactions` (in addition to any
+    /// that are already active) to files written by the text, svg and serde
+    /// extensions.
+    pub fn with_redactions(&self, redactions: Redactions) -> Provider {
+        let mut new = self.clone();
+        new.redactions = Arc::new(self.redactions.merged(redactions));
+        new
+    }
+
+    pub(crate) fn redactions(&self) -> Arc<Redactions> {
+        self.redactions.clone()
+    }
+
+    pub(crate) fn new(root_fs: Box<FileSystem>, name: &str) -> Provider {
         Provider {
+            fs: root_fs
+                .subsystem(Path::new("actual"))
+                .subsystem(Path::new(name)),
+            expected_fs: root_fs
+                .subsystem(Path::new("expected"))
+                .subsystem(Path::new(name)),
             root_fs,
-            fs,
             files: Arc::new(Mutex::new(vec![])),
+            sidecars: Arc::new(Mutex::new(vec![])),
+            uncompared: Arc::new(Mutex::new(vec![])),
+            hashes: Arc::new(Mutex::new(HashMap::new())),
             cur_offset: PathBuf::new(),
             is_diagnostic: false,
+            redactions: Arc::new(Redactions::new()),
         }
     }
 
@@ -110,29 +232,118 @@ impl Provider {
         swap(&mut empty, &mut lock);
         empty
     }
+
+    /// Marks `name` as a file that lives next to the expected files without
+    /// being written by the test, so that `validate` doesn't report it as
+    /// missing.
+    #[cfg_attr(not(feature = "image"), allow(dead_code))]
+    pub(crate) fn register_sidecar<S: AsRef<Path>>(&self, name: S) {
+        if !self.is_diagnostic {
+            let mut lock = self.sidecars.lock().unwrap();
+            lock.push(self.cur_offset.join(name));
+        }
+    }
+
+    pub(crate) fn take_hashes(&self) -> Hashes {
+        use std::mem::swap;
+        let mut empty = HashMap::new();
+        let mut lock = self.hashes.lock().unwrap();
+        swap(&mut empty, &mut lock);
+        empty
+    }
+
+    /// Marks `name` as a file that the test writes to `actual/` on purpose
+    /// without comparing it, so that `validate` doesn't report it as
+    /// untracked.
+    pub(crate) fn register_uncompared<S: AsRef<Path>>(&self, name: S) {
+        let mut lock = self.uncompared.lock().unwrap();
+        lock.push(self.cur_offset.join(name));
+    }
+
+    pub(crate) fn take_uncompared(&self) -> Vec<PathBuf> {
+        use std::mem::swap;
+        let mut empty = vec![];
+        let mut lock = self.uncompared.lock().unwrap();
+        swap(&mut empty, &mut lock);
+        empty
+    }
+
+    pub(crate) fn take_sidecars(&self) -> Vec<PathBuf> {
+        use std::mem::swap;
+        let mut empty = vec![];
+        let mut lock = self.sidecars.lock().unwrap();
+        swap(&mut empty, &mut lock);
+        empty
+    }
 }
 
 impl Write for Writer {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        self.inner.write(buf)
+        if self.filter.is_some() {
+            return self.inner.write(buf);
+        }
+        if self.file.is_none() {
+            self.file = Some(self.filesystem.create(&self.path).map_err(|e| {
+                self.failed = true;
+                e
+            })?);
+        }
+        match self.file.as_mut().unwrap().write(buf) {
+            Ok(n) => {
+                self.hasher.update(&buf[..n]);
+                self.size += n as u64;
+                Ok(n)
+            }
+            Err(e) => {
+                self.failed = true;
+                Err(e)
+            }
+        }
     }
     fn flush(&mut self) -> IoResult<()> {
-        self.inner.flush()
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
     }
 }
 
 impl Drop for Writer {
     fn drop(&mut self) {
-        let mut contents = Vec::new();
-        ::std::mem::swap(&mut contents, &mut self.inner);
         // TODO: maybe don't ignore?
-        let _ = self
-            .filesystem
-            .write(&self.path, &mut |w| w.write_all(&contents));
+        let written = if let Some(mut file) = self.file.take() {
+            file.flush()
+        } else {
+            let mut contents = Vec::new();
+            ::std::mem::swap(&mut contents, &mut self.inner);
+            if let Some(filter) = &self.filter {
+                contents = filter(contents);
+            }
+            self.hasher.update(&contents);
+            self.size += contents.len() as u64;
+            self.filesystem
+                .write(&self.path, &mut |w| w.write_all(&contents))
+        };
+        if written.is_err() || self.failed {
+            return;
+        }
+        let hash = hex_digest(::std::mem::replace(&mut self.hasher, Sha256::new()));
+        if let Some(digest) = self.digest.take() {
+            let contents = digest_file(&hash, self.size);
+            let _ = self
+                .filesystem
+                .write(&digest, &mut |w| w.write_all(contents.as_bytes()));
+        }
+        if let Some((hashes, path)) = self.record.take() {
+            hashes.lock().unwrap().insert(path, hash);
+        }
     }
 }
 
 impl Provider {
+    /// Registers a file that is compared with `compare` and, when it
+    /// differs, diffed with `diff`.  The file is validated on the thread
+    /// that runs the test.
     pub fn custom_test<S, C, D>(&self, name: S, compare: C, diff: D) -> Writer
     where
         S: AsRef<Path>,
@@ -141,16 +352,38 @@ impl Provider {
                 -> IoResult<()>
             + 'static,
     {
+        self.register(name, Check::Local(Box::new(compare), Box::new(diff)))
+    }
+
+    /// Like `custom_test`, for functions that can be shared between threads.
+    /// The file is validated in parallel with the other files of the test.
+    pub fn custom_test_parallel<S, C, D>(&self, name: S, compare: C, diff: D) -> Writer
+    where
+        S: AsRef<Path>,
+        C: for<'a> Fn(&'a mut (ReadSeek), &'a mut (ReadSeek)) -> IoResult<bool>
+            + Send
+            + Sync
+            + 'static,
+        D: for<'b> Fn(&'b mut (ReadSeek), &'b mut (ReadSeek), &'b Path, &'b mut WriteRequester)
+                -> IoResult<()>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.register(name, Check::Shared(Box::new(compare), Box::new(diff)))
+    }
+
+    fn register<S: AsRef<Path>>(&self, name: S, check: Check) -> Writer {
         let name: PathBuf = name.as_ref().into();
+        let mut writer = Writer::new(self.fs.duplicate(), name.clone());
         if !self.is_diagnostic {
             let mut lock = self.files.lock().unwrap();
-            lock.push((
-                self.cur_offset.join(name.clone()),
-                Box::new(compare),
-                Box::new(diff),
-            ));
+            lock.push((self.cur_offset.join(name.clone()), check));
+            writer.record = Some((self.hashes.clone(), self.cur_offset.join(name)));
+        } else {
+            self.register_uncompared(name);
         }
-        Writer::new(self.fs.duplicate(), name)
+        writer
     }
 }
 
@@ -0,0 +1,211 @@
+use regex::Regex;
+use serde_json::Value;
+use std::env;
+
+use extensions::escape_html;
+
+/// A set of rules that replace volatile values (timestamps, UUIDs, temporary
+/// paths, ...) with stable placeholders before an "actual" file is written.
+///
+/// Rules are attached to a `Provider` with `Provider::with_redactions`.
+#[derive(Clone, Debug, Default)]
+pub struct Redactions {
+    rules: Vec<Rule>,
+}
+
+#[derive(Clone, Debug)]
+enum Rule {
+    Regex(Regex, String),
+    Path(String, Vec<Segment>, String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+    Any,
+}
+
+impl Redactions {
+    pub fn new() -> Redactions {
+        Redactions { rules: vec![] }
+    }
+
+    /// Replaces every match of `pattern` with `placeholder`.  The placeholder
+    /// may reference capture groups with `$1` or `$name`.
+    pub fn regex<P: Into<String>>(mut self, pattern: &str, placeholder: P) -> Redactions {
+        let regex = match Regex::new(pattern) {
+            Ok(r) => r,
+            Err(e) => panic!("invalid redaction pattern {:?}: {}", pattern, e),
+        };
+        self.rules.push(Rule::Regex(regex, placeholder.into()));
+        self
+    }
+
+    /// Replaces the values found at `selector` in serialized data with
+    /// `placeholder`.  Selectors use the same syntax as serde diffs
+    /// (`a.b[3].c`), with `*` or `[*]` matching any key or index.
+    ///
+    /// Path rules only apply to files written by the serde extension.
+    pub fn path<P: Into<String>>(mut self, selector: &str, placeholder: P) -> Redactions {
+        let segments = parse_selector(selector);
+        self.rules
+            .push(Rule::Path(selector.into(), segments, placeholder.into()));
+        self
+    }
+
+    pub fn uuids(self) -> Redactions {
+        self.regex(
+            r"[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}",
+            "[uuid]",
+        )
+    }
+
+    pub fn timestamps(self) -> Redactions {
+        self.regex(
+            r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(\.\d+)?(Z|[+-]\d{2}:?\d{2})?",
+            "[timestamp]",
+        )
+    }
+
+    pub fn pointers(self) -> Redactions {
+        self.regex(r"0x[0-9a-fA-F]{6,16}", "[pointer]")
+    }
+
+    pub fn temp_paths(self) -> Redactions {
+        let temp = env::temp_dir();
+        let temp = temp.to_string_lossy();
+        let temp = temp.trim_end_matches(&['/', '\\'][..]);
+        self.regex(
+            &format!(r#"{}[^\s"']*"#, ::regex::escape(temp)),
+            "[tmp]",
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub(crate) fn merged(&self, other: Redactions) -> Redactions {
+        let mut rules = self.rules.clone();
+        rules.extend(other.rules);
+        Redactions { rules }
+    }
+
+    pub(crate) fn redact_str(&self, text: &str) -> String {
+        let mut text = text.to_owned();
+        for rule in &self.rules {
+            if let Rule::Regex(regex, placeholder) = rule {
+                text = regex.replace_all(&text, placeholder.as_str()).into_owned();
+            }
+        }
+        text
+    }
+
+    /// Applies the regex rules to `bytes` if they are valid UTF-8, otherwise
+    /// returns them untouched.
+    pub(crate) fn redact_bytes(&self, bytes: Vec<u8>) -> Vec<u8> {
+        if self.rules.is_empty() {
+            return bytes;
+        }
+        match String::from_utf8(bytes) {
+            Ok(s) => self.redact_str(&s).into_bytes(),
+            Err(e) => e.into_bytes(),
+        }
+    }
+
+    #[cfg_attr(not(feature = "serialize"), allow(dead_code))]
+    pub(crate) fn redact_value(&self, value: &mut Value) {
+        for rule in &self.rules {
+            if let Rule::Path(_, segments, placeholder) = rule {
+                redact_path(value, segments, placeholder);
+            }
+        }
+    }
+
+    /// Renders the active rules for the HTML report.
+    pub(crate) fn to_html(&self) -> String {
+        if self.rules.is_empty() {
+            return String::new();
+        }
+        let mut html = String::from("<h3> Redactions </h3><ul>");
+        for rule in &self.rules {
+            let (kind, pattern, placeholder) = match rule {
+                Rule::Regex(regex, placeholder) => ("regex", regex.as_str(), placeholder),
+                Rule::Path(selector, _, placeholder) => ("path", selector.as_str(), placeholder),
+            };
+            html.push_str(&format!(
+                "<li>{} <code>{}</code> → <code>{}</code></li>",
+                kind,
+                escape_html(pattern),
+                escape_html(placeholder)
+            ));
+        }
+        html.push_str("</ul>");
+        html
+    }
+}
+
+fn parse_selector(selector: &str) -> Vec<Segment> {
+    let mut out = vec![];
+    for part in selector.split('.').filter(|p| !p.is_empty()) {
+        let (key, mut rest) = match part.find('[') {
+            Some(i) => (&part[..i], &part[i..]),
+            None => (part, ""),
+        };
+        match key {
+            "" => {}
+            "*" => out.push(Segment::Any),
+            key => out.push(Segment::Key(key.into())),
+        }
+        while rest.starts_with('[') {
+            let end = match rest.find(']') {
+                Some(end) => end,
+                None => panic!("invalid redaction selector {:?}", selector),
+            };
+            let inner = &rest[1..end];
+            out.push(match inner {
+                "*" => Segment::Any,
+                inner => match inner.parse() {
+                    Ok(i) => Segment::Index(i),
+                    Err(_) => Segment::Key(inner.trim_matches('"').into()),
+                },
+            });
+            rest = &rest[end + 1..];
+        }
+    }
+    out
+}
+
+fn redact_path(value: &mut Value, segments: &[Segment], placeholder: &str) {
+    let (first, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => {
+            *value = Value::String(placeholder.into());
+            return;
+        }
+    };
+    match (first, value) {
+        (Segment::Key(k), Value::Object(map)) => {
+            if let Some(v) = map.get_mut(k) {
+                redact_path(v, rest, placeholder);
+            }
+        }
+        (Segment::Index(i), Value::Array(values)) => {
+            if let Some(v) = values.get_mut(*i) {
+                redact_path(v, rest, placeholder);
+            }
+        }
+        (Segment::Any, Value::Object(map)) => {
+            for v in map.values_mut() {
+                redact_path(v, rest, placeholder);
+            }
+        }
+        (Segment::Any, Value::Array(values)) => {
+            for v in values {
+                redact_path(v, rest, placeholder);
+            }
+        }
+        _ => {}
+    }
+}
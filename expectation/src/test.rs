@@ -2,16 +2,10 @@ use super::extensions::*;
 use super::*;
 use expectation_shared::filesystem;
 use expectation_shared::Result as EResult;
-use std::io::{Read, Result as IoResult};
+use std::io::{BufRead, Read, Result as IoResult};
 
-fn byte_for_byte_equality<R1: Read, R2: Read>(mut r1: R1, mut r2: R2) -> IoResult<bool> {
-    let mut v1 = vec![];
-    let mut v2 = vec![];
-
-    r1.read_to_end(&mut v1)?;
-    r2.read_to_end(&mut v2)?;
-
-    Ok(v1 == v2)
+fn byte_for_byte_equality<R1: BufRead, R2: BufRead>(r1: R1, r2: R2) -> IoResult<bool> {
+    streams_equal(r1, r2)
 }
 
 fn byte_for_byte_diff<R1: Read, R2: Read>(
@@ -43,27 +37,26 @@ fn byte_for_byte_diff<R1: Read, R2: Read>(
 #[cfg(test)]
 pub fn difftest_prepare<F: FnOnce(Provider)>(name: &str, f: F) -> FakeFileSystem {
     let top_fs = filesystem::FakeFileSystem::new();
-    let provider = provider::Provider::new(
-        top_fs.duplicate(),
-        top_fs
-            .subsystem(Path::new("actual"))
-            .subsystem(Path::new(name)),
-    );
+    let provider = provider::Provider::new(top_fs.duplicate(), name);
     f(provider);
     top_fs
 }
 
 #[cfg(test)]
 pub fn difftest_validate<F: FnOnce(Provider)>(name: &str, f: F) -> Vec<EResult> {
+    difftest_validate_with_fs(name, f).0
+}
+
+#[cfg(test)]
+pub fn difftest_validate_with_fs<F: FnOnce(Provider)>(
+    name: &str,
+    f: F,
+) -> (Vec<EResult>, FakeFileSystem) {
     let top_fs = filesystem::FakeFileSystem::new();
-    let provider = provider::Provider::new(
-        top_fs.duplicate(),
-        top_fs
-            .subsystem(Path::new("actual"))
-            .subsystem(Path::new(name)),
-    );
+    let provider = provider::Provider::new(top_fs.duplicate(), name);
     f(provider.clone());
-    validate(name, top_fs.duplicate(), provider, |_| true)
+    let results = validate(name, top_fs.duplicate(), provider, |_| true, 4, UntrackedPolicy::Warn, SizeBudgets::default());
+    (results, top_fs)
 }
 
 #[test]
@@ -160,7 +153,7 @@ fn validate_one_file_expected_not_found() {
             "foo.txt",
             "/actual/hi/foo.txt",
             "/expected/hi/foo.txt",
-        )]
+        ).with_size(11)]
     );
 }
 
@@ -184,7 +177,7 @@ fn validate_one_file_expected_not_found_subdir() {
             "hello/foo.txt",
             "/actual/hi/hello/foo.txt",
             "/expected/hi/hello/foo.txt",
-        )]
+        ).with_size(11)]
     );
 }
 
@@ -214,7 +207,7 @@ fn validate_one_file_actual_not_found() {
                 "foo.txt",
                 "/actual/hi/foo.txt",
                 "/expected/hi/foo.txt",
-            ),
+            ).with_size(11),
             EResult::actual_not_found(
                 "hi",
                 "something_else.txt",
@@ -252,7 +245,7 @@ fn validate_one_file_diff_is_bad() {
             "/expected/hi/foo.txt",
             vec!["/diff/hi/foo.txt.diff".into()],
             None,
-        )]
+        ).with_size(11)]
     );
 }
 
@@ -278,7 +271,1602 @@ fn validate_one_file_diff_is_bad_with_text_extension() {
             "/actual/hi/foo.txt",
             "/expected/hi/foo.txt",
             vec!["/diff/hi/foo.txt.diff".into()],
-            Some("<h2> Actual </h2><code> hello world <code><h2> Expected </h2><code> goodbye found <code><h2> Diff </h2><code> &#43;hello world\n-goodbye found\n <code>".into())
-        )]
+            Some("<h3> Diff </h3><code><pre>&#64;&#64; -1 &#43;1 &#64;&#64;\n<span style=\"background: #fdd\">-goodbye found</span>\n<span style=\"background: #dfd\">&#43;hello world</span>\n</pre></code>".into())
+        ).with_size(11)]
+    );
+}
+
+#[cfg(feature = "serialize")]
+#[test]
+fn validate_serde_ignores_formatting_and_key_order() {
+    use std::collections::HashMap;
+    let results = difftest_validate("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.json"), &mut |writer| {
+                write!(writer, r#"{{"b": [1, 2.0], "a": "x"}}"#)
+            }).unwrap();
+
+        let mut map = HashMap::new();
+        map.insert("a", serde_json::json!("x"));
+        map.insert("b", serde_json::json!([1, 2]));
+        provider.json("foo.json", &map).unwrap();
+    });
+
+    assert_eq!(results, vec![EResult::ok("hi", "foo.json").with_size(25)]);
+}
+
+#[cfg(feature = "serialize")]
+#[test]
+fn validate_serde_reports_changed_paths() {
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.yaml"), &mut |writer| {
+                write!(writer, "a:\n  b: [1, 2, 3, {{c: 1}}]\n  gone: true\n")
+            }).unwrap();
+        provider
+            .yaml(
+                "foo.yaml",
+                &serde_json::json!({"a": {"b": [1, 2, 3, {"c": 2}], "new": null}}),
+            ).unwrap();
+    });
+
+    let mut without_html = results.clone();
+    let html = match &mut without_html[0].kind {
+        ResultKind::Difference(d) => d.html.take().unwrap(),
+        other => panic!("expected a difference, got {:?}", other),
+    };
+    assert_eq!(
+        without_html,
+        vec![EResult::difference(
+            "hi",
+            "foo.yaml",
+            "/actual/hi/foo.yaml",
+            "/expected/hi/foo.yaml",
+            vec!["/diff/hi/foo.yaml.diff".into()],
+            None,
+        ).with_size(47)]
+    );
+    assert!(
+        html.contains("<tr><td><code>a.b&#91;3&#93;.c</code></td><td><code>1</code></td><td><code>2</code></td></tr>"),
+        "{}",
+        html
+    );
+
+    fs.read(Path::new("diff/hi/foo.yaml.diff"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(v, "a.b[3].c: 1 → 2\na.gone: - true\na.new: + null\n");
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn validate_text_with_redactions() {
+    use std::io::Write;
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.txt"), &mut |writer| {
+                write!(writer, "id: [uuid] at [pointer]")
+            }).unwrap();
+
+        let provider = provider.with_redactions(Redactions::new().uuids().pointers());
+        let mut w = provider.text_writer("foo.txt");
+        write!(w, "id: 123e4567-e89b-12d3-a456-426614174000 at 0x7ffd5e8a9c10").unwrap();
+    });
+
+    assert_eq!(results, vec![EResult::ok("hi", "foo.txt").with_size(23)]);
+    fs.read(Path::new("actual/hi/foo.txt"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(v, "id: [uuid] at [pointer]");
+        Ok(())
+    }).unwrap();
+}
+
+#[cfg(feature = "serialize")]
+#[test]
+fn validate_serde_with_path_redactions() {
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        let provider = provider.with_redactions(
+            Redactions::new()
+                .path("runs[*].started", "[time]")
+                .regex(r"seed-\d+", "seed-N"),
+        );
+        provider
+            .json(
+                "foo.json",
+                &serde_json::json!({
+                    "runs": [{"started": 1234, "name": "seed-42"}, {"started": 99}]
+                }),
+            ).unwrap();
+    });
+
+    assert_eq!(
+        results,
+        vec![EResult::expected_not_found(
+            "hi",
+            "foo.json",
+            "/actual/hi/foo.json",
+            "/expected/hi/foo.json",
+        ).with_size(121)]
+    );
+    fs.read(Path::new("actual/hi/foo.json"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        let value: serde_json::Value = serde_json::from_str(&v).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "runs": [{"started": "[time]", "name": "seed-N"}, {"started": "[time]"}]
+            })
+        );
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn validate_text_with_lenient_options() {
+    use std::io::Write;
+    let results = difftest_validate("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.txt"), &mut |writer| {
+                write!(writer, "caf\u{e9}\r\n\r\nhello  \r\nworld")
+            }).unwrap();
+
+        let mut w = provider.text_writer_with("foo.txt", TextOptions::lenient());
+        write!(w, "cafe\u{301}\nhello\nworld\n").unwrap();
+    });
+
+    assert_eq!(results, vec![EResult::ok("hi", "foo.txt").with_size(23)]);
+}
+
+#[test]
+fn validate_text_options_are_echoed_in_diff() {
+    use std::io::Write;
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.txt"), &mut |writer| {
+                write!(writer, "Hello\nWorld\n")
+            }).unwrap();
+
+        let options = TextOptions {
+            ignore_case: true,
+            ..TextOptions::default()
+        };
+        let mut w = provider.text_writer_with("foo.txt", options);
+        write!(w, "hello\nmoon\n").unwrap();
+    });
+
+    assert_eq!(results.len(), 1);
+    assert!(!results[0].is_ok());
+    fs.read(Path::new("diff/hi/foo.txt.diff"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(
+            v,
+            "# text options: ignore case\n\
+             # actual: actual/hi/foo.txt\n\
+             --- a/expected/hi/foo.txt\n\
+             +++ b/expected/hi/foo.txt\n\
+             @@ -1,2 +1,2 @@\n \
+             Hello\n\
+             -World\n\
+             +moon\n"
+        );
+        Ok(())
+    }).unwrap();
+}
+
+/// A provider that writes straight into the expected files without
+/// registering them.
+#[cfg(any(feature = "image", feature = "table"))]
+fn expected_provider(provider: &Provider) -> Provider {
+    let mut expected = provider.diagnostic();
+    expected.fs = expected.expected_fs.duplicate();
+    expected
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn validate_svg_ignores_attribute_order_whitespace_and_number_format() {
+    let results = difftest_validate("hi", |provider| {
+        let expected_fs = provider.root_fs.subsystem(Path::new("expected/hi"));
+        expected_fs
+            .write(Path::new("foo.svg"), &mut |w| {
+                write!(w, r#"<svg width="10" height="20"><rect x="1" y="2.5"/></svg>"#)
+            }).unwrap();
+        provider
+            .svg(
+                "foo.svg",
+                "<svg height=\"20.0\"  width=\"1e1\">\n  <rect y=\"2.50\" x=\"1.0\" />\n</svg>\n",
+            ).unwrap();
+    });
+    assert_eq!(results, vec![EResult::ok("hi", "foo.svg").with_size(55)]);
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn validate_xml_and_html_report_element_paths() {
+    use std::io::Write;
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        let expected_fs = provider.root_fs.subsystem(Path::new("expected/hi"));
+        expected_fs
+            .write(Path::new("foo.xml"), &mut |w| {
+                write!(w, r#"<a><b x="1.004"/><b x="2"/><c>hi</c></a>"#)
+            }).unwrap();
+        expected_fs
+            .write(Path::new("foo.html"), &mut |w| {
+                write!(w, "<P>one<BR><img src=x.png></P>")
+            }).unwrap();
+        let options = XmlOptions {
+            round_numbers: Some(2),
+            ..XmlOptions::default()
+        };
+        let mut w = provider.xml_writer_with("foo.xml", options);
+        write!(w, r#"<a><b x="1"/><b x="3"/><d/><c>bye</c></a>"#).unwrap();
+        drop(w);
+        provider
+            .html("foo.html", "<p>one<br/><img src=\"x.png\"/></p>")
+            .unwrap();
+    });
+
+    assert!(!results[0].is_ok());
+    assert_eq!(results[1], EResult::ok("hi", "foo.html").with_size(29));
+    fs.read(Path::new("diff/hi/foo.xml.diff"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(
+            v,
+            "/a/b[2]@x: \"2\" → \"3\"\n\
+             /a/d: + <d>\n\
+             /a/c/text(): \"hi\" → \"bye\"\n"
+        );
+        Ok(())
+    }).unwrap();
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn validate_xml_compares_numbers_by_value_only_in_geometry_attributes() {
+    let results = difftest_validate("hi", |provider| {
+        let expected_fs = provider.root_fs.subsystem(Path::new("expected/hi"));
+        expected_fs
+            .write(Path::new("ids.xml"), &mut |w| write!(w, r#"<a id="a007"/>"#))
+            .unwrap();
+        expected_fs
+            .write(Path::new("version.xml"), &mut |w| write!(w, r#"<a version="v1.0"/>"#))
+            .unwrap();
+        expected_fs
+            .write(Path::new("long.svg"), &mut |w| {
+                write!(w, r#"<svg width="12345678901234567890" x="+007"/>"#)
+            }).unwrap();
+        provider.xml("ids.xml", r#"<a id="a7"/>"#).unwrap();
+        provider.xml("version.xml", r#"<a version="v1"/>"#).unwrap();
+        provider
+            .svg("long.svg", r#"<svg width="12345678901234567891" x="7"/>"#)
+            .unwrap();
+    });
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|r| !r.is_ok()), "{:?}", results);
+}
+
+#[cfg(feature = "image")]
+fn write_image(
+    provider: &Provider,
+    path: &str,
+    image: ::image::DynamicImage,
+    format: ::image::ImageOutputFormat,
+) {
+    provider
+        .root_fs
+        .write(Path::new(path), &mut |mut writer| {
+            image
+                .write_to(&mut writer, format.clone())
+                .map_err(|e| ::std::io::Error::other(e.to_string()))
+        }).unwrap();
+}
+
+#[cfg(feature = "image")]
+fn write_png(provider: &Provider, path: &str, image: ::image::RgbaImage) {
+    write_image(
+        provider,
+        path,
+        ::image::DynamicImage::ImageRgba8(image),
+        ::image::ImageOutputFormat::Png,
     );
 }
+
+#[cfg(feature = "image")]
+#[test]
+fn validate_image_with_channel_tolerance() {
+    use image::{ImageBuffer, Rgba};
+    let expected = ImageBuffer::from_pixel(4, 4, Rgba([100, 100, 100, 255]));
+    let mut actual = expected.clone();
+    actual.put_pixel(1, 1, Rgba([102, 99, 100, 255]));
+
+    let results = difftest_validate("hi", |provider| {
+        write_png(&provider, "expected/hi/exact.png", expected.clone());
+        write_png(&provider, "expected/hi/tolerant.png", expected.clone());
+        provider.rgba_image("exact.png", actual.clone()).unwrap();
+        let tolerant = ImageOptions {
+            metric: ImageMetric::Channel { tolerance: 2 },
+            ..ImageOptions::default()
+        };
+        let mut w = provider.png_writer_with("tolerant.png", tolerant);
+        ::image::DynamicImage::ImageRgba8(actual.clone())
+            .write_to(&mut w, ::image::ImageOutputFormat::Png)
+            .unwrap();
+    });
+
+    assert_eq!(results.len(), 2);
+    assert!(!results[0].is_ok());
+    assert_eq!(results[1], EResult::ok("hi", "tolerant.png").with_size(75));
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn validate_image_with_differing_pixel_budget() {
+    use image::{ImageBuffer, Rgba};
+    let expected = ImageBuffer::from_pixel(10, 10, Rgba([0, 0, 0, 255]));
+    let mut actual = expected.clone();
+    actual.put_pixel(0, 0, Rgba([255, 255, 255, 255]));
+    actual.put_pixel(9, 9, Rgba([255, 255, 255, 255]));
+
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        write_png(&provider, "expected/hi/foo.png", expected.clone());
+        let options = ImageOptions {
+            max_differing_pixels: Some(1),
+            max_differing_percent: Some(5.0),
+            ..ImageOptions::default()
+        };
+        let mut w = provider.png_writer_with("foo.png", options);
+        ::image::DynamicImage::ImageRgba8(actual.clone())
+            .write_to(&mut w, ::image::ImageOutputFormat::Png)
+            .unwrap();
+    });
+
+    assert_eq!(results.len(), 1);
+    assert!(!results[0].is_ok());
+    fs.read(Path::new("diff/hi/foo.png/comparison.txt"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(
+            v,
+            "metric: channel (tolerance: 0)\n\
+             differing pixels: 2 of 100 (2.0000%)\n\
+             allowed differing pixels: 1\n\
+             allowed differing percent: 5%\n\
+             result: fail\n"
+        );
+        Ok(())
+    }).unwrap();
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn validate_image_with_ssim() {
+    use image::{ImageBuffer, Rgba};
+    let expected = ImageBuffer::from_fn(16, 16, |x, y| {
+        let v = ((x + y) * 8) as u8;
+        Rgba([v, v, v, 255])
+    });
+    let mut actual = expected.clone();
+    actual.put_pixel(3, 3, Rgba([60, 60, 60, 255]));
+
+    let results = difftest_validate("hi", |provider| {
+        write_png(&provider, "expected/hi/foo.png", expected.clone());
+        let options = ImageOptions {
+            metric: ImageMetric::Ssim { min_score: 0.9 },
+            ..ImageOptions::default()
+        };
+        let mut w = provider.png_writer_with("foo.png", options);
+        ::image::DynamicImage::ImageRgba8(actual.clone())
+            .write_to(&mut w, ::image::ImageOutputFormat::Png)
+            .unwrap();
+    });
+
+    assert_eq!(results, vec![EResult::ok("hi", "foo.png").with_size(148)]);
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn validate_image_16_bit_precision() {
+    use image::{DynamicImage, ImageBuffer, ImageOutputFormat, Luma};
+    let expected = ImageBuffer::from_pixel(2, 2, Luma([1000u16]));
+    let mut actual = expected.clone();
+    actual.put_pixel(0, 0, Luma([1001u16]));
+
+    let results = difftest_validate("hi", |provider| {
+        write_image(
+            &provider,
+            "expected/hi/same.png",
+            DynamicImage::ImageLuma16(expected.clone()),
+            ImageOutputFormat::Png,
+        );
+        write_image(
+            &provider,
+            "expected/hi/changed.png",
+            DynamicImage::ImageLuma16(expected.clone()),
+            ImageOutputFormat::Png,
+        );
+        provider
+            .dynamic_image("same.png", &DynamicImage::ImageLuma16(expected.clone()))
+            .unwrap();
+        provider
+            .dynamic_image("changed.png", &DynamicImage::ImageLuma16(actual.clone()))
+            .unwrap();
+    });
+
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0], EResult::ok("hi", "same.png").with_size(71));
+    assert!(!results[1].is_ok());
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn validate_image_across_formats_and_color_types() {
+    use image::{DynamicImage, ImageBuffer, ImageOutputFormat, Rgb};
+    let pixels = ImageBuffer::from_fn(3, 3, |x, y| Rgb([x as u8 * 40, y as u8 * 40, 7]));
+
+    let results = difftest_validate("hi", |provider| {
+        write_image(
+            &provider,
+            "expected/hi/foo.png",
+            DynamicImage::ImageRgb8(pixels.clone()),
+            ImageOutputFormat::Bmp,
+        );
+        let rgba = DynamicImage::ImageRgb8(pixels.clone()).to_rgba8();
+        provider.rgba_image("foo.png", rgba).unwrap();
+    });
+
+    assert_eq!(results, vec![EResult::ok("hi", "foo.png").with_size(90)]);
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn validate_image_decode_error_is_io_error() {
+    use image::{ImageBuffer, Rgba};
+    let results = difftest_validate("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.png"), &mut |writer| {
+                write!(writer, "not an image")
+            }).unwrap();
+        provider
+            .rgba_image("foo.png", ImageBuffer::from_pixel(1, 1, Rgba([0, 0, 0, 255])))
+            .unwrap();
+    });
+
+    assert_eq!(results.len(), 1);
+    match &results[0].kind {
+        ResultKind::IoError(_) => {}
+        other => panic!("expected an io error, got {:?}", other),
+    }
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn image_diff_writes_visualizations_and_stats() {
+    use image::{GenericImageView, ImageBuffer, Rgba};
+    let expected = ImageBuffer::from_pixel(6, 4, Rgba([10, 20, 30, 255]));
+    let mut actual = expected.clone();
+    actual.put_pixel(1, 1, Rgba([50, 20, 30, 255]));
+    actual.put_pixel(2, 2, Rgba([10, 20, 30, 200]));
+    actual.put_pixel(5, 3, Rgba([10, 25, 30, 255]));
+
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        write_png(&provider, "expected/hi/foo.png", expected.clone());
+        provider.rgba_image("foo.png", actual.clone()).unwrap();
+    });
+
+    match &results[0].kind {
+        ResultKind::Difference(d) => assert_eq!(
+            d.diffs,
+            vec![
+                PathBuf::from("/diff/hi/foo.png/comparison.txt"),
+                PathBuf::from("/diff/hi/foo.png/stats.txt"),
+                PathBuf::from("/diff/hi/foo.png/color-diff.png"),
+                PathBuf::from("/diff/hi/foo.png/transparency-diff.png"),
+                PathBuf::from("/diff/hi/foo.png/heatmap.png"),
+                PathBuf::from("/diff/hi/foo.png/overlay.png"),
+            ]
+        ),
+        other => panic!("expected a difference, got {:?}", other),
+    }
+
+    fs.read(Path::new("diff/hi/foo.png/stats.txt"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(
+            v,
+            "changed pixels: 3 of 24 (12.5000%)\n\
+             max delta: 55 (red: 40, green: 5, blue: 0, alpha: 55)\n\
+             changed regions: 2\n  \
+             x: 1..=2 y: 1..=2 (2 pixels)\n  \
+             x: 5..=5 y: 3..=3 (1 pixels)\n"
+        );
+        Ok(())
+    }).unwrap();
+
+    fs.read(Path::new("diff/hi/foo.png/color-diff.png"), &mut |r| {
+        let image = decode(r)?;
+        assert_eq!(image.dimensions(), (18, 4));
+        assert_eq!(image.get_pixel(0, 0), Rgba([10, 20, 30, 255]));
+        assert_eq!(image.get_pixel(7, 1), Rgba([10, 20, 30, 255]));
+        assert_eq!(image.get_pixel(13, 1), Rgba([255, 0, 0, 255]));
+        Ok(())
+    }).unwrap();
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn validate_image_with_masks() {
+    use image::{ImageBuffer, Luma, Rgba};
+    let expected = ImageBuffer::from_pixel(8, 8, Rgba([0, 0, 0, 255]));
+    let mut actual = expected.clone();
+    actual.put_pixel(1, 1, Rgba([255, 255, 255, 255]));
+    actual.put_pixel(6, 6, Rgba([255, 255, 255, 255]));
+    let mut mask = ImageBuffer::from_pixel(8, 8, Luma([0u8]));
+    mask.put_pixel(6, 6, Luma([255]));
+
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        write_png(&provider, "expected/hi/rect.png", expected.clone());
+        write_png(&provider, "expected/hi/both.png", expected.clone());
+        write_image(
+            &provider,
+            "expected/hi/both.png.mask.png",
+            ::image::DynamicImage::ImageLuma8(mask.clone()),
+            ::image::ImageOutputFormat::Png,
+        );
+        let rect = Mask::Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 2,
+        };
+        let rect_only = ImageOptions {
+            masks: vec![rect],
+            ..ImageOptions::default()
+        };
+        let mut w = provider.png_writer_with("rect.png", rect_only);
+        ::image::DynamicImage::ImageRgba8(actual.clone())
+            .write_to(&mut w, ::image::ImageOutputFormat::Png)
+            .unwrap();
+        let both = ImageOptions {
+            masks: vec![rect, Mask::Sidecar],
+            ..ImageOptions::default()
+        };
+        let mut w = provider.png_writer_with("both.png", both);
+        ::image::DynamicImage::ImageRgba8(actual.clone())
+            .write_to(&mut w, ::image::ImageOutputFormat::Png)
+            .unwrap();
+    });
+
+    assert_eq!(results.len(), 2);
+    assert!(!results[0].is_ok());
+    assert_eq!(results[1], EResult::ok("hi", "both.png").with_size(76));
+
+    fs.read(Path::new("diff/hi/rect.png/stats.txt"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert!(v.starts_with("changed pixels: 1 of 60 (1.6667%)\nignored pixels: 4\n"));
+        Ok(())
+    }).unwrap();
+
+    // The heatmap leaves the masked change out.
+    fs.read(Path::new("diff/hi/rect.png/heatmap.png"), &mut |r| {
+        let mut v = vec![];
+        r.read_to_end(&mut v)?;
+        let heatmap = ::image::load_from_memory(&v).unwrap().to_rgb8();
+        assert_eq!(heatmap.get_pixel(1, 1), &::image::Rgb([128, 128, 128]));
+        assert_eq!(heatmap.get_pixel(3, 3), &::image::Rgb([0, 0, 0]));
+        assert_ne!(heatmap.get_pixel(6, 6), &::image::Rgb([0, 0, 0]));
+        Ok(())
+    }).unwrap();
+}
+
+#[cfg(feature = "apng")]
+#[test]
+fn validate_frames_reports_differing_frames() {
+    use image::{ImageBuffer, Rgba};
+    let frames: Vec<_> = (0..3)
+        .map(|i| ImageBuffer::from_pixel(4, 4, Rgba([i * 50, 0, 0, 255])))
+        .collect();
+    let mut changed = frames.clone();
+    changed[1].put_pixel(2, 2, Rgba([255, 255, 255, 255]));
+    changed.push(frames[0].clone());
+
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        let expected_provider = expected_provider(&provider);
+        expected_provider.frames("same.png", frames.clone()).unwrap();
+        expected_provider.frames("walk.png", frames.clone()).unwrap();
+        provider.frames("same.png", frames.clone()).unwrap();
+        provider.frames("walk.png", changed.clone()).unwrap();
+    });
+
+    assert_eq!(results.len(), 2, "{:?}", results);
+    assert_eq!(results[0], EResult::ok("hi", "same.png").with_size(455));
+    match &results[1].kind {
+        ResultKind::Difference(d) => assert_eq!(
+            d.diffs,
+            vec![
+                PathBuf::from("/diff/hi/walk.png/frames.txt"),
+                PathBuf::from("/diff/hi/walk.png/frame-0000.png"),
+                PathBuf::from("/diff/hi/walk.png/frame-0001.png"),
+                PathBuf::from("/diff/hi/walk.png/frame-0002.png"),
+            ]
+        ),
+        other => panic!("expected a difference, got {:?}", other),
+    }
+
+    fs.read(Path::new("diff/hi/walk.png/frames.txt"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(
+            v,
+            "frames: actual 4, expected 3\n\
+             first differing frame: 1\n\
+             differing frames: 1, 3\n\
+             frame 1: 1 of 16 pixels differ (6.2500%)\n\
+             frame 3: only in actual\n"
+        );
+        Ok(())
+    }).unwrap();
+}
+
+#[cfg(feature = "svg-raster")]
+#[test]
+fn validate_rasterized_svg_compares_pixels() {
+    use std::io::Write;
+    let square = r##"<svg xmlns="http://www.w3.org/2000/svg" width="8" height="8"><rect x="2" y="2" width="4" height="4" fill="red"/></svg>"##;
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        let expected_fs = provider.root_fs.subsystem(Path::new("expected/hi"));
+        for name in &["same.svg", "moved.svg"] {
+            expected_fs
+                .write(Path::new(name), &mut |w| write!(w, "{}", square))
+                .unwrap();
+        }
+        let mut w = provider.svg_writer_rasterized("same.svg", ImageOptions::default());
+        write!(
+            w,
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="8" height="8"><path d="M2 2H6V6H2Z" fill="#ff0000"/></svg>"##
+        ).unwrap();
+        drop(w);
+        let mut w = provider.svg_writer_rasterized("moved.svg", ImageOptions::default());
+        write!(w, "{}", square.replace(r#"x="2""#, r#"x="3""#)).unwrap();
+    });
+
+    assert_eq!(results[0], EResult::ok("hi", "same.svg").with_size(118));
+    match &results[1].kind {
+        ResultKind::Difference(d) => {
+            assert!(d.diffs.contains(&PathBuf::from("/diff/hi/moved.svg.diff")));
+            assert!(d.diffs.contains(&PathBuf::from("/diff/hi/moved.svg/color-diff.png")));
+        }
+        other => panic!("expected a difference, got {:?}", other),
+    }
+
+    fs.read(Path::new("diff/hi/moved.svg/stats.txt"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert!(v.starts_with("changed pixels: 8 of 64"), "{}", v);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn validate_binary_writes_hex_dump() {
+    let expected: Vec<u8> = (0..40).collect();
+    let mut actual = expected.clone();
+    actual[3] = 0xff;
+    actual[4] = 0xfe;
+    actual.truncate(36);
+
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        let expected_fs = provider.root_fs.subsystem(Path::new("expected/hi"));
+        expected_fs
+            .write(Path::new("foo.bin"), &mut |w| w.write_all(&expected))
+            .unwrap();
+        provider.bytes("foo.bin", &actual).unwrap();
+    });
+
+    assert!(!results[0].is_ok());
+    fs.read(Path::new("diff/hi/foo.bin.diff"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(
+            v,
+            [
+                "length: actual 36, expected 40",
+                "first difference at offset 0x00000003",
+                "changed regions: 2",
+                "  0x00000003..0x00000005: 2 bytes changed",
+                "  0x00000024..0x00000028: 4 bytes only in expected",
+                "",
+                "+00000000  00 01 02 ff fe 05 06 07  08 09 0a 0b 0c 0d 0e 0f  |................|",
+                "-00000000  00 01 02 03 04 05 06 07  08 09 0a 0b 0c 0d 0e 0f  |................|",
+                "                    ^^ ^^",
+                " 00000010  10 11 12 13 14 15 16 17  18 19 1a 1b 1c 1d 1e 1f  |................|",
+                "+00000020  20 21 22 23                                       | !\"#|",
+                "-00000020  20 21 22 23 24 25 26 27                           | !\"#$%&'|",
+                "                       ^^ ^^ ^^ ^^",
+                "",
+            ].join("\n")
+        );
+        Ok(())
+    }).unwrap();
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn validate_table_compares_cells_by_key() {
+    let expected = vec![
+        vec!["id", "name", "score"],
+        vec!["1", "ann", "1.50"],
+        vec!["2", "bob", "2.00"],
+        vec!["3", "cid", "3.00"],
+    ];
+    let actual = vec![
+        vec!["id", "name", "score"],
+        vec!["2", "bob", "2.25"],
+        vec!["1", "ann", "1.5000001"],
+        vec!["4", "dee", "4.00"],
+    ];
+
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        let expected_provider = expected_provider(&provider);
+        let options = TableOptions {
+            key_columns: vec!["id".into()],
+            numeric_tolerance: Some(0.001),
+            ..TableOptions::default()
+        };
+        expected_provider
+            .table("scores.csv", TableFormat::Csv, expected.clone())
+            .unwrap();
+        expected_provider
+            .table("unordered.tsv", TableFormat::Tsv, expected.clone())
+            .unwrap();
+        provider
+            .table_with("scores.csv", TableFormat::Csv, options, actual.clone())
+            .unwrap();
+        let unordered = TableOptions {
+            ignore_row_order: true,
+            ..TableOptions::default()
+        };
+        let mut shuffled = expected.clone();
+        shuffled.swap(1, 3);
+        provider
+            .table_with("unordered.tsv", TableFormat::Tsv, unordered, shuffled)
+            .unwrap();
+    });
+
+    assert_eq!(results[1], EResult::ok("hi", "unordered.tsv").with_size(47));
+    assert!(!results[0].is_ok());
+    fs.read(Path::new("diff/hi/scores.csv.diff"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(
+            v,
+            "# table options: key columns: id; numeric tolerance: 0.001\n\
+             [id=2] score: \"2.00\" → \"2.25\"\n\
+             [id=4]: + [\"4\", \"dee\", \"4.00\"]\n\
+             [id=3]: - [\"3\", \"cid\", \"3.00\"]\n"
+        );
+        Ok(())
+    }).unwrap();
+    match &results[0].kind {
+        ResultKind::Difference(d) => {
+            let html = d.html.as_ref().unwrap();
+            assert!(
+                html.contains(r#"<td style="background: #fcc" title="2.00">2.25</td>"#),
+                "{}",
+                html
+            );
+        }
+        other => panic!("expected a difference, got {:?}", other),
+    }
+}
+
+#[test]
+fn validate_text_marks_changed_words_and_characters() {
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/gen.rs"), &mut |writer| {
+                write!(writer, "let total = compute(alpha, beta_1);\nfn main() {{}}\n")
+            }).unwrap();
+        provider
+            .text("gen.rs", "let total = compute(alpha, beta_2);\nfn main() {}\n")
+            .unwrap();
+    });
+
+    match &results[0].kind {
+        ResultKind::Difference(d) => assert!(
+            d.html.as_ref().unwrap().contains("beta_<ins>2</ins>"),
+            "{:?}",
+            d.html
+        ),
+        other => panic!("expected a difference, got {:?}", other),
+    }
+    fs.read(Path::new("diff/hi/gen.rs.intraline.diff"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(
+            v,
+            "# actual: actual/hi/gen.rs\n\
+             @@ -1,2 +1,2 @@\n\
+             -let total = compute(alpha, beta_1);\n\
+             ?                                ^\n\
+             +let total = compute(alpha, beta_2);\n\
+             ?                                ^\n \
+             fn main() {}\n"
+        );
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn validate_text_writes_unified_diff_hunks() {
+    use std::io::Write;
+    let expected: String = (1..=20).map(|i| format!("line {}\n", i)).collect();
+    let actual = expected.replace("line 3\n", "line three\n").replace("line 20\n", "line 20");
+
+    let (_, fs) = difftest_validate_with_fs("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.txt"), &mut |writer| {
+                write!(writer, "{}", expected)
+            }).unwrap();
+        let options = TextOptions {
+            context_lines: Some(1),
+            ..TextOptions::default()
+        };
+        let mut w = provider.text_writer_with("foo.txt", options);
+        write!(w, "{}", actual).unwrap();
+    });
+
+    fs.read(Path::new("diff/hi/foo.txt.diff"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(
+            v,
+            "# actual: actual/hi/foo.txt\n\
+             --- a/expected/hi/foo.txt\n\
+             +++ b/expected/hi/foo.txt\n\
+             @@ -2,3 +2,3 @@\n \
+             line 2\n\
+             -line 3\n\
+             +line three\n \
+             line 4\n\
+             @@ -19,2 +19,2 @@\n \
+             line 19\n\
+             -line 20\n\
+             +line 20\n\
+             \\ No newline at end of file\n"
+        );
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn validate_text_reports_moved_blocks_with_every_algorithm() {
+    use std::io::Write;
+    // `f1` and `f2` moved below the `g` lines, while `f3` was replaced.
+    let expected = "header\nf1\nf2\nf3\ng1\ng2\ng3\ng4\nend\n";
+    let actual = "header\ng1\ng2\ng3\ng4\nf1\nf2\nnew\nend\n";
+    let algorithms = [
+        DiffAlgorithm::Lcs,
+        DiffAlgorithm::Myers,
+        DiffAlgorithm::Patience,
+        DiffAlgorithm::Histogram,
+    ];
+
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        for (i, &algorithm) in algorithms.iter().enumerate() {
+            let name = format!("{}.txt", i);
+            provider
+                .root_fs
+                .write(&Path::new("expected/hi").join(&name), &mut |writer| {
+                    write!(writer, "{}", expected)
+                }).unwrap();
+            let options = TextOptions {
+                algorithm,
+                detect_moves: true,
+                ..TextOptions::default()
+            };
+            let mut w = provider.text_writer_with(&name, options);
+            write!(w, "{}", actual).unwrap();
+        }
+    });
+
+    for (i, algorithm) in algorithms.iter().enumerate() {
+        fs.read(&Path::new("diff/hi").join(format!("{}.txt.diff", i)), &mut |r| {
+            let mut v = String::new();
+            r.read_to_string(&mut v)?;
+            assert!(
+                v.contains("# moved: expected lines 2-3 → actual lines 6-7\n"),
+                "{:?}: {}",
+                algorithm,
+                v
+            );
+            assert!(v.contains("<f1\n<f2\n-f3\n") && v.contains(">f1\n>f2\n+new\n"), "{}", v);
+            Ok(())
+        }).unwrap();
+        match &results[i].kind {
+            ResultKind::Difference(d) => {
+                let html = d.html.as_ref().unwrap();
+                assert!(html.contains(r#"title="moved to line 6">&lt;f1"#), "{}", html);
+                assert!(html.contains(r#"title="moved from line 2">&gt;f1"#), "{}", html);
+            }
+            other => panic!("expected a difference, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn writer_streams_to_filesystem_before_drop() {
+    use std::io::Write;
+    let fs = difftest_prepare("hi", |provider| {
+        let mut w = provider.text_writer("foo.txt");
+        write!(w, "hello").unwrap();
+        w.flush().unwrap();
+        assert!(provider.fs.exists(Path::new("foo.txt")));
+        write!(w, " world").unwrap();
+    });
+
+    fs.read(Path::new("actual/hi/foo.txt"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(v, "hello world");
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn validate_large_text_only_diffs_the_changed_region() {
+    use std::io::Write;
+    let expected: String = (1..=100_000).map(|i| format!("line {}\n", i)).collect();
+    let actual = expected
+        .replace("line 50000\n", "line fifty thousand\n")
+        .replace("line 50002\n", "");
+
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.txt"), &mut |writer| {
+                write!(writer, "{}", expected)
+            }).unwrap();
+        let options = TextOptions {
+            context_lines: Some(1),
+            ..TextOptions::default()
+        };
+        let mut w = provider.text_writer_with("foo.txt", options);
+        write!(w, "{}", actual).unwrap();
+    });
+
+    assert!(!results[0].is_ok());
+    fs.read(Path::new("diff/hi/foo.txt.diff"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(
+            v,
+            "# actual: actual/hi/foo.txt\n\
+             --- a/expected/hi/foo.txt\n\
+             +++ b/expected/hi/foo.txt\n\
+             @@ -49999,5 +49999,4 @@\n \
+             line 49999\n\
+             -line 50000\n\
+             +line fifty thousand\n \
+             line 50001\n\
+             -line 50002\n \
+             line 50003\n"
+        );
+        Ok(())
+    }).unwrap();
+}
+
+/// Applies a unified diff to `original` the way `patch` does, panicking when
+/// a context or removed line doesn't match the file.
+fn apply_patch(original: &str, patch: &str) -> String {
+    let lines: Vec<&str> = original.split_inclusive('\n').collect();
+    let mut patch_lines: Vec<String> = vec![];
+    for line in patch.split_inclusive('\n') {
+        if line.starts_with('\\') {
+            patch_lines.last_mut().unwrap().pop();
+        } else {
+            patch_lines.push(line.to_owned());
+        }
+    }
+
+    let mut out = String::new();
+    let mut next = 0;
+    let first_hunk = patch_lines.iter().position(|l| l.starts_with("@@ -")).unwrap();
+    for line in &patch_lines[first_hunk..] {
+        if let Some(header) = line.strip_prefix("@@ -") {
+            let range = header.split(' ').next().unwrap();
+            let mut numbers = range.split(',').map(|n| n.parse::<usize>().unwrap());
+            let start = numbers.next().unwrap();
+            let start = if numbers.next() == Some(0) { start } else { start - 1 };
+            for line in &lines[next..start] {
+                out.push_str(line);
+            }
+            next = start;
+            continue;
+        }
+        let (prefix, text) = line.split_at(1);
+        match prefix {
+            " " | "-" => {
+                assert_eq!(lines[next], text, "line {} doesn't match", next + 1);
+                if prefix == " " {
+                    out.push_str(text);
+                }
+                next += 1;
+            }
+            "+" => out.push_str(text),
+            _ => {}
+        }
+    }
+    for line in &lines[next..] {
+        out.push_str(line);
+    }
+    out
+}
+
+#[test]
+fn validate_text_diffs_apply_to_the_expected_file() {
+    use std::io::Write;
+    let lines: String = (1..=20).map(|i| format!("line {}\n", i)).collect();
+    let cases = [
+        (
+            TextOptions {
+                context_lines: Some(1),
+                ..TextOptions::default()
+            },
+            lines.clone(),
+            lines.replace("line 3\n", "line three\n").replace("line 20\n", "line 20"),
+        ),
+        (
+            TextOptions {
+                ignore_case: true,
+                ..TextOptions::lenient()
+            },
+            "Title\r\n\r\nfirst  \r\nsecond\r\nthird\r\n".to_owned(),
+            "title\nfirst\n2nd\n\nthird\nfourth".to_owned(),
+        ),
+    ];
+
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        for (i, (options, expected, actual)) in cases.iter().enumerate() {
+            let name = format!("{}.txt", i);
+            provider
+                .root_fs
+                .write(&Path::new("expected/hi").join(&name), &mut |writer| {
+                    write!(writer, "{}", expected)
+                }).unwrap();
+            let mut w = provider.text_writer_with(&name, options.clone());
+            write!(w, "{}", actual).unwrap();
+        }
+    });
+
+    assert!(results.iter().all(|r| !r.is_ok()), "{:?}", results);
+    for (i, (options, expected, actual)) in cases.iter().enumerate() {
+        fs.read(&Path::new("diff/hi").join(format!("{}.txt.diff", i)), &mut |r| {
+            let mut diff = String::new();
+            r.read_to_string(&mut diff)?;
+            let header = format!("--- a/expected/hi/{0}.txt\n+++ b/expected/hi/{0}.txt\n", i);
+            assert!(diff.contains(&header), "{}", diff);
+            // Only the differences that the options don't ignore are applied.
+            let patched = apply_patch(expected, &diff);
+            assert!(
+                text_eq(patched.as_bytes(), actual.as_bytes(), options)?,
+                "{:?}",
+                patched
+            );
+            Ok(())
+        }).unwrap();
+    }
+}
+
+#[test]
+fn validate_trusts_recorded_hashes_of_unchanged_files() {
+    use expectation_shared::hashes::{Entry, HashManifest};
+    use std::io::Write;
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.txt"), &mut |writer| {
+                write!(writer, "hello")
+            }).unwrap();
+        provider.text("foo.txt", "hello").unwrap();
+    });
+    assert_eq!(results, vec![EResult::ok("hi", "foo.txt").with_size(5)]);
+    // Only promoting writes the manifest.
+    assert!(!fs.exists(Path::new("expected/hi/.hashes")));
+
+    let expected_fs = fs.subsystem(Path::new("expected/hi"));
+    let mut manifest = HashManifest::default();
+    manifest.insert("foo.txt", Entry::for_file(&*expected_fs, Path::new("foo.txt")).unwrap());
+    manifest.save(&*expected_fs, Path::new("")).unwrap();
+
+    // The same output is now confirmed by its hash alone, without comparing
+    // it against the expected file.
+    let provider = provider::Provider::new(fs.duplicate(), "hi");
+    {
+        let mut w = provider.custom_test(
+            "foo.txt",
+            |_, _| unimplemented!(),
+            |_, _, _, _| unimplemented!(),
+        );
+        write!(w, "hello").unwrap();
+    }
+    let results = validate("hi", fs.duplicate(), provider, |_| true, 4, UntrackedPolicy::Warn, SizeBudgets::default());
+    assert_eq!(results, vec![EResult::ok("hi", "foo.txt").with_size(5)]);
+
+    // The committed manifest only has the hash and size, and the size and
+    // modification time go to the untracked stat cache.
+    fs.read(Path::new("expected/hi/.hashes"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(
+            v,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824  5  foo.txt\n"
+        );
+        Ok(())
+    }).unwrap();
+    assert!(fs.exists(Path::new("actual/.stat-cache/hi")));
+}
+
+#[test]
+fn validate_compares_expected_files_edited_since_they_were_hashed() {
+    use expectation_shared::hashes::{Entry, HashManifest};
+    let fs = filesystem::FakeFileSystem::new();
+    let expected_fs = fs.subsystem(Path::new("expected/hi"));
+    expected_fs
+        .write(Path::new("foo.txt"), &mut |writer| write!(writer, "hello"))
+        .unwrap();
+    let mut manifest = HashManifest::default();
+    manifest.insert("foo.txt", Entry::for_file(&*expected_fs, Path::new("foo.txt")).unwrap());
+    manifest.save(&*expected_fs, Path::new("")).unwrap();
+
+    let run = || {
+        let provider = provider::Provider::new(fs.duplicate(), "hi");
+        provider.text("foo.txt", "hello").unwrap();
+        validate("hi", fs.duplicate(), provider, |_| true, 4, UntrackedPolicy::Warn, SizeBudgets::default())
+    };
+    // The first run fills the stat cache.
+    assert_eq!(run(), vec![EResult::ok("hi", "foo.txt").with_size(5)]);
+    // Edited by hand, so neither the recorded hash nor the cache describes
+    // it any more, even though its size didn't change.
+    expected_fs
+        .write(Path::new("foo.txt"), &mut |writer| write!(writer, "jello"))
+        .unwrap();
+    let results = run();
+    assert_eq!(results.len(), 1);
+    assert!(!results[0].is_ok());
+    assert!(fs.exists(Path::new("expected/hi/.hashes")));
+}
+
+#[test]
+fn validate_hashed_compares_digests_and_keeps_actual() {
+    use std::io::Write;
+    let (results, fs) = difftest_validate_with_fs("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/data.sha256"), &mut |writer| {
+                write!(
+                    writer,
+                    "sha256 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824\n\
+                     size 5\n"
+                )
+            }).unwrap();
+        provider.hashed("new").write_all(b"hello").unwrap();
+        provider.hashed("data").write_all(b"hello!").unwrap();
+    });
+
+    assert_eq!(
+        results[0],
+        EResult::expected_not_found(
+            "hi",
+            "new.sha256",
+            "/actual/hi/new.sha256",
+            "/expected/hi/new.sha256"
+        ).with_size(79)
+    );
+    assert!(!results[1].is_ok());
+    assert!(fs.exists(Path::new("actual/hi/data")));
+    assert!(!fs.exists(Path::new("expected/hi/data")));
+    fs.read(Path::new("diff/hi/data.sha256.diff"), &mut |r| {
+        let mut v = String::new();
+        r.read_to_string(&mut v)?;
+        assert_eq!(
+            v,
+            "only the digest of this output is stored, so no diff is possible\n\
+             expected: sha256 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824, size 5 bytes\n\
+             actual:   sha256 ce06092fb948d9ffac7d1a376e404b26b7575bcc11ee05a4615fef4fec3a308b, size 6 bytes\n\
+             the actual output is kept at actual/hi/data\n"
+        );
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn validate_in_parallel_keeps_registration_order() {
+    let run = |jobs| {
+        let top_fs = filesystem::FakeFileSystem::new();
+        let provider = provider::Provider::new(top_fs.duplicate(), "hi");
+        for i in 0..40 {
+            let name = format!("{}.txt", 39 - i);
+            if i % 3 != 0 {
+                provider
+                    .root_fs
+                    .write(&Path::new("expected/hi").join(&name), &mut |w| {
+                        write!(w, "file {}", i % 2)
+                    }).unwrap();
+            }
+            provider.text(&name, "file 0").unwrap();
+        }
+        validate("hi", top_fs.duplicate(), provider, |_| true, jobs, UntrackedPolicy::Warn, SizeBudgets::default())
+    };
+
+    let sequential = run(1);
+    assert_eq!(sequential.len(), 40);
+    assert_eq!(sequential[0].file_name, Path::new("39.txt"));
+    assert_eq!(run(8), sequential);
+}
+
+#[test]
+fn validate_runs_local_tests_in_registration_order() {
+    use std::cell::Cell;
+    use std::io::Write;
+    use std::rc::Rc;
+    let compared = Rc::new(Cell::new(0));
+    let results = difftest_validate("hi", |provider| {
+        for name in &["a.txt", "b.txt", "c.txt"] {
+            provider
+                .root_fs
+                .write(&Path::new("expected/hi").join(name), &mut |w| write!(w, "same"))
+                .unwrap();
+        }
+        provider.text("a.txt", "same").unwrap();
+        let counter = compared.clone();
+        let mut w = provider.custom_test(
+            "b.txt",
+            move |_, _| {
+                counter.set(counter.get() + 1);
+                Ok(true)
+            },
+            |_, _, _, _| unimplemented!(),
+        );
+        write!(w, "same").unwrap();
+        drop(w);
+        provider.text("c.txt", "same").unwrap();
+    });
+    assert_eq!(compared.get(), 1);
+    let names: Vec<_> = results.iter().map(|r| r.file_name.clone()).collect();
+    assert_eq!(
+        names,
+        vec![
+            PathBuf::from("a.txt"),
+            PathBuf::from("b.txt"),
+            PathBuf::from("c.txt"),
+        ]
+    );
+}
+
+#[test]
+fn validate_reports_untracked_actual_files_by_policy() {
+    use std::io::Write;
+    let run = |policy| {
+        let top_fs = filesystem::FakeFileSystem::new();
+        let provider = provider::Provider::new(top_fs.duplicate(), "hi");
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.txt"), &mut |w| write!(w, "foo"))
+            .unwrap();
+        provider.text("foo.txt", "foo").unwrap();
+        provider.diagnostic().text("notes.txt", "notes").unwrap();
+        provider.hashed("data.bin").write_all(b"data").unwrap();
+        provider
+            .root_fs
+            .write(Path::new("actual/hi/stray.txt"), &mut |w| write!(w, "stray"))
+            .unwrap();
+        validate("hi", top_fs.duplicate(), provider, |_| true, 4, policy, SizeBudgets::default())
+    };
+
+    let untracked = |fatal| EResult::untracked("hi", "stray.txt", "/actual/hi/stray.txt", fatal);
+    let warned = run(UntrackedPolicy::Warn);
+    assert_eq!(warned.len(), 3);
+    assert_eq!(warned[2], untracked(false));
+    assert!(warned[2].is_ok());
+
+    let failed = run(UntrackedPolicy::Error);
+    assert_eq!(failed[2], untracked(true));
+    assert!(!failed[2].is_ok());
+
+    assert_eq!(run(UntrackedPolicy::Ignore).len(), 2);
+}
+
+#[test]
+fn clear_output_removes_only_the_tests_actual_and_diff_files() {
+    let fs = filesystem::FakeFileSystem::new();
+    for path in &[
+        "actual/hi/stale.txt",
+        "actual/hi/sub/stale.png",
+        "diff/hi/stale.txt.diff",
+        "actual/hi2/other.txt",
+        "expected/hi/foo.txt",
+    ] {
+        fs.write(Path::new(path), &mut |w| write!(w, "x")).unwrap();
+    }
+
+    clear_output(&fs, "hi").unwrap();
+
+    let mut files = fs.files();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            PathBuf::from("actual/hi2/other.txt"),
+            PathBuf::from("expected/hi/foo.txt"),
+        ]
+    );
+}
+
+#[test]
+fn validate_skips_expected_files_matched_by_expectignore() {
+    let top_fs = filesystem::FakeFileSystem::new();
+    let provider = provider::Provider::new(top_fs.duplicate(), "hi");
+    let files: &[(&str, &str)] = &[
+        ("expected/.expectignore", "*.swp\n.DS_Store\n"),
+        ("expected/hi/.expectignore", "README.md\nmeta/\n!keep.swp\n"),
+        ("expected/hi/foo.txt", "foo"),
+        ("expected/hi/.foo.txt.swp", "swap"),
+        ("expected/hi/.DS_Store", "junk"),
+        ("expected/hi/README.md", "about this baseline"),
+        ("expected/hi/meta/info.json", "{}"),
+        ("expected/hi/keep.swp", "kept"),
+        ("expected/hi/gone.txt", "gone"),
+    ];
+    for &(path, contents) in files {
+        provider
+            .root_fs
+            .write(Path::new(path), &mut |w| write!(w, "{}", contents))
+            .unwrap();
+    }
+    provider.text("foo.txt", "foo").unwrap();
+
+    let mut results = validate("hi", top_fs.duplicate(), provider, |_| true, 4, UntrackedPolicy::Warn, SizeBudgets::default());
+    results.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    let not_found = |file: &str| {
+        EResult::actual_not_found(
+            "hi",
+            file,
+            Path::new("/actual/hi").join(file),
+            Path::new("/expected/hi").join(file),
+        )
+    };
+    assert_eq!(
+        results,
+        vec![
+            EResult::ok("hi", "foo.txt").with_size(3),
+            not_found("gone.txt"),
+            not_found("keep.swp"),
+        ]
+    );
+}
+
+#[test]
+fn validate_reports_files_and_tests_over_their_size_budgets() {
+    let top_fs = filesystem::FakeFileSystem::new();
+    let provider = provider::Provider::new(top_fs.duplicate(), "hi");
+    provider.text("small.txt", "x".repeat(100)).unwrap();
+    provider.text("medium.txt", "x".repeat(2000)).unwrap();
+    provider.text("large.txt", "x".repeat(5000)).unwrap();
+
+    let budgets = SizeBudgets {
+        file: SizeLimit::parse("1K,4K"),
+        test: SizeLimit::parse("6K"),
+    };
+    let results = validate("hi", top_fs.duplicate(), provider, |_| true, 4, UntrackedPolicy::Warn, budgets);
+    let over: Vec<_> = results
+        .into_iter()
+        .filter(|r| matches!(r.kind, ResultKind::OverBudget(_)))
+        .collect();
+    assert_eq!(
+        over,
+        vec![
+            EResult::over_budget("hi", "medium.txt", 2000, 1024, false),
+            EResult::over_budget("hi", "large.txt", 5000, 4096, true),
+            EResult::over_budget("hi", "", 7100, 6144, false),
+        ]
+    );
+    assert!(over[0].is_ok());
+    assert!(!over[1].is_ok());
+    assert_eq!(SizeLimit::parse(",10M").exceeded(11 << 20), Some((10 << 20, true)));
+}
+
+#[test]
+fn compressed_filesystem_keeps_the_format_of_existing_files() {
+    let fs = filesystem::FakeFileSystem::new();
+    let zstd = CompressedFileSystem::new(fs.duplicate(), Some(Compression::Zstd));
+    let plain = CompressedFileSystem::new(fs.duplicate(), None);
+
+    zstd.write(Path::new("a.txt"), &mut |w| write!(w, "{}", "a".repeat(1000)))
+        .unwrap();
+    CompressedFileSystem::new(fs.duplicate(), Some(Compression::Gzip))
+        .write(Path::new("b.txt"), &mut |w| write!(w, "b"))
+        .unwrap();
+    plain.write(Path::new("c.txt"), &mut |w| write!(w, "c")).unwrap();
+    plain.write(Path::new("b.txt"), &mut |w| write!(w, "bb")).unwrap();
+
+    let mut stored = fs.files();
+    stored.sort();
+    assert_eq!(
+        stored,
+        vec![
+            PathBuf::from("a.txt.zst"),
+            PathBuf::from("b.txt.gz"),
+            PathBuf::from("c.txt"),
+        ]
+    );
+    assert!(fs.size(Path::new("a.txt.zst")).unwrap() < 100);
+    assert_eq!(
+        plain.files(),
+        vec![
+            PathBuf::from("a.txt"),
+            PathBuf::from("b.txt"),
+            PathBuf::from("c.txt"),
+        ]
+    );
+
+    let read = |path: &str| {
+        let mut contents = String::new();
+        plain
+            .read(Path::new(path), &mut |r| r.read_to_string(&mut contents).map(|_| ()))
+            .unwrap();
+        contents
+    };
+    assert_eq!(read("a.txt"), "a".repeat(1000));
+    assert_eq!(read("b.txt"), "bb");
+    assert_eq!(read("c.txt"), "c");
+
+    zstd.write(Path::new("c.txt"), &mut |w| write!(w, "cc")).unwrap();
+    assert!(!fs.exists(Path::new("c.txt")));
+    plain.remove(Path::new("a.txt")).unwrap();
+    assert!(!plain.exists(Path::new("a.txt")));
+    assert_eq!(read("c.txt"), "cc");
+}
+
+#[test]
+fn validate_compares_against_compressed_and_shared_expected_files() {
+    use expectation_shared::objects::ObjectFileSystem;
+    type Store = fn(&FakeFileSystem) -> Box<FileSystem>;
+    // How the expected files are stored, and the size that a four byte file
+    // takes up that way.
+    let stores: [(Store, u64); 2] = [
+        (
+            |fs| {
+                Box::new(CompressedFileSystem::new(
+                    fs.subsystem(Path::new("expected/hi")),
+                    Some(Compression::Zstd),
+                ))
+            },
+            13,
+        ),
+        (
+            |fs| {
+                Box::new(ObjectFileSystem::new(
+                    fs.subsystem(Path::new("expected/hi")),
+                    fs.subsystem(Path::new("objects")),
+                    true,
+                ))
+            },
+            4,
+        ),
+    ];
+
+    for (store, size) in stores.iter() {
+        let top_fs = filesystem::FakeFileSystem::new();
+        let provider = provider::Provider::new(top_fs.duplicate(), "hi");
+        let expected = store(&top_fs);
+        expected.write(Path::new("same.txt"), &mut |w| write!(w, "same")).unwrap();
+        expected.write(Path::new("changed.txt"), &mut |w| writeln!(w, "old")).unwrap();
+        provider.text("same.txt", "same").unwrap();
+        provider.text("changed.txt", "new\n").unwrap();
+
+        let results = validate("hi", top_fs.duplicate(), provider, |_| true, 4, UntrackedPolicy::Warn, SizeBudgets::default());
+        assert_eq!(results.len(), 2, "{:?}", results);
+        // Sizes are of the files as they are stored.
+        assert_eq!(results[0], EResult::ok("hi", "same.txt").with_size(*size));
+        assert_eq!(results[1].size, Some(*size));
+        match &results[1].kind {
+            ResultKind::Difference(difference) => {
+                assert_eq!(difference.expected, PathBuf::from("/expected/hi/changed.txt"));
+                let html = difference.html.as_ref().unwrap();
+                assert!(html.contains("old"), "{}", html);
+            }
+            other => panic!("expected a difference, got {:?}", other),
+        }
+
+        // The report gets a plain copy of the expected file, and validating
+        // writes nothing into the expected directory.
+        let mut copy = String::new();
+        top_fs
+            .read(Path::new("diff/hi/expected/changed.txt"), &mut |r| {
+                r.read_to_string(&mut copy).map(|_| ())
+            })
+            .unwrap();
+        assert_eq!(copy, "old\n");
+        assert!(!top_fs.exists(Path::new("expected/hi/.hashes")));
+    }
+}
+
+#[test]
+fn object_filesystem_stores_identical_files_once() {
+    use expectation_shared::objects::{collect_garbage, ObjectFileSystem};
+    let fs = filesystem::FakeFileSystem::new();
+    let objects = fs.subsystem(Path::new("objects"));
+    let expected = ObjectFileSystem::new(fs.subsystem(Path::new("expected")), objects.duplicate(), true);
+
+    expected.write(Path::new("one/ref.png"), &mut |w| write!(w, "shared")).unwrap();
+    expected.write(Path::new("two/ref.png"), &mut |w| write!(w, "shared")).unwrap();
+    expected.write(Path::new("two/own.png"), &mut |w| write!(w, "own")).unwrap();
+
+    assert_eq!(objects.files().len(), 2);
+    assert!(fs.exists(Path::new("expected/one/ref.png.object")));
+    assert!(!fs.exists(Path::new("expected/one/ref.png")));
+    let mut files = expected.files();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            PathBuf::from("one/ref.png"),
+            PathBuf::from("two/own.png"),
+            PathBuf::from("two/ref.png"),
+        ]
+    );
+    assert_eq!(expected.size(Path::new("two/own.png")).unwrap(), 3);
+    let mut contents = String::new();
+    expected
+        .read(Path::new("two/ref.png"), &mut |r| r.read_to_string(&mut contents).map(|_| ()))
+        .unwrap();
+    assert_eq!(contents, "shared");
+
+    // An object is kept while any pointer still points to it.
+    expected.remove(Path::new("one/ref.png")).unwrap();
+    expected.remove(Path::new("two/own.png")).unwrap();
+    let removed = collect_garbage(&*fs.subsystem(Path::new("expected")), &*objects).unwrap();
+    assert_eq!(removed.len(), 1);
+    assert_eq!(objects.files().len(), 1);
+    assert!(expected.exists(Path::new("two/ref.png")));
+}
+
+#[test]
+fn object_filesystem_keeps_old_file_until_created_file_is_flushed() {
+    use expectation_shared::objects::ObjectFileSystem;
+    use std::io::Write;
+    let fs = filesystem::FakeFileSystem::new();
+    let expected = ObjectFileSystem::new(
+        fs.subsystem(Path::new("expected")),
+        fs.subsystem(Path::new("objects")),
+        true,
+    );
+    fs.write(Path::new("expected/foo.txt"), &mut |w| write!(w, "old")).unwrap();
+
+    let mut w = expected.create(Path::new("foo.txt")).unwrap();
+    write!(w, "new").unwrap();
+    assert!(fs.exists(Path::new("expected/foo.txt")));
+    assert!(!fs.exists(Path::new("expected/foo.txt.object")));
+
+    w.flush().unwrap();
+    assert!(!fs.exists(Path::new("expected/foo.txt")));
+    let mut contents = String::new();
+    expected
+        .read(Path::new("foo.txt"), &mut |r| r.read_to_string(&mut contents).map(|_| ()))
+        .unwrap();
+    assert_eq!(contents, "new");
+}